@@ -0,0 +1,5963 @@
+//! A payments engine: reads deposits, withdrawals, disputes, resolves and chargebacks and keeps
+//! each client's available, held and locked state.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, Error, ErrorKind, Write};
+use std::iter::Sum;
+use std::num::NonZeroUsize;
+use std::ops::{Add, AddAssign, Sub, SubAssign};
+use std::path::Path;
+use std::str::FromStr;
+use std::thread;
+use std::time::Instant;
+
+use csv::Trim;
+use flate2::read::GzDecoder;
+pub use rust_decimal::Decimal;
+use rust_decimal::RoundingStrategy;
+use serde::de::{self, DeserializeOwned, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransactionType {
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+    // Moves funds from one client's available balance to the `target` client's.
+    Transfer,
+}
+
+// How each transaction type is written, in variant order.
+const TRANSACTION_TYPES: &[&str] = &["deposit", "withdrawal", "dispute", "resolve", "chargeback", "transfer"];
+
+impl TransactionType {
+    const ALL: [TransactionType; 6] = [
+        TransactionType::Deposit,
+        TransactionType::Withdrawal,
+        TransactionType::Dispute,
+        TransactionType::Resolve,
+        TransactionType::Chargeback,
+        TransactionType::Transfer,
+    ];
+
+    fn name(self) -> &'static str {
+        TRANSACTION_TYPES[self as usize]
+    }
+
+    // Exports disagree on casing, so `Deposit` and `DEPOSIT` are read as `deposit`.
+    fn from_name(name: &str) -> Option<TransactionType> {
+        TransactionType::ALL.iter().copied().find(|transaction_type| transaction_type.name().eq_ignore_ascii_case(name))
+    }
+
+    // Only deposits and withdrawals move money in or out, so only they can be disputed, resolved
+    // or charged back. Control operations are never valid targets, even if one gets recorded.
+    fn is_disputable(self) -> bool {
+        matches!(self, TransactionType::Deposit | TransactionType::Withdrawal)
+    }
+
+    // Rows that move funds carry an amount and an id of their own, which must not repeat.
+    fn moves_funds(self) -> bool {
+        self.is_disputable() || self == TransactionType::Transfer
+    }
+}
+
+struct TransactionTypeVisitor;
+
+impl<'de> Visitor<'de> for TransactionTypeVisitor {
+    type Value = TransactionType;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a transaction type")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<TransactionType, E> {
+        // Reported the way serde reports an unknown variant, which `TransactionError::row` relies on.
+        TransactionType::from_name(value).ok_or_else(|| E::unknown_variant(value, TRANSACTION_TYPES))
+    }
+}
+
+impl<'de> Deserialize<'de> for TransactionType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_str(TransactionTypeVisitor)
+    }
+}
+
+impl Serialize for TransactionType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+/// An amount of money held as a whole number of ten-thousandths, so that adding and
+/// subtracting amounts is exact no matter how many operations are applied.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Money(i64);
+
+// Amounts carry at most four decimal places.
+const MONEY_DECIMALS: usize = 4;
+const MONEY_SCALE: i64 = 10_000;
+
+impl Money {
+    const ZERO: Money = Money(0);
+
+    // Rounds to `decimals` places, half away from zero like parsing does. Anything from four
+    // places up leaves the amount as it is.
+    pub fn round_to(self, decimals: usize) -> Money {
+        if decimals >= MONEY_DECIMALS {
+            return self;
+        }
+        let step = 10_i64.pow((MONEY_DECIMALS - decimals) as u32);
+        let (steps, remainder) = (self.0 / step, self.0 % step);
+        let away = if remainder.abs() * 2 >= step { self.0.signum() } else { 0 };
+        Money((steps + away) * step)
+    }
+}
+
+/// The arithmetic accounts and the engine need from an amount. `Money` is the one every reader
+/// and writer uses; callers that build their own transactions can plug in another type, with
+/// `Default` as its zero.
+pub trait Amount:
+    Copy
+    + Default
+    + PartialOrd
+    + fmt::Debug
+    + fmt::Display
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + AddAssign
+    + SubAssign
+{
+    /// `None` if the sum can't be represented, rather than wrapping around.
+    fn checked_add(self, other: Self) -> Option<Self>;
+
+    /// `None` if the difference can't be represented, rather than wrapping around.
+    fn checked_sub(self, other: Self) -> Option<Self>;
+
+    /// Like `checked_add`, but stopping at the largest representable amount.
+    fn saturating_add(self, other: Self) -> Self;
+}
+
+impl Amount for Money {
+    fn checked_add(self, other: Money) -> Option<Money> {
+        self.0.checked_add(other.0).map(Money)
+    }
+
+    fn checked_sub(self, other: Money) -> Option<Money> {
+        self.0.checked_sub(other.0).map(Money)
+    }
+
+    fn saturating_add(self, other: Money) -> Money {
+        Money(self.0.saturating_add(other.0))
+    }
+}
+
+// Exact base-10 arithmetic with up to 28 decimal places, for callers that need more than the
+// four `Money` keeps. Amounts are read from their text form, so nothing passes through a float.
+impl Amount for Decimal {
+    fn checked_add(self, other: Decimal) -> Option<Decimal> {
+        Decimal::checked_add(self, other)
+    }
+
+    fn checked_sub(self, other: Decimal) -> Option<Decimal> {
+        Decimal::checked_sub(self, other)
+    }
+
+    fn saturating_add(self, other: Decimal) -> Decimal {
+        Decimal::saturating_add(self, other)
+    }
+}
+
+impl From<Money> for Decimal {
+    fn from(value: Money) -> Decimal {
+        Decimal::new(value.0, MONEY_DECIMALS as u32)
+    }
+}
+
+// Rounds to four places half away from zero, the same as parsing a `Money` does, failing only if
+// the amount is beyond `Money`'s range.
+impl TryFrom<Decimal> for Money {
+    type Error = String;
+
+    fn try_from(value: Decimal) -> Result<Self, Self::Error> {
+        let rounded = value.round_dp_with_strategy(MONEY_DECIMALS as u32, RoundingStrategy::MidpointAwayFromZero);
+        rounded.to_string().parse().map_err(|_| format!("amount {} is out of range", value))
+    }
+}
+
+impl FromStr for Money {
+    type Err = String;
+
+    // Parses a plain decimal such as `12`, `-0.5` or `1.2345` straight into ten-thousandths,
+    // without going through a float. Digits past the fourth decimal place are rounded half
+    // away from zero (`1.00005` becomes `1.0001`, `-1.00005` becomes `-1.0001`), so every
+    // stored amount, and therefore every printed balance and total, has at most four places.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid amount {}", value);
+        let (negative, digits) = match value.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, value.strip_prefix('+').unwrap_or(value)),
+        };
+        let (whole, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+        let all_digits = |part: &str| part.bytes().all(|byte| byte.is_ascii_digit());
+        if (whole.is_empty() && fraction.is_empty()) || !all_digits(whole) || !all_digits(fraction) {
+            return Err(invalid());
+        }
+        let (kept, dropped) = fraction.split_at(fraction.len().min(MONEY_DECIMALS));
+        let round_up = dropped.bytes().next().is_some_and(|digit| digit >= b'5');
+        let whole: i64 = if whole.is_empty() { 0 } else { whole.parse().map_err(|_| invalid())? };
+        let fraction: i64 = format!("{:0<width$}", kept, width = MONEY_DECIMALS)
+            .parse()
+            .map_err(|_| invalid())?;
+        let units = whole
+            .checked_mul(MONEY_SCALE)
+            .and_then(|units| units.checked_add(fraction + i64::from(round_up)))
+            .ok_or_else(invalid)?;
+        Ok(Money(if negative { -units } else { units }))
+    }
+}
+
+impl TryFrom<f64> for Money {
+    type Error = String;
+
+    fn try_from(value: f64) -> Result<Self, Self::Error> {
+        let units = (value * MONEY_SCALE as f64).round();
+        if units.is_finite() && units.abs() < i64::MAX as f64 {
+            Ok(Money(units as i64))
+        } else {
+            Err(format!("invalid amount {}", value))
+        }
+    }
+}
+
+// Prints at most four decimal places and drops trailing zeros: `10`, `1.5`, `-0.0001`. With a
+// precision, as in `{:.2}`, rounds like `round_to` and prints exactly that many places: `10.00`.
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = f.precision().map_or(*self, |decimals| self.round_to(decimals));
+        let sign = if value.0 < 0 { "-" } else { "" };
+        let units = value.0.unsigned_abs();
+        let scale = MONEY_SCALE as u64;
+        let (whole, fraction) = (units / scale, units % scale);
+        if let Some(decimals) = f.precision() {
+            let fraction = format!("{:0width$}", fraction, width = MONEY_DECIMALS);
+            let kept = &fraction[..decimals.min(MONEY_DECIMALS)];
+            let point = if decimals == 0 { "" } else { "." };
+            write!(f, "{}{}{}{:0<width$}", sign, whole, point, kept, width = decimals)
+        } else if fraction == 0 {
+            write!(f, "{}{}", sign, whole)
+        } else {
+            let fraction = format!("{:0width$}", fraction, width = MONEY_DECIMALS);
+            write!(f, "{}{}.{}", sign, whole, fraction.trim_end_matches('0'))
+        }
+    }
+}
+
+impl Add for Money {
+    type Output = Money;
+
+    fn add(self, other: Money) -> Money {
+        Money(self.0 + other.0)
+    }
+}
+
+impl Sub for Money {
+    type Output = Money;
+
+    fn sub(self, other: Money) -> Money {
+        Money(self.0 - other.0)
+    }
+}
+
+impl AddAssign for Money {
+    fn add_assign(&mut self, other: Money) {
+        self.0 += other.0;
+    }
+}
+
+impl SubAssign for Money {
+    fn sub_assign(&mut self, other: Money) {
+        self.0 -= other.0;
+    }
+}
+
+impl Sum for Money {
+    fn sum<I: Iterator<Item = Money>>(iter: I) -> Money {
+        iter.fold(Money::ZERO, Add::add)
+    }
+}
+
+struct MoneyVisitor;
+
+impl<'de> Visitor<'de> for MoneyVisitor {
+    type Value = Money;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "a decimal amount with at most four decimal places")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Money, E> {
+        value.parse().map_err(E::custom)
+    }
+
+    fn visit_f64<E: de::Error>(self, value: f64) -> Result<Money, E> {
+        Money::try_from(value).map_err(E::custom)
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<Money, E> {
+        value.checked_mul(MONEY_SCALE).map(Money).ok_or_else(|| E::custom(format!("invalid amount {}", value)))
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<Money, E> {
+        i64::try_from(value)
+            .map_err(|_| E::custom(format!("invalid amount {}", value)))
+            .and_then(|value| self.visit_i64(value))
+    }
+}
+
+impl<'de> Deserialize<'de> for Money {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Money, D::Error> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_str(MoneyVisitor)
+        } else {
+            i64::deserialize(deserializer).map(Money)
+        }
+    }
+}
+
+// Serialized as a JSON number so machine-readable output keeps its numeric type. Binary formats
+// get the raw ten-thousandths instead, which round-trip exactly.
+impl Serialize for Money {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_f64(self.0 as f64 / MONEY_SCALE as f64)
+        } else {
+            serializer.serialize_i64(self.0)
+        }
+    }
+}
+
+/// A deposit or withdrawal amount. Only values greater than zero can be constructed, so anything
+/// holding a `PositiveAmount` never has to re-check the sign.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PositiveAmount<A = Money>(A);
+
+impl<A: Amount> PositiveAmount<A> {
+    // Negative amounts would let a deposit drain an account or a withdrawal fund it, and a zero
+    // amount moves nothing, so neither can be constructed. A row carrying one is still read, and
+    // the engine skips it; see `Transaction::invalid_amount`.
+    pub fn new(value: A) -> Option<PositiveAmount<A>> {
+        if value > A::default() {
+            Some(PositiveAmount(value))
+        } else {
+            None
+        }
+    }
+
+    pub fn value(self) -> A {
+        self.0
+    }
+}
+
+impl TryFrom<Money> for PositiveAmount {
+    type Error = String;
+
+    fn try_from(value: Money) -> Result<Self, Self::Error> {
+        PositiveAmount::new(value).ok_or_else(|| format!("invalid amount {}", value))
+    }
+}
+
+impl<'de, A: Amount + Deserialize<'de>> Deserialize<'de> for PositiveAmount<A> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = A::deserialize(deserializer)?;
+        PositiveAmount::new(value).ok_or_else(|| de::Error::custom(format!("invalid amount {}", value)))
+    }
+}
+
+// When a transaction happened, as milliseconds since the Unix epoch. Parsed from either an
+// RFC 3339 date-time or a plain integer count of milliseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp(i64);
+
+impl Timestamp {
+    pub fn from_millis(millis: i64) -> Timestamp {
+        Timestamp(millis)
+    }
+
+    pub fn as_millis(self) -> i64 {
+        self.0
+    }
+}
+
+impl FromStr for Timestamp {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if let Ok(millis) = value.parse() {
+            return Ok(Timestamp(millis));
+        }
+        chrono::DateTime::parse_from_rfc3339(value)
+            .map(|time| Timestamp(time.timestamp_millis()))
+            .map_err(|_| format!("invalid timestamp {}", value))
+    }
+}
+
+struct TimestampVisitor;
+
+impl<'de> Visitor<'de> for TimestampVisitor {
+    type Value = Timestamp;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an RFC 3339 date-time or milliseconds since the epoch")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+        value.parse().map_err(de::Error::custom)
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<Self::Value, E> {
+        Ok(Timestamp(value))
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<Self::Value, E> {
+        i64::try_from(value).map(Timestamp).map_err(|_| de::Error::custom(format!("invalid timestamp {}", value)))
+    }
+}
+
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(TimestampVisitor)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(from = "TransactionRow<A>", bound(deserialize = "A: Amount + Deserialize<'de>"))]
+pub struct Transaction<A = Money> {
+    pub transaction_type: TransactionType,
+    pub client: u16,
+    pub tx: u32,
+    pub amount: Option<PositiveAmount<A>>,
+    // An amount of zero or less, which `amount` can't hold. The engine skips the transaction for it
+    // rather than failing the read, so one bad row doesn't cost every other client their balances.
+    pub invalid_amount: Option<A>,
+    // Optional canonical ordering for feeds whose file order can't be trusted.
+    pub seq: Option<u64>,
+    // Optional event time, for feeds that should be replayed chronologically; see `sort_by_timestamp`.
+    pub timestamp: Option<Timestamp>,
+    // The client a transfer pays into; unused by every other type.
+    pub target: Option<u16>,
+}
+
+impl<A> Transaction<A> {
+    // A transaction with none of the optional columns set.
+    pub fn new(transaction_type: TransactionType, client: u16, tx: u32, amount: Option<PositiveAmount<A>>) -> Self {
+        let (invalid_amount, seq, timestamp, target) = (None, None, None, None);
+        Transaction { transaction_type, client, tx, amount, invalid_amount, seq, timestamp, target }
+    }
+}
+
+// A transaction as it appears in the input, with the amount exactly as written.
+#[derive(Deserialize)]
+#[serde(bound(deserialize = "A: Deserialize<'de>"))]
+struct TransactionRow<A> {
+    #[serde(rename = "type")]
+    transaction_type: TransactionType,
+    client: u16,
+    tx: u32,
+    amount: Option<A>,
+    #[serde(default)]
+    seq: Option<u64>,
+    #[serde(default)]
+    timestamp: Option<Timestamp>,
+    #[serde(default)]
+    target: Option<u16>,
+}
+
+impl<A: Amount> From<TransactionRow<A>> for Transaction<A> {
+    fn from(row: TransactionRow<A>) -> Self {
+        let amount = row.amount.and_then(PositiveAmount::new);
+        Transaction {
+            transaction_type: row.transaction_type,
+            client: row.client,
+            tx: row.tx,
+            amount,
+            invalid_amount: if amount.is_none() { row.amount } else { None },
+            seq: row.seq,
+            timestamp: row.timestamp,
+            target: row.target,
+        }
+    }
+}
+
+// The ids of an account's open disputes. A plain `Vec` by default; building with the
+// `compact-disputes` feature stores them in a roaring bitmap instead, which is far smaller
+// for dispute-heavy accounts whose transaction ids cluster together.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DisputedTransactions {
+    #[cfg(not(feature = "compact-disputes"))]
+    ids: Vec<u32>,
+    #[cfg(feature = "compact-disputes")]
+    ids: roaring::RoaringBitmap,
+}
+
+impl DisputedTransactions {
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+}
+
+#[cfg(not(feature = "compact-disputes"))]
+impl DisputedTransactions {
+    pub fn contains(&self, transaction_id: u32) -> bool {
+        self.ids.contains(&transaction_id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    fn insert(&mut self, transaction_id: u32) {
+        self.ids.push(transaction_id);
+    }
+
+    fn remove(&mut self, transaction_id: u32) {
+        self.ids.retain(|x| x != &transaction_id);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u32> + '_ {
+        self.ids.iter().copied()
+    }
+
+    // Approximate heap bytes used to store the ids.
+    #[cfg(test)]
+    fn heap_size(&self) -> usize {
+        self.ids.capacity() * std::mem::size_of::<u32>()
+    }
+}
+
+#[cfg(feature = "compact-disputes")]
+impl DisputedTransactions {
+    pub fn contains(&self, transaction_id: u32) -> bool {
+        self.ids.contains(transaction_id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len() as usize
+    }
+
+    fn insert(&mut self, transaction_id: u32) {
+        self.ids.insert(transaction_id);
+    }
+
+    fn remove(&mut self, transaction_id: u32) {
+        self.ids.remove(transaction_id);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u32> + '_ {
+        self.ids.iter()
+    }
+
+    // Approximate heap bytes used to store the ids.
+    #[cfg(test)]
+    fn heap_size(&self) -> usize {
+        self.ids.serialized_size()
+    }
+}
+
+impl From<Vec<u32>> for DisputedTransactions {
+    fn from(ids: Vec<u32>) -> Self {
+        let mut disputed = DisputedTransactions::default();
+        for id in ids {
+            disputed.insert(id);
+        }
+        disputed
+    }
+}
+
+// Stored as a plain list of ids whichever representation is in use, so state saved by one build
+// loads in the other.
+impl Serialize for DisputedTransactions {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+impl<'de> Deserialize<'de> for DisputedTransactions {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Vec::<u32>::deserialize(deserializer).map(DisputedTransactions::from)
+    }
+}
+
+impl PartialEq<Vec<u32>> for DisputedTransactions {
+    fn eq(&self, other: &Vec<u32>) -> bool {
+        self.iter().eq(other.iter().copied())
+    }
+}
+
+// Why an account was frozen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FreezeReason {
+    Chargeback,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Account<A = Money> {
+    disputed_transactions: DisputedTransactions,
+    frozen: bool,
+    // Kept next to the flag rather than replacing it: accounts loaded frozen from a baseline are
+    // locked without the engine knowing why.
+    freeze_reason: Option<FreezeReason>,
+    held: A,
+    available: A,
+    // Running totals of every deposit and withdrawal that went through, for reconciliation.
+    // Transfers count as a withdrawal from one account and a deposit into the other.
+    total_deposited: A,
+    total_withdrawn: A,
+}
+
+impl Account {
+    // A fresh, unlocked account with nothing in it. Accounts over other amounts use `default`.
+    pub fn new() -> Account {
+        Account::default()
+    }
+}
+
+impl Account<Decimal> {
+    // This account in `Money`, every balance rounded to four places. Fails if one is out of range.
+    pub fn to_money(&self) -> Result<Account, String> {
+        Ok(Account {
+            disputed_transactions: self.disputed_transactions.clone(),
+            frozen: self.frozen,
+            freeze_reason: self.freeze_reason,
+            held: Money::try_from(self.held)?,
+            available: Money::try_from(self.available)?,
+            total_deposited: Money::try_from(self.total_deposited)?,
+            total_withdrawn: Money::try_from(self.total_withdrawn)?,
+        })
+    }
+}
+
+impl<A: Amount> Account<A> {
+    pub fn available(&self) -> A {
+        self.available
+    }
+
+    pub fn held(&self) -> A {
+        self.held
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.frozen
+    }
+
+    pub fn freeze_reason(&self) -> Option<FreezeReason> {
+        self.freeze_reason
+    }
+
+    // Lifts a freeze once the account has been reviewed. Balances are left as they are, and
+    // disputes that were open when it froze can be resolved or charged back again.
+    pub fn unfreeze(&mut self) {
+        self.frozen = false;
+        self.freeze_reason = None;
+    }
+
+    pub fn total_deposited(&self) -> A {
+        self.total_deposited
+    }
+
+    pub fn total_withdrawn(&self) -> A {
+        self.total_withdrawn
+    }
+
+    // Only strictly positive amounts can get here: the engine skips a transaction of zero or less
+    // before it reaches the account, so neither a deposit nor a withdrawal can run in the wrong
+    // direction and `available` is left as it was.
+    //
+    // Returns whether the deposit went through. One that would overflow the balance or the total
+    // is refused rather than letting it wrap around to a negative amount.
+    fn deposit(&mut self, amount: PositiveAmount<A>) -> bool {
+        // Assuming that if the account is frozen, all deposit/withdrawal operations are blocked.
+        if self.frozen {
+            return false;
+        }
+        let held = self.held;
+        match self.available.checked_add(amount.value()).filter(|available| available.checked_add(held).is_some()) {
+            Some(available) => {
+                self.available = available;
+                // The running total is only informational, so it stops at the limit instead.
+                self.total_deposited = self.total_deposited.saturating_add(amount.value());
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Returns whether the withdrawal went through.
+    fn withdraw(&mut self, amount: PositiveAmount<A>) -> bool {
+        // Assuming that if the account is frozen, all deposit/withdrawal operations are blocked.
+        if self.frozen {
+            return false;
+        }
+        if amount.value() > self.available {
+            return false;
+        }
+        self.available -= amount.value();
+        self.total_withdrawn = self.total_withdrawn.saturating_add(amount.value());
+        true
+    }
+
+    // Disputing a deposit claims the money that came in may have to go back, so the amount moves
+    // from available into held. Disputing a withdrawal claims the money that went out may have to
+    // come back: it already left available, so the amount is held on top of the balance until the
+    // dispute settles. Resolving keeps the original transaction and undoes the dispute exactly;
+    // a chargeback reverses the original, taking a deposit back out or returning a withdrawal to
+    // available, and freezes the account.
+    //
+    // A frozen account is locked for review, so none of the three touch it: disputes that were
+    // still open when it was frozen stay held until the account is reviewed and unfrozen.
+    //
+    // Put together, each deposit or withdrawal moves through these states:
+    //
+    //   processed --dispute--> disputed --resolve--> processed (disputable again if allowed)
+    //                          disputed --chargeback--> charged back (final; the engine forgets it)
+    //
+    // Any other step, such as resolving or charging back a transaction that isn't disputed, or
+    // anything on a frozen account, is ignored and leaves the account as it was. So is a step that
+    // would take available, held or the total past what the amount type can represent.
+    //
+    // Returns whether the transaction was put under dispute.
+    fn dispute(&mut self, transaction_id: u32, amount: A, disputed_type: TransactionType) -> bool {
+        // A transaction that is already under dispute can't be held a second time.
+        if self.frozen || self.disputed_transactions.contains(transaction_id) {
+            return false;
+        }
+        // The full amount is held even if some of it was already withdrawn, so available can go
+        // negative: the client owes the difference if the dispute ends in a chargeback. Engines
+        // configured to reject this never call `dispute` in that case.
+        let available = match disputed_type {
+            TransactionType::Deposit => self.available.checked_sub(amount),
+            _ => Some(self.available),
+        };
+        if !self.rebalance(available, self.held.checked_add(amount)) {
+            return false;
+        }
+        self.disputed_transactions.insert(transaction_id);
+        true
+    }
+
+    // Returns whether an open dispute was resolved.
+    fn resolve(&mut self, transaction_id: u32, amount: A, disputed_type: TransactionType) -> bool {
+        if self.frozen || !self.disputed_transactions.contains(transaction_id) {
+            return false;
+        }
+        let available = match disputed_type {
+            TransactionType::Deposit => self.available.checked_add(amount),
+            _ => Some(self.available),
+        };
+        if !self.rebalance(available, self.held.checked_sub(amount)) {
+            return false;
+        }
+        self.disputed_transactions.remove(transaction_id);
+        true
+    }
+
+    // Returns whether an open dispute was charged back.
+    fn chargeback(&mut self, transaction_id: u32, amount: A, disputed_type: TransactionType) -> bool {
+        if self.frozen || !self.disputed_transactions.contains(transaction_id) {
+            return false;
+        }
+        let available = match disputed_type {
+            TransactionType::Withdrawal => self.available.checked_add(amount),
+            _ => Some(self.available),
+        };
+        if !self.rebalance(available, self.held.checked_sub(amount)) {
+            return false;
+        }
+        self.disputed_transactions.remove(transaction_id);
+        self.frozen = true;
+        self.freeze_reason = Some(FreezeReason::Chargeback);
+        true
+    }
+
+    // Sets both balances, unless either of them or their total couldn't be represented.
+    fn rebalance(&mut self, available: Option<A>, held: Option<A>) -> bool {
+        match (available, held) {
+            (Some(available), Some(held)) if available.checked_add(held).is_some() => {
+                self.available = available;
+                self.held = held;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Every change to the balances keeps this representable; only accounts loaded from outside
+    // the engine could be out of range, and those stop at the limit rather than wrapping.
+    pub fn total(&self) -> A {
+        self.available.saturating_add(self.held)
+    }
+}
+
+impl<A: Amount> Default for Account<A> {
+    fn default() -> Self {
+        Account {
+            disputed_transactions: DisputedTransactions::default(),
+            frozen: false,
+            freeze_reason: None,
+            held: A::default(),
+            available: A::default(),
+            total_deposited: A::default(),
+            total_withdrawn: A::default(),
+        }
+    }
+}
+
+const UTF8_BOM: &[u8] = b"\xef\xbb\xbf";
+
+// Input that has had its header line read to detect the delimiter: replays that line, then any
+// error that cut it short, then carries on with the rest of the input.
+struct SniffedInput<R> {
+    header: io::Cursor<Vec<u8>>,
+    error: Option<io::Error>,
+    rest: R,
+}
+
+impl<R: io::Read> io::Read for SniffedInput<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.header.read(buf)?;
+        if read > 0 {
+            return Ok(read);
+        }
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        self.rest.read(buf)
+    }
+}
+
+// Some exports separate fields with semicolons or tabs, so the delimiter is taken from the header
+// line: a comma if it has one, otherwise a semicolon or a tab, falling back to a comma. A UTF-8
+// byte order mark in front of the header is dropped so it can't end up in the first column name.
+fn sniff_delimiter<R: io::Read>(mut reader: R) -> (u8, SniffedInput<R>) {
+    let mut header = vec![];
+    let mut error = None;
+    let mut byte = [0u8];
+    // One byte at a time so nothing past the header line is taken from `reader`.
+    loop {
+        match reader.read(&mut byte) {
+            Ok(0) => break,
+            Ok(_) => {
+                header.push(byte[0]);
+                if byte[0] == b'\n' {
+                    break;
+                }
+            }
+            Err(read_error) if read_error.kind() == ErrorKind::Interrupted => {}
+            Err(read_error) => {
+                error = Some(read_error);
+                break;
+            }
+        }
+    }
+    if header.starts_with(UTF8_BOM) {
+        header.drain(..UTF8_BOM.len());
+    }
+    let delimiter = [b',', b';', b'\t'].iter().copied().find(|delimiter| header.contains(delimiter)).unwrap_or(b',');
+    (delimiter, SniffedInput { header: io::Cursor::new(header), error, rest: reader })
+}
+
+pub fn read_csv_file(filename: &str) -> Result<Vec<Transaction>, TransactionError> {
+    read_csv(open_transactions(Path::new(filename))?)
+}
+
+// Reads every row up front, so unlike `stream_csv` a deposit, withdrawal or transfer without an
+// amount fails the read on its line instead of reaching the engine.
+fn read_csv<R: io::Read>(reader: R) -> Result<Vec<Transaction>, TransactionError> {
+    csv_transactions(reader, ExcessPrecision::Round, ZeroAmounts::Skip, true, |_| {}).collect()
+}
+
+// Disputes, resolves and chargebacks act on the amount of the transaction they refer to, so an
+// amount of their own means nothing and is dropped. A missing amount is only an error with
+// `require_amount`: otherwise the engine decides, as `isolate_clients` needs it to. A zero amount
+// is only an error under `ZeroAmounts::Reject`.
+fn check_amount<A: Amount>(
+    mut transaction: Transaction<A>,
+    line: u64,
+    require_amount: bool,
+    zero_amounts: ZeroAmounts,
+) -> Result<Transaction<A>, TransactionError> {
+    if !transaction.transaction_type.moves_funds() {
+        transaction.amount = None;
+        transaction.invalid_amount = None;
+    } else if zero_amounts == ZeroAmounts::Reject && transaction.invalid_amount == Some(A::default()) {
+        return Err(TransactionError::MalformedRow {
+            line,
+            reason: format!("{} of zero", transaction.transaction_type.name()),
+        });
+    } else if require_amount && transaction.amount.is_none() && transaction.invalid_amount.is_none() {
+        return Err(TransactionError::MalformedRow {
+            line,
+            reason: format!("{} without an amount", transaction.transaction_type.name()),
+        });
+    }
+    Ok(transaction)
+}
+
+// Opens a transactions file, decompressing it on the fly when it ends in `.gz`.
+pub fn open_transactions(path: &Path) -> io::Result<Box<dyn io::Read>> {
+    let file = File::open(path)?;
+    if path.extension().is_some_and(|extension| extension == "gz") {
+        Ok(Box::new(GzDecoder::new(file)))
+    } else {
+        Ok(Box::new(file))
+    }
+}
+
+// Deserializes transactions lazily, one row per item, so callers never need the whole file.
+pub fn stream_csv<R: io::Read>(reader: R) -> impl Iterator<Item = Result<Transaction, TransactionError>> {
+    csv_transactions(reader, ExcessPrecision::Round, ZeroAmounts::Skip, false, |_| {})
+}
+
+// Like `stream_csv`, reading amounts as `Decimal` so digits past the fourth place are kept.
+pub fn stream_csv_decimal<R: io::Read>(
+    reader: R,
+) -> impl Iterator<Item = Result<Transaction<Decimal>, TransactionError>> {
+    csv_transactions(reader, ExcessPrecision::Round, ZeroAmounts::Skip, false, |_| {})
+}
+
+fn csv_transactions<A, R, F>(
+    reader: R,
+    precision: ExcessPrecision,
+    zero_amounts: ZeroAmounts,
+    require_amount: bool,
+    mut on_truncate: F,
+) -> impl Iterator<Item = Result<Transaction<A>, TransactionError>>
+where
+    A: Amount + DeserializeOwned,
+    R: io::Read,
+    F: FnMut(Truncation),
+{
+    let (delimiter, reader) = sniff_delimiter(reader);
+    let mut rdr = csv::ReaderBuilder::new().trim(Trim::All).delimiter(delimiter).from_reader(reader);
+    // A header that can't be read leaves no headers, so every row then fails to deserialize.
+    let headers = rdr.headers().cloned().unwrap_or_default();
+    // A bad header is reported once, in place of rows that would all be misread.
+    let header_error = validate_headers(&headers).err();
+    let amount_column = headers.iter().position(|header| header == "amount");
+    let rows = if header_error.is_none() { Some(rdr.into_records()) } else { None };
+    header_error.map(Err).into_iter().chain(rows.into_iter().flatten().map(move |row| {
+        let mut row = row?;
+        let line = row.position().map_or(0, |position| position.line());
+        let amount = amount_column.and_then(|column| row.get(column));
+        if let (Some(column), Some(limited)) = (amount_column, limit_precision(amount, line, precision)?) {
+            let original = amount.unwrap_or_default().to_string();
+            row = with_amount(&row, column, &limited);
+            on_truncate(Truncation { line, original, truncated: limited });
+        }
+        check_amount(deserialize_row(&row, &headers)?, line, require_amount, zero_amounts)
+    }))
+}
+
+// A copy of `row` with `amount` in the amount column, keeping the row's position for errors.
+fn with_amount(row: &csv::StringRecord, column: usize, amount: &str) -> csv::StringRecord {
+    let mut replaced: csv::StringRecord =
+        row.iter().enumerate().map(|(index, field)| if index == column { amount } else { field }).collect();
+    replaced.set_position(row.position().cloned());
+    replaced
+}
+
+// The columns of a transactions CSV. The first four are required.
+const CSV_COLUMNS: [&str; 7] = ["type", "client", "tx", "amount", "seq", "timestamp", "target"];
+
+// Checks the header up front, in any column order: a missing or misspelled column would
+// otherwise only show up as rows that fail to parse, or worse, as amounts read as absent.
+// Empty input has no header at all and is left alone.
+fn validate_headers(headers: &csv::StringRecord) -> Result<(), TransactionError> {
+    if headers.is_empty() {
+        return Ok(());
+    }
+    if let Some(unknown) = headers.iter().find(|header| !CSV_COLUMNS.contains(header)) {
+        return Err(TransactionError::InvalidHeader { reason: format!("unknown column {:?}", unknown) });
+    }
+    let missing: Vec<&str> = CSV_COLUMNS[..4]
+        .iter()
+        .copied()
+        .filter(|column| !headers.iter().any(|header| header == *column))
+        .collect();
+    if !missing.is_empty() {
+        return Err(TransactionError::InvalidHeader { reason: format!("missing column {}", missing.join(", ")) });
+    }
+    Ok(())
+}
+
+// What to do with an amount that has more than the four decimal places the spec allows.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ExcessPrecision {
+    // Round half away from zero, as `Money` parsing always does.
+    #[default]
+    Round,
+    // Drop the extra digits. Each truncated amount is reported as a `Truncation`.
+    Truncate,
+    // Fail the row.
+    Reject,
+}
+
+impl FromStr for ExcessPrecision {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "round" => Ok(ExcessPrecision::Round),
+            "truncate" => Ok(ExcessPrecision::Truncate),
+            "reject" => Ok(ExcessPrecision::Reject),
+            other => Err(format!("unknown precision policy {}", other)),
+        }
+    }
+}
+
+// What to do with a deposit, withdrawal or transfer of exactly zero, or of an amount that rounds to it.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ZeroAmounts {
+    // Read it and let the engine skip it, as it does a negative amount.
+    #[default]
+    Skip,
+    // Fail the row.
+    Reject,
+}
+
+impl FromStr for ZeroAmounts {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "skip" => Ok(ZeroAmounts::Skip),
+            "reject" => Ok(ZeroAmounts::Reject),
+            other => Err(format!("unknown zero amount policy {}", other)),
+        }
+    }
+}
+
+// An amount cut down to four decimal places under `ExcessPrecision::Truncate`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Truncation {
+    pub line: u64,
+    pub original: String,
+    pub truncated: String,
+}
+
+impl fmt::Display for Truncation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "amount {} on line {} truncated to {}", self.original, self.line, self.truncated)
+    }
+}
+
+// Applies `precision` to the amount read on `line`. Returns the text to parse in its place, or
+// `None` if the amount can be parsed as it is. Anything that isn't a plain decimal is left for
+// `Money` parsing to reject.
+fn limit_precision(
+    amount: Option<&str>,
+    line: u64,
+    precision: ExcessPrecision,
+) -> Result<Option<String>, TransactionError> {
+    let (whole, fraction) = match amount.and_then(|amount| amount.split_once('.')) {
+        Some((whole, fraction))
+            if fraction.len() > MONEY_DECIMALS && fraction.bytes().all(|byte| byte.is_ascii_digit()) =>
+        {
+            (whole, fraction)
+        }
+        _ => return Ok(None),
+    };
+    match precision {
+        ExcessPrecision::Round => Ok(None),
+        ExcessPrecision::Truncate => {
+            Ok(Some(format!("{}.{}", whole, &fraction[..MONEY_DECIMALS])))
+        }
+        ExcessPrecision::Reject => Err(TransactionError::MalformedRow {
+            line,
+            reason: format!("amount {}.{} has more than {} decimal places", whole, fraction, MONEY_DECIMALS),
+        }),
+    }
+}
+
+// Deserializes one CSV row, reporting a client id too large for a `u16` as such rather than as
+// a generic parse failure: upstream systems sometimes export 32-bit client ids.
+fn deserialize_row<A: Amount + DeserializeOwned>(
+    row: &csv::StringRecord,
+    headers: &csv::StringRecord,
+) -> Result<Transaction<A>, TransactionError> {
+    row.deserialize(Some(headers)).map_err(|error| {
+        let client = headers.iter().position(|header| header == "client").and_then(|column| row.get(column));
+        match client.and_then(|client| client.parse::<u64>().ok()) {
+            Some(client) if client > u64::from(u16::MAX) => TransactionError::ClientOutOfRange {
+                line: row.position().map_or(0, |position| position.line()),
+                client,
+            },
+            _ => error.into(),
+        }
+    })
+}
+
+// Newline-delimited JSON input: one transaction object per line, blank lines ignored. A numeric
+// amount is handed over as its decimal text so it is parsed exactly like a CSV amount.
+pub fn stream_ndjson<R: BufRead>(reader: R) -> impl Iterator<Item = Result<Transaction, TransactionError>> {
+    ndjson_transactions(reader, ExcessPrecision::Round, ZeroAmounts::Skip, |_| {})
+}
+
+fn ndjson_transactions<R: BufRead, F: FnMut(Truncation)>(
+    reader: R,
+    precision: ExcessPrecision,
+    zero_amounts: ZeroAmounts,
+    mut on_truncate: F,
+) -> impl Iterator<Item = Result<Transaction, TransactionError>> {
+    reader.lines().enumerate().filter_map(move |(index, line)| {
+        let line_number = index as u64 + 1;
+        let line = match line {
+            Ok(line) if line.trim().is_empty() => return None,
+            Ok(line) => line,
+            Err(error) => return Some(Err(TransactionError::Io(error))),
+        };
+        // Files saved with a byte order mark carry it in front of the first line.
+        let line = if index == 0 { line.trim_start_matches('\u{feff}') } else { &line };
+        let mut value = match serde_json::from_str::<serde_json::Value>(line) {
+            Ok(value) => value,
+            Err(error) => return Some(Err(TransactionError::row(line_number, error.to_string()))),
+        };
+        match value.get("client").and_then(serde_json::Value::as_u64) {
+            Some(client) if client > u64::from(u16::MAX) => {
+                return Some(Err(TransactionError::ClientOutOfRange { line: line_number, client }));
+            }
+            _ => {}
+        }
+        if let Some(amount) = value.get_mut("amount") {
+            if let serde_json::Value::Number(number) = amount {
+                *amount = serde_json::Value::String(number.to_string());
+            }
+            match limit_precision(amount.as_str(), line_number, precision) {
+                Ok(Some(limited)) => {
+                    let original = amount.as_str().unwrap_or_default().to_string();
+                    on_truncate(Truncation { line: line_number, original, truncated: limited.clone() });
+                    *amount = serde_json::Value::String(limited);
+                }
+                Ok(None) => {}
+                Err(error) => return Some(Err(error)),
+            }
+        }
+        let transaction = match Transaction::deserialize(value) {
+            Ok(transaction) => transaction,
+            Err(error) => return Some(Err(TransactionError::row(line_number, error.to_string()))),
+        };
+        Some(check_amount(transaction, line_number, false, zero_amounts))
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputFormat {
+    Csv,
+    Ndjson,
+}
+
+impl InputFormat {
+    // `.json` and `.ndjson` files are read as NDJSON, anything else as CSV. A `.gz` suffix is
+    // looked through, so `feed.ndjson.gz` is NDJSON too.
+    pub fn from_path(path: &Path) -> InputFormat {
+        let path = match path.file_stem() {
+            Some(stem) if path.extension().is_some_and(|extension| extension == "gz") => Path::new(stem),
+            _ => path,
+        };
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") | Some("ndjson") => InputFormat::Ndjson,
+            _ => InputFormat::Csv,
+        }
+    }
+}
+
+impl FromStr for InputFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "csv" => Ok(InputFormat::Csv),
+            "ndjson" => Ok(InputFormat::Ndjson),
+            other => Err(format!("unknown input format {}", other)),
+        }
+    }
+}
+
+// Streams transactions in whichever format the input is in, handling amounts with too many
+// decimal places as `precision` says.
+pub fn stream_transactions<'a, R: io::Read + 'a>(
+    reader: R,
+    format: InputFormat,
+    precision: ExcessPrecision,
+) -> Box<dyn Iterator<Item = Result<Transaction, TransactionError>> + 'a> {
+    stream_transactions_noting_truncations(reader, format, precision, ZeroAmounts::Skip, |_| {})
+}
+
+// Like `stream_transactions`, handling zero amounts as `zero_amounts` says and handing every
+// amount cut down under `ExcessPrecision::Truncate` to `on_truncate` as it is read.
+pub fn stream_transactions_noting_truncations<'a, R: io::Read + 'a, F: FnMut(Truncation) + 'a>(
+    reader: R,
+    format: InputFormat,
+    precision: ExcessPrecision,
+    zero_amounts: ZeroAmounts,
+    on_truncate: F,
+) -> Box<dyn Iterator<Item = Result<Transaction, TransactionError>> + 'a> {
+    match format {
+        InputFormat::Csv => Box::new(csv_transactions(reader, precision, zero_amounts, false, on_truncate)),
+        InputFormat::Ndjson => {
+            Box::new(ndjson_transactions(io::BufReader::new(reader), precision, zero_amounts, on_truncate))
+        }
+    }
+}
+
+// Like `read_csv_file`, but rows whose type column holds `marker` (e.g. `total,,,1234.56`) are
+// treated as a control trailer rather than a transaction. The trailer's amount is returned as
+// the control total so it can be reconciled against the transactions that were read.
+pub fn read_transactions_with_trailer<R: io::Read>(
+    reader: R,
+    marker: &str,
+) -> Result<(Vec<Transaction>, Option<Money>), TransactionError> {
+    let (delimiter, reader) = sniff_delimiter(reader);
+    let mut rdr = csv::ReaderBuilder::new().trim(Trim::All).delimiter(delimiter).from_reader(reader);
+    let headers = rdr.headers()?.clone();
+    validate_headers(&headers)?;
+    let type_column = headers.iter().position(|header| header == "type");
+    let amount_column = headers.iter().position(|header| header == "amount");
+    let mut transactions = vec![];
+    let mut control_total = None;
+    for row in rdr.records() {
+        let row = row?;
+        if type_column.and_then(|column| row.get(column)) == Some(marker) {
+            let total = amount_column
+                .and_then(|column| row.get(column))
+                .and_then(|value| value.parse::<Money>().ok())
+                .ok_or_else(|| TransactionError::MalformedRow {
+                    line: row.position().map_or(0, |position| position.line()),
+                    reason: "control trailer without a valid amount".to_string(),
+                })?;
+            control_total = Some(total);
+            continue;
+        }
+        let line = row.position().map_or(0, |position| position.line());
+        transactions.push(check_amount(deserialize_row(&row, &headers)?, line, true, ZeroAmounts::Skip)?);
+    }
+    Ok((transactions, control_total))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum RowErrorKind {
+    Parse,
+    MissingAmount,
+    // A deposit, withdrawal or transfer of zero or less.
+    InvalidAmount,
+    ClientOutOfRange,
+    UnknownType,
+    DuplicateTransaction,
+    // Only under `ExcessPrecision::Reject`.
+    ExcessPrecision,
+}
+
+impl fmt::Display for RowErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            RowErrorKind::Parse => "parse error",
+            RowErrorKind::MissingAmount => "missing amount",
+            RowErrorKind::InvalidAmount => "amount not above zero",
+            RowErrorKind::ClientOutOfRange => "client id out of range",
+            RowErrorKind::UnknownType => "unknown type",
+            RowErrorKind::DuplicateTransaction => "duplicate transaction id",
+            RowErrorKind::ExcessPrecision => "too many decimal places",
+        };
+        f.write_str(description)
+    }
+}
+
+fn classify_row(
+    row: &csv::StringRecord,
+    headers: &csv::StringRecord,
+    precision: ExcessPrecision,
+) -> Result<Transaction, RowErrorKind> {
+    let column = |name: &str| headers.iter().position(|header| header == name);
+    let field = |name: &str| column(name).and_then(|column| row.get(column));
+    if field("type").and_then(TransactionType::from_name).is_none() {
+        return Err(RowErrorKind::UnknownType);
+    }
+    if let Some(client) = field("client") {
+        if client.parse::<u16>().is_err() && client.parse::<u64>().is_ok() {
+            return Err(RowErrorKind::ClientOutOfRange);
+        }
+    }
+    let line = row.position().map_or(0, |position| position.line());
+    let limited = limit_precision(field("amount"), line, precision).map_err(|_| RowErrorKind::ExcessPrecision)?;
+    let row = match (column("amount"), limited) {
+        (Some(column), Some(limited)) => with_amount(row, column, &limited),
+        _ => row.clone(),
+    };
+    let transaction: Transaction = row.deserialize(Some(headers)).map_err(|_| RowErrorKind::Parse)?;
+    match transaction.transaction_type {
+        transaction_type if transaction_type.moves_funds() && transaction.invalid_amount.is_some() => {
+            Err(RowErrorKind::InvalidAmount)
+        }
+        transaction_type if transaction_type.moves_funds() && transaction.amount.is_none() => {
+            Err(RowErrorKind::MissingAmount)
+        }
+        _ => Ok(transaction),
+    }
+}
+
+// Reads every row it can, skipping the ones that are malformed and tallying why each was skipped.
+pub fn read_transactions_skipping_errors<R: io::Read>(
+    reader: R,
+) -> Result<(Vec<Transaction>, HashMap<RowErrorKind, usize>), TransactionError> {
+    skip_errors(reader, ExcessPrecision::Round)
+}
+
+// `read_transactions_skipping_errors`, handling amounts with too many decimal places as
+// `precision` says.
+fn skip_errors<R: io::Read>(
+    reader: R,
+    precision: ExcessPrecision,
+) -> Result<(Vec<Transaction>, HashMap<RowErrorKind, usize>), TransactionError> {
+    let (delimiter, reader) = sniff_delimiter(reader);
+    let mut rdr = csv::ReaderBuilder::new().trim(Trim::All).flexible(true).delimiter(delimiter).from_reader(reader);
+    let headers = rdr.headers()?.clone();
+    validate_headers(&headers)?;
+    let mut transactions = vec![];
+    let mut errors = HashMap::new();
+    for row in rdr.records() {
+        let classified = match row {
+            Ok(row) => classify_row(&row, &headers, precision),
+            Err(_) => Err(RowErrorKind::Parse),
+        };
+        match classified {
+            Ok(transaction) => transactions.push(transaction),
+            Err(kind) => *errors.entry(kind).or_insert(0) += 1,
+        }
+    }
+    Ok((transactions, errors))
+}
+
+// A row that could not be read, kept so every bad row can be reported in one go.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RowError {
+    pub line: u64,
+    pub message: String,
+}
+
+impl fmt::Display for RowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+// Like `read_csv_file`, but a bad row is recorded and skipped instead of ending the read, so one
+// pass finds every malformed row. Problems with the input as a whole, such as an invalid header
+// or a failed read, still fail the read.
+pub fn read_transactions_collecting_errors<R: io::Read>(
+    reader: R,
+) -> Result<(Vec<Transaction>, Vec<RowError>), TransactionError> {
+    let mut transactions = vec![];
+    let mut errors = vec![];
+    for transaction in stream_csv(reader) {
+        match transaction {
+            Ok(transaction) => transactions.push(transaction),
+            Err(error) => match error.line() {
+                Some(line) => errors.push(RowError { line, message: error.to_string() }),
+                None => return Err(error),
+            },
+        }
+    }
+    Ok((transactions, errors))
+}
+
+// Validates every row without applying anything: on top of what `read_transactions_skipping_errors`
+// rejects, a deposit, withdrawal or transfer reusing an earlier id counts as an error. Ids are
+// compared the way the engine would under `config`, so client-scoped ids only clash per client,
+// and an amount with too many decimal places is only an error if `precision` rejects it.
+pub fn validate_transactions<R: io::Read>(
+    reader: R,
+    config: &EngineConfig,
+    precision: ExcessPrecision,
+) -> Result<HashMap<RowErrorKind, usize>, TransactionError> {
+    let (transactions, mut errors) = skip_errors(reader, precision)?;
+    let mut seen = HashSet::new();
+    for transaction in transactions {
+        let scope = if config.client_scoped_tx_ids { transaction.client } else { 0 };
+        if transaction.transaction_type.moves_funds() && !seen.insert((scope, transaction.tx)) {
+            *errors.entry(RowErrorKind::DuplicateTransaction).or_insert(0) += 1;
+        }
+    }
+    Ok(errors)
+}
+
+pub fn write_error_summary<W: Write>(
+    errors: &HashMap<RowErrorKind, usize>,
+    title: &str,
+    writer: &mut W,
+) -> io::Result<()> {
+    let mut kinds: Vec<&RowErrorKind> = errors.keys().collect();
+    kinds.sort();
+    writeln!(writer, "{}: {}", title, errors.values().sum::<usize>())?;
+    for kind in kinds {
+        writeln!(writer, "  {}: {}", kind, errors[kind])?;
+    }
+    Ok(())
+}
+
+// Sum of every deposit and withdrawal amount, the figure a control trailer is checked against.
+pub fn amount_total(transactions: &[Transaction]) -> Money {
+    transactions
+        .iter()
+        .filter_map(|transaction| transaction.amount)
+        .map(PositiveAmount::value)
+        .sum()
+}
+
+// Keeps only one client's transactions. Accounts never interact, so processing the filtered
+// stream yields exactly that client's account from a full run.
+pub fn filter_client(transactions: Vec<Transaction>, client: u16) -> Vec<Transaction> {
+    transactions
+        .into_iter()
+        .filter(|transaction| transaction.client == client)
+        .collect()
+}
+
+// Orders transactions by their `seq` column. The sort is stable, so rows sharing a sequence
+// number (or all lacking one) keep their file order; rows without a sequence number sort first.
+pub fn sort_by_sequence(transactions: &mut [Transaction]) {
+    transactions.sort_by_key(|transaction| transaction.seq);
+}
+
+// Orders transactions chronologically by their `timestamp` column. Like `sort_by_sequence` the
+// sort is stable, so rows without a timestamp keep their file order and sort first.
+pub fn sort_by_timestamp(transactions: &mut [Transaction]) {
+    transactions.sort_by_key(|transaction| transaction.timestamp);
+}
+
+#[derive(Debug, Clone)]
+pub struct EngineConfig<A = Money> {
+    // Deposits that would take an account's available funds above this are treated as feed
+    // corruption: they are rejected instead of being applied.
+    pub max_balance: Option<A>,
+    // Whether a transaction that was disputed and then resolved can be disputed again. When
+    // allowed, the second dispute is treated like a fresh one and holds the funds again.
+    pub allow_redispute: bool,
+    // When set, only these clients' transactions are processed. Transactions for any other
+    // client are rejected and counted without creating an account.
+    pub allowed_clients: Option<HashSet<u16>>,
+    // Disputing a deposit whose funds were already withdrawn drives available negative. When
+    // allowed (the default) the dispute still holds the full amount; otherwise it is ignored.
+    pub allow_negative_available: bool,
+    // Keeps every applied transaction per client so the final accounts can be recomputed from
+    // scratch and checked against the incrementally maintained ones. Expensive; off by default.
+    pub verify_replay: bool,
+    // A deposit or withdrawal without an amount is normally skipped on its own. With isolation
+    // the whole client is treated as corrupt and quarantined instead: its account is dropped
+    // from the output and its later transactions are ignored, while every other client is
+    // processed as usual.
+    pub isolate_clients: bool,
+    // Transaction ids are normally unique across the whole input. Some upstreams only keep them
+    // unique per client; with this set, two clients can each use the same id.
+    pub client_scoped_tx_ids: bool,
+    // Some feeds are slightly out of order, so a dispute can arrive before the deposit or
+    // withdrawal it refers to. With this set such a dispute is held back and applied as soon as
+    // that transaction is processed, instead of being dropped.
+    pub buffer_early_disputes: bool,
+    // Keeps a record of transactions the engine refused, such as withdrawals beyond the available
+    // funds or disputes of unknown transactions, which otherwise leave no trace. See
+    // `TransactionEngine::events`.
+    pub record_events: bool,
+    // Keeps an entry for every transaction saying what became of it. See
+    // `TransactionEngine::outcomes`.
+    pub record_outcomes: bool,
+    // Caps how many disputes a client can have open at once. Further disputes are ignored until
+    // one of the open ones is resolved or charged back. Unlimited by default.
+    pub max_open_disputes: Option<usize>,
+}
+
+impl<A> EngineConfig<A> {
+    // The same settings for an engine over another amount type.
+    pub fn map_amounts<B, F: FnOnce(A) -> B>(self, f: F) -> EngineConfig<B> {
+        EngineConfig {
+            max_balance: self.max_balance.map(f),
+            allow_redispute: self.allow_redispute,
+            allowed_clients: self.allowed_clients,
+            allow_negative_available: self.allow_negative_available,
+            verify_replay: self.verify_replay,
+            isolate_clients: self.isolate_clients,
+            client_scoped_tx_ids: self.client_scoped_tx_ids,
+            buffer_early_disputes: self.buffer_early_disputes,
+            record_events: self.record_events,
+            record_outcomes: self.record_outcomes,
+            max_open_disputes: self.max_open_disputes,
+        }
+    }
+}
+
+impl<A> Default for EngineConfig<A> {
+    fn default() -> Self {
+        EngineConfig {
+            max_balance: None,
+            allow_redispute: true,
+            allowed_clients: None,
+            allow_negative_available: true,
+            verify_replay: false,
+            isolate_clients: false,
+            client_scoped_tx_ids: false,
+            buffer_early_disputes: false,
+            record_events: false,
+            record_outcomes: false,
+            max_open_disputes: None,
+        }
+    }
+}
+
+// Reads an allow list: one client id per line, blank lines ignored.
+pub fn read_client_list<R: BufRead>(reader: R) -> io::Result<HashSet<u16>> {
+    let mut clients = HashSet::new();
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let client = line
+            .parse::<u16>()
+            .map_err(|_| Error::new(ErrorKind::InvalidData, format!("invalid client id {}", line)))?;
+        clients.insert(client);
+    }
+    Ok(clients)
+}
+
+/// Applies `transactions` in order to empty accounts with the default configuration and returns
+/// the resulting accounts keyed by client, which iterate in client id order.
+pub fn process_transactions<A: Amount>(transactions: Vec<Transaction<A>>) -> BTreeMap<u16, Account<A>> {
+    TransactionEngine::with_accounts(BTreeMap::new(), EngineConfig::default()).process(transactions)
+}
+
+/// Like `process_transactions`, but applies the batch on top of an earlier run's accounts and
+/// processed transactions, as returned by a previous call. Either can be left out to start empty.
+pub fn process_transactions_with_state<A: Amount>(
+    transactions: Vec<Transaction<A>>,
+    accounts: Option<BTreeMap<u16, Account<A>>>,
+    processed: Option<ProcessedTransactions<A>>,
+) -> (BTreeMap<u16, Account<A>>, ProcessedTransactions<A>) {
+    let (accounts, processed) = (accounts.unwrap_or_default(), processed.unwrap_or_default());
+    let mut engine = TransactionEngine::with_state(accounts, processed, EngineConfig::default());
+    engine.apply_all(transactions, None);
+    engine.into_state()
+}
+
+/// Like `process_transactions`, but also returns what became of each transaction, in order, along
+/// with the client's balances right after it.
+pub fn process_transactions_with_outcomes<A: Amount>(
+    transactions: Vec<Transaction<A>>,
+) -> (BTreeMap<u16, Account<A>>, Vec<AppliedEvent<A>>) {
+    let config = EngineConfig { record_outcomes: true, ..EngineConfig::default() };
+    let mut engine = TransactionEngine::with_accounts(BTreeMap::new(), config);
+    engine.apply_all(transactions, None);
+    let outcomes = std::mem::take(&mut engine.outcomes);
+    (engine.into_accounts(), outcomes)
+}
+
+/// Like `process_transactions`, but reads CSV rows from `reader` and applies each one as soon as
+/// it is deserialized, so only the accounts and the disputable transactions stay in memory.
+/// Stops at the first row that fails to parse.
+pub fn process_transactions_streaming<R: io::Read>(reader: R) -> Result<BTreeMap<u16, Account>, TransactionError> {
+    let mut engine = TransactionEngine::new(EngineConfig::default());
+    engine.apply_stream(stream_csv(reader), None)?;
+    Ok(engine.into_accounts())
+}
+
+#[cfg(test)]
+fn process_transactions_from(
+    accounts: BTreeMap<u16, Account>,
+    transactions: Vec<Transaction>,
+    config: &EngineConfig,
+) -> BTreeMap<u16, Account> {
+    let mut engine = TransactionEngine::with_accounts(accounts, config.clone());
+    engine.apply_all(transactions, None);
+    let ledger = *engine.ledger();
+    let accounts = engine.into_accounts();
+    check_invariants(&accounts, &ledger).unwrap();
+    accounts
+}
+
+// How many transactions are applied between checks of the processing deadline.
+const DEADLINE_CHECK_INTERVAL: usize = 1024;
+
+// What a later dispute, resolve or chargeback needs from a deposit or withdrawal. One of these is
+// kept per processed transaction, so it holds far less than the full `Transaction` row.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ProcessedTransaction<A = Money> {
+    transaction_type: TransactionType,
+    client: u16,
+    amount: A,
+}
+
+// Processed deposits and withdrawals by client and transaction id.
+pub type ProcessedTransactions<A = Money> = HashMap<(u16, u32), ProcessedTransaction<A>>;
+
+impl<A> ProcessedTransaction<A> {
+    // Only the client who owns a deposit or withdrawal can dispute, resolve or charge it back;
+    // a tx id quoted by any other client is ignored so it can't move funds on the wrong account.
+    fn disputable_by(&self, client: u16) -> bool {
+        self.transaction_type.is_disputable() && self.client == client
+    }
+}
+
+// Where the money across all accounts came from, kept by the engine independently of the accounts
+// so the two can be reconciled with `check_invariants`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Ledger<A = Money> {
+    // The combined balance of the accounts the engine started with.
+    pub opening: A,
+    pub deposited: A,
+    pub withdrawn: A,
+    // Deposits taken back out by a chargeback, and withdrawals returned by one.
+    pub deposits_charged_back: A,
+    pub withdrawals_charged_back: A,
+    // Withdrawals under dispute are held on top of the balance until the dispute settles.
+    pub withdrawals_disputed: A,
+    // Balances dropped along with quarantined accounts.
+    pub written_off: A,
+}
+
+// Every figure saturates like an account's running totals, so input near the limits of `A` can't
+// wrap one around.
+impl<A: Amount> Ledger<A> {
+    // What the accounts should hold between them, or `None` if that is beyond what `A` can hold.
+    pub fn expected_total(&self) -> Option<A> {
+        let paid_in = self
+            .opening
+            .saturating_add(self.deposited)
+            .saturating_add(self.withdrawals_charged_back)
+            .saturating_add(self.withdrawals_disputed);
+        let paid_out = self.withdrawn.saturating_add(self.deposits_charged_back).saturating_add(self.written_off);
+        paid_in.checked_sub(paid_out)
+    }
+
+    fn merge(&mut self, other: Ledger<A>) {
+        self.opening = self.opening.saturating_add(other.opening);
+        self.deposited = self.deposited.saturating_add(other.deposited);
+        self.withdrawn = self.withdrawn.saturating_add(other.withdrawn);
+        self.deposits_charged_back = self.deposits_charged_back.saturating_add(other.deposits_charged_back);
+        self.withdrawals_charged_back = self.withdrawals_charged_back.saturating_add(other.withdrawals_charged_back);
+        self.withdrawals_disputed = self.withdrawals_disputed.saturating_add(other.withdrawals_disputed);
+        self.written_off = self.written_off.saturating_add(other.written_off);
+    }
+
+    // A disputed withdrawal that settles. Its amount went into `withdrawals_disputed` when the
+    // dispute opened, so this never goes below zero; it only stops there if that sum saturated.
+    fn settle_disputed_withdrawal(&mut self, amount: A) {
+        self.withdrawals_disputed = self.withdrawals_disputed.checked_sub(amount).unwrap_or_default();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InvariantViolation<A = Money> {
+    // `None` if the ledger's total is out of range.
+    pub expected: Option<A>,
+    pub actual: A,
+}
+
+impl<A: Amount> fmt::Display for InvariantViolation<A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.expected {
+            Some(expected) => {
+                write!(f, "accounts hold {} in total but the ledger accounts for {}", self.actual, expected)
+            }
+            None => write!(f, "accounts hold {} in total but the ledger's total is out of range", self.actual),
+        }
+    }
+}
+
+impl<A: Amount> std::error::Error for InvariantViolation<A> {}
+
+// Checks that available plus held across every account adds up to what the ledger says went in
+// and out. Any difference means funds were created or lost along the way.
+pub fn check_invariants<A: Amount>(
+    accounts: &BTreeMap<u16, Account<A>>,
+    ledger: &Ledger<A>,
+) -> Result<(), InvariantViolation<A>> {
+    let actual = accounts.values().fold(A::default(), |sum, account| sum.saturating_add(account.total()));
+    let expected = ledger.expected_total();
+    if expected == Some(actual) {
+        Ok(())
+    } else {
+        Err(InvariantViolation { expected, actual })
+    }
+}
+
+// A transaction the engine refused, recorded when `EngineConfig::record_events` is set.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EngineEvent<A = Money> {
+    // A withdrawal larger than the account's available funds.
+    IgnoredWithdrawal { client: u16, tx: u32, amount: A },
+    // Any other transaction that changed nothing.
+    Skipped { transaction_type: TransactionType, client: u16, tx: u32, reason: SkipReason },
+}
+
+// Why a transaction was skipped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SkipReason {
+    // Its id was already used by an earlier deposit, withdrawal or transfer.
+    Duplicate,
+    // The client, or a transfer's target, isn't on the allow list.
+    UnlistedClient,
+    // The client, or a transfer's target, was quarantined under `isolate_clients`.
+    Quarantined,
+    // A deposit, withdrawal or transfer without an amount.
+    MissingAmount,
+    // A deposit, withdrawal or transfer of zero or less.
+    InvalidAmount,
+    // A transfer without a target, or paying into its own account.
+    NoTarget,
+    // The balance would go over `max_balance`, or overflow.
+    AboveMaxBalance,
+    // A dispute, resolve or chargeback for an id the client has no deposit or withdrawal under,
+    // or for one that was already charged back.
+    NotFound,
+    // The transaction it refers to is not a deposit or withdrawal.
+    WrongType,
+    AccountFrozen,
+    AlreadyDisputed,
+    // Resolves and chargebacks need an open dispute.
+    NotDisputed,
+    // A withdrawal or transfer beyond the available funds, or a dispute that can't hold the full
+    // deposit without `allow_negative_available`.
+    InsufficientFunds,
+    // Only without `allow_redispute`.
+    AlreadyResolved,
+    // The client already has `max_open_disputes` open.
+    TooManyDisputes,
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            SkipReason::Duplicate => "duplicate transaction id",
+            SkipReason::UnlistedClient => "client not on the allow list",
+            SkipReason::Quarantined => "client is quarantined",
+            SkipReason::MissingAmount => "missing amount",
+            SkipReason::InvalidAmount => "amount is not above zero",
+            SkipReason::NoTarget => "no other client to pay into",
+            SkipReason::AboveMaxBalance => "balance would go over the maximum",
+            SkipReason::NotFound => "transaction not found",
+            SkipReason::WrongType => "transaction is not a deposit or withdrawal",
+            SkipReason::AccountFrozen => "account is frozen",
+            SkipReason::AlreadyDisputed => "transaction is already disputed",
+            SkipReason::NotDisputed => "transaction is not disputed",
+            SkipReason::InsufficientFunds => "insufficient funds",
+            SkipReason::AlreadyResolved => "transaction was already resolved",
+            SkipReason::TooManyDisputes => "client has too many open disputes",
+        };
+        f.write_str(description)
+    }
+}
+
+impl<A: Amount> fmt::Display for EngineEvent<A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EngineEvent::IgnoredWithdrawal { client, tx, amount } => {
+                write!(f, "ignored withdrawal tx {} for client {}: insufficient funds for {}", tx, client, amount)
+            }
+            EngineEvent::Skipped { transaction_type, client, tx, reason } => {
+                write!(f, "ignored {} tx {} for client {}: {}", transaction_type.name(), tx, client, reason)
+            }
+        }
+    }
+}
+
+// What became of a transaction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    Applied,
+    Ignored(SkipReason),
+    // An early dispute held back under `buffer_early_disputes`. It gets an outcome of its own once
+    // its transaction arrives.
+    Deferred,
+}
+
+// One entry of the audit log kept under `EngineConfig::record_outcomes`: a transaction, what
+// became of it and the client's balances right after.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AppliedEvent<A = Money> {
+    pub transaction_type: TransactionType,
+    pub client: u16,
+    pub tx: u32,
+    pub outcome: Outcome,
+    pub available: A,
+    pub held: A,
+}
+
+// Holds the state needed to apply transactions one at a time: the accounts themselves plus the
+// deposits and withdrawals that later disputes, resolves and chargebacks refer back to.
+pub struct TransactionEngine<A = Money> {
+    config: EngineConfig<A>,
+    accounts: BTreeMap<u16, Account<A>>,
+    // Keyed by client as well as id. Only the owning client can refer back to a transaction, so
+    // this finds the same transactions whether ids are unique globally or only per client.
+    processed_transactions: ProcessedTransactions<A>,
+    resolved_transactions: HashSet<(u16, u32)>,
+    // Every deposit and withdrawal id seen so far, whether or not it was applied, so a repeated id
+    // is skipped rather than overwriting the first. See `seen_key` for how ids are scoped.
+    seen_transactions: HashSet<(u16, u32)>,
+    // Transactions rejected because their client isn't on the allow list.
+    unlisted_client_transactions: usize,
+    // Resolves and chargebacks that had no open dispute to settle, for data-quality monitoring.
+    ignored_resolves: usize,
+    ignored_chargebacks: usize,
+    // Deposits, withdrawals and transfers skipped for an amount of zero or less.
+    invalid_amounts: usize,
+    // Every transaction that was ignored or refused for any reason, so callers can fail the run.
+    anomalies: usize,
+    // Only populated with `verify_replay`: the accounts as they were before any transaction was
+    // applied, and each client's transactions in the order they were applied.
+    starting_accounts: BTreeMap<u16, Account<A>>,
+    history: HashMap<u16, Vec<Transaction<A>>>,
+    // Clients quarantined under `isolate_clients`, with the transaction that corrupted them.
+    quarantined_clients: HashMap<u16, u32>,
+    // Clients in the order their accounts were opened; accounts loaded up front come first, by id.
+    client_order: Vec<u16>,
+    // Only populated with `buffer_early_disputes`: disputes waiting for the transaction they refer to.
+    pending_disputes: HashMap<(u16, u32), Transaction<A>>,
+    ledger: Ledger<A>,
+    // Only populated with `record_events`.
+    events: Vec<EngineEvent<A>>,
+    // Only populated with `record_outcomes`.
+    outcomes: Vec<AppliedEvent<A>>,
+}
+
+#[derive(Debug)]
+pub struct ReplayMismatch {
+    client: u16,
+    incremental: Box<Account>,
+    replayed: Option<Box<Account>>,
+}
+
+impl fmt::Display for ReplayMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "replay mismatch for client {}: incremental state {:?}, replayed state {:?}",
+            self.client, self.incremental, self.replayed
+        )
+    }
+}
+
+impl std::error::Error for ReplayMismatch {}
+
+// `new` and replay verification are only offered for `Money`, so `TransactionEngine::new(..)`
+// needs no type annotation; engines over other amounts are built with `with_accounts`.
+impl TransactionEngine {
+    pub fn new(config: EngineConfig) -> TransactionEngine {
+        TransactionEngine::with_accounts(BTreeMap::new(), config)
+    }
+
+    // Recomputes every account by replaying its client's transactions alone through a fresh
+    // engine, and fails on the first account whose replayed state differs from the live one.
+    pub fn verify_replay(&self) -> Result<(), ReplayMismatch> {
+        let mut config = self.config.clone();
+        config.verify_replay = false;
+        for &client in self.accounts.keys() {
+            let mut starting_accounts = BTreeMap::new();
+            if let Some(account) = self.starting_accounts.get(&client) {
+                starting_accounts.insert(client, account.clone());
+            }
+            let mut replay = TransactionEngine::with_accounts(starting_accounts, config.clone());
+            for transaction in self.history.get(&client).into_iter().flatten() {
+                replay.apply(transaction.clone());
+            }
+            let incremental = &self.accounts[&client];
+            let replayed = replay.accounts.remove(&client);
+            if replayed.as_ref() != Some(incremental) {
+                return Err(ReplayMismatch {
+                    client,
+                    incremental: Box::new(incremental.clone()),
+                    replayed: replayed.map(Box::new),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<A: Amount> TransactionEngine<A> {
+    pub fn with_accounts(accounts: BTreeMap<u16, Account<A>>, config: EngineConfig<A>) -> TransactionEngine<A> {
+        let starting_accounts = if config.verify_replay { accounts.clone() } else { BTreeMap::new() };
+        let client_order: Vec<u16> = accounts.keys().copied().collect();
+        let opening = accounts.values().fold(A::default(), |sum, account| sum.saturating_add(account.total()));
+        TransactionEngine {
+            config,
+            accounts,
+            processed_transactions: HashMap::new(),
+            resolved_transactions: HashSet::new(),
+            seen_transactions: HashSet::new(),
+            unlisted_client_transactions: 0,
+            ignored_resolves: 0,
+            ignored_chargebacks: 0,
+            invalid_amounts: 0,
+            anomalies: 0,
+            starting_accounts,
+            history: HashMap::new(),
+            quarantined_clients: HashMap::new(),
+            client_order,
+            pending_disputes: HashMap::new(),
+            ledger: Ledger { opening, ..Ledger::default() },
+            events: vec![],
+            outcomes: vec![],
+        }
+    }
+
+    // Picks up from an earlier run, so the new transactions can still dispute, resolve and charge
+    // back the ones it applied. Their ids count as seen too, so repeating one is a duplicate.
+    // Which disputes were already settled isn't carried over.
+    pub fn with_state(
+        accounts: BTreeMap<u16, Account<A>>,
+        processed_transactions: ProcessedTransactions<A>,
+        config: EngineConfig<A>,
+    ) -> TransactionEngine<A> {
+        let mut engine = TransactionEngine::with_accounts(accounts, config);
+        for &(client, tx) in processed_transactions.keys() {
+            let scope = if engine.config.client_scoped_tx_ids { client } else { 0 };
+            engine.seen_transactions.insert((scope, tx));
+        }
+        engine.processed_transactions = processed_transactions;
+        engine
+    }
+
+    // Human-readable summary of one client's account, for support tooling.
+    pub fn describe(&self, client: u16) -> String {
+        let account = match self.accounts.get(&client) {
+            Some(account) => account,
+            None => return format!("client {}: no account", client),
+        };
+        // Accounts loaded frozen from a baseline carry no reason.
+        let locked = match (account.frozen, account.freeze_reason()) {
+            (false, _) => "no",
+            (true, Some(FreezeReason::Chargeback)) => "yes (chargeback)",
+            (true, None) => "yes",
+        };
+        let open_disputes = if account.disputed_transactions.is_empty() {
+            "none".to_string()
+        } else {
+            account
+                .disputed_transactions
+                .iter()
+                .map(|tx| tx.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        format!(
+            "client {}\n  available: {}\n  held: {}\n  total: {}\n  locked: {}\n  open disputes: {}",
+            client,
+            account.available,
+            account.held,
+            account.total(),
+            locked,
+            open_disputes
+        )
+    }
+
+    // How many transactions were rejected because their client isn't on the allow list.
+    pub fn unlisted_client_transactions(&self) -> usize {
+        self.unlisted_client_transactions
+    }
+
+    // Clients quarantined under `isolate_clients`, by id, with the transaction that corrupted each.
+    pub fn quarantined_clients(&self) -> Vec<(u16, u32)> {
+        let mut quarantined: Vec<(u16, u32)> =
+            self.quarantined_clients.iter().map(|(&client, &tx)| (client, tx)).collect();
+        quarantined.sort_unstable();
+        quarantined
+    }
+
+    // How many disputes buffered under `buffer_early_disputes` are still waiting for their
+    // transaction. They are dropped if it never arrives.
+    pub fn pending_disputes(&self) -> usize {
+        self.pending_disputes.len()
+    }
+
+    // How many resolves had no open dispute to settle and so changed nothing.
+    pub fn ignored_resolves(&self) -> usize {
+        self.ignored_resolves
+    }
+
+    // How many deposits, withdrawals and transfers were skipped for an amount of zero or less.
+    pub fn invalid_amounts(&self) -> usize {
+        self.invalid_amounts
+    }
+
+    // How many chargebacks had no open dispute to settle and so changed nothing.
+    pub fn ignored_chargebacks(&self) -> usize {
+        self.ignored_chargebacks
+    }
+
+    // How many transactions changed nothing: duplicates, refused withdrawals and transfers,
+    // disputes of unknown transactions and the like. Disputes still waiting for their transaction
+    // count too, as they will never be applied if it doesn't turn up.
+    pub fn anomalies(&self) -> usize {
+        self.anomalies + self.pending_disputes.len()
+    }
+
+    // Refused transactions in the order they were applied, which `apply_all_parallel` keeps too.
+    pub fn events(&self) -> &[EngineEvent<A>] {
+        &self.events
+    }
+
+    // Every transaction's outcome in the order they were dealt with.
+    pub fn outcomes(&self) -> &[AppliedEvent<A>] {
+        &self.outcomes
+    }
+
+    pub fn into_accounts(self) -> BTreeMap<u16, Account<A>> {
+        self.accounts
+    }
+
+    pub fn ledger(&self) -> &Ledger<A> {
+        &self.ledger
+    }
+
+    // The accounts along with the transactions later disputes need, to resume from with `with_state`.
+    pub fn into_state(self) -> (BTreeMap<u16, Account<A>>, ProcessedTransactions<A>) {
+        (self.accounts, self.processed_transactions)
+    }
+
+    // Applies every transaction in order and returns the resulting accounts, for callers that
+    // have the whole input up front and need neither a deadline nor the engine afterwards.
+    pub fn process<I>(mut self, transactions: I) -> BTreeMap<u16, Account<A>>
+    where
+        I: IntoIterator<Item = Transaction<A>>,
+    {
+        self.apply_all(transactions, None);
+        self.into_accounts()
+    }
+
+    // The accounts along with their clients in first-seen order, for output that follows the input.
+    pub fn into_accounts_with_order(mut self) -> (BTreeMap<u16, Account<A>>, Vec<u16>) {
+        let accounts = &self.accounts;
+        self.client_order.retain(|client| accounts.contains_key(client));
+        (self.accounts, self.client_order)
+    }
+
+    // Splits the engine's state by client into `partitions` engines. Every piece of state belongs
+    // to exactly one client, so each engine can go on applying its own clients' transactions.
+    // Client-scoped seen ids go with their client too. Globally unique ones can't be split by
+    // client and all go to the first engine, so callers have to tell the others about any of
+    // them their clients reuse.
+    // Events and outcomes are left out: callers keep them to merge in input order.
+    fn into_partitions(self, partitions: NonZeroUsize) -> Vec<TransactionEngine<A>> {
+        let partition_of = |client: u16| usize::from(client) % partitions.get();
+        let mut engines: Vec<TransactionEngine<A>> = (0..partitions.get())
+            .map(|_| TransactionEngine::with_accounts(BTreeMap::new(), self.config.clone()))
+            .collect();
+        for (client, account) in self.accounts {
+            engines[partition_of(client)].accounts.insert(client, account);
+        }
+        for (client, tx) in self.resolved_transactions {
+            engines[partition_of(client)].resolved_transactions.insert((client, tx));
+        }
+        for ((client, tx), processed) in self.processed_transactions {
+            engines[partition_of(client)].processed_transactions.insert((client, tx), processed);
+        }
+        for (scope, tx) in self.seen_transactions {
+            let partition = if self.config.client_scoped_tx_ids { partition_of(scope) } else { 0 };
+            engines[partition].seen_transactions.insert((scope, tx));
+        }
+        for (client, account) in self.starting_accounts {
+            engines[partition_of(client)].starting_accounts.insert(client, account);
+        }
+        for (client, history) in self.history {
+            engines[partition_of(client)].history.insert(client, history);
+        }
+        for (client, tx) in self.quarantined_clients {
+            engines[partition_of(client)].quarantined_clients.insert(client, tx);
+        }
+        for ((client, tx), dispute) in self.pending_disputes {
+            engines[partition_of(client)].pending_disputes.insert((client, tx), dispute);
+        }
+        engines[0].unlisted_client_transactions = self.unlisted_client_transactions;
+        engines[0].ignored_resolves = self.ignored_resolves;
+        engines[0].ignored_chargebacks = self.ignored_chargebacks;
+        engines[0].invalid_amounts = self.invalid_amounts;
+        engines[0].anomalies = self.anomalies;
+        engines[0].ledger = self.ledger;
+        engines
+    }
+
+    // Reassembles an engine from the partitions produced by `into_partitions`.
+    fn from_partitions(config: EngineConfig<A>, partitions: Vec<TransactionEngine<A>>) -> TransactionEngine<A> {
+        let mut engine = TransactionEngine::with_accounts(BTreeMap::new(), config);
+        for partition in partitions {
+            engine.accounts.extend(partition.accounts);
+            engine.processed_transactions.extend(partition.processed_transactions);
+            engine.resolved_transactions.extend(partition.resolved_transactions);
+            engine.seen_transactions.extend(partition.seen_transactions);
+            engine.unlisted_client_transactions += partition.unlisted_client_transactions;
+            engine.ignored_resolves += partition.ignored_resolves;
+            engine.ignored_chargebacks += partition.ignored_chargebacks;
+            engine.invalid_amounts += partition.invalid_amounts;
+            engine.anomalies += partition.anomalies;
+            engine.starting_accounts.extend(partition.starting_accounts);
+            engine.history.extend(partition.history);
+            engine.quarantined_clients.extend(partition.quarantined_clients);
+            engine.pending_disputes.extend(partition.pending_disputes);
+            engine.ledger.merge(partition.ledger);
+        }
+        engine
+    }
+
+    // Like `apply_all`, but spreads clients across `threads` threads. A transaction only ever
+    // touches its own client's state, so as long as each client's transactions keep their order
+    // the result is the same as applying everything in sequence. Events and outcomes are put back
+    // in input order once the threads are done.
+    pub fn apply_all_parallel(
+        &mut self,
+        transactions: Vec<Transaction<A>>,
+        threads: NonZeroUsize,
+        deadline: Option<Instant>,
+    ) -> bool
+    where
+        A: Send,
+    {
+        // A transfer touches two clients, which may well land on different threads. With globally
+        // unique ids, whether a dispute is early depends on every client's ids seen so far, which
+        // no single thread has.
+        let global_early_disputes = self.config.buffer_early_disputes && !self.config.client_scoped_tx_ids;
+        if global_early_disputes
+            || transactions.iter().any(|transaction| transaction.transaction_type == TransactionType::Transfer)
+        {
+            return self.apply_all(transactions, deadline);
+        }
+        let partition_of = |client: u16| usize::from(client) % threads.get();
+        let mut batches: Vec<Vec<(usize, Transaction<A>)>> = (0..threads.get()).map(|_| vec![]).collect();
+        // First-seen order is global too. Clients that never end up with an account are dropped
+        // from it by `into_accounts_with_order`.
+        let mut client_order = std::mem::take(&mut self.client_order);
+        let mut ordered_clients: HashSet<u16> = client_order.iter().copied().collect();
+        // The thread each id first went to; ids seen before this batch are wherever
+        // `into_partitions` puts them. A duplicate is skipped by its own thread, which has to be
+        // told about the id if it was first used on another one.
+        let mut first_used = HashMap::new();
+        let mut foreign_ids: Vec<Vec<(u16, u32)>> = (0..threads.get()).map(|_| vec![]).collect();
+        for (position, transaction) in transactions.into_iter().enumerate() {
+            let partition = partition_of(transaction.client);
+            let mut duplicate = false;
+            if transaction.transaction_type.moves_funds() {
+                let key = self.seen_key(&transaction);
+                let home = if self.seen_transactions.contains(&key) {
+                    Some(if self.config.client_scoped_tx_ids { partition_of(key.0) } else { 0 })
+                } else {
+                    first_used.get(&key).copied()
+                };
+                match home {
+                    Some(home) if home != partition => foreign_ids[partition].push(key),
+                    Some(_) => {}
+                    None => {
+                        first_used.insert(key, partition);
+                    }
+                }
+                duplicate = home.is_some();
+            }
+            if !duplicate && ordered_clients.insert(transaction.client) {
+                client_order.push(transaction.client);
+            }
+            batches[partition].push((position, transaction));
+        }
+        // Whatever was recorded before comes first. What the threads record is tagged with the
+        // input position of the transaction it came from.
+        let (prior_events, prior_outcomes) = (std::mem::take(&mut self.events), std::mem::take(&mut self.outcomes));
+        let config = self.config.clone();
+        let engine = std::mem::replace(self, TransactionEngine::with_accounts(BTreeMap::new(), config.clone()));
+        let mut partitions = engine.into_partitions(threads);
+        for (partition, ids) in partitions.iter_mut().zip(foreign_ids) {
+            partition.seen_transactions.extend(ids);
+        }
+        let results: Vec<_> = thread::scope(|scope| {
+            let handles: Vec<_> = partitions
+                .iter_mut()
+                .zip(batches)
+                .map(|(partition, batch)| scope.spawn(move || partition.apply_batch(batch, deadline)))
+                .collect();
+            handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+        });
+        let (mut events, mut outcomes) = (vec![], vec![]);
+        let mut finished = true;
+        for (partition, result) in partitions.iter_mut().zip(results) {
+            let (partition_finished, event_positions, outcome_positions) = result;
+            finished &= partition_finished;
+            events.extend(event_positions.into_iter().zip(std::mem::take(&mut partition.events)));
+            outcomes.extend(outcome_positions.into_iter().zip(std::mem::take(&mut partition.outcomes)));
+        }
+        // Stable, so what one transaction recorded keeps its order.
+        events.sort_by_key(|&(position, _)| position);
+        outcomes.sort_by_key(|&(position, _)| position);
+        *self = TransactionEngine::from_partitions(config, partitions);
+        self.events = prior_events;
+        self.events.extend(events.into_iter().map(|(_, event)| event));
+        self.outcomes = prior_outcomes;
+        self.outcomes.extend(outcomes.into_iter().map(|(_, outcome)| outcome));
+        self.client_order = client_order;
+        finished
+    }
+
+    // `apply_all` for one thread's share of `apply_all_parallel`. Also returns the input position
+    // behind each event and outcome recorded along the way.
+    fn apply_batch(
+        &mut self,
+        batch: Vec<(usize, Transaction<A>)>,
+        deadline: Option<Instant>,
+    ) -> (bool, Vec<usize>, Vec<usize>) {
+        let mut event_positions = vec![];
+        let mut outcome_positions = vec![];
+        for (applied, (position, transaction)) in batch.into_iter().enumerate() {
+            if applied > 0 && applied % DEADLINE_CHECK_INTERVAL == 0 {
+                if let Some(deadline) = deadline {
+                    if Instant::now() >= deadline {
+                        return (false, event_positions, outcome_positions);
+                    }
+                }
+            }
+            self.apply(transaction);
+            event_positions.resize(self.events.len(), position);
+            outcome_positions.resize(self.outcomes.len(), position);
+        }
+        (true, event_positions, outcome_positions)
+    }
+
+    // With globally unique ids every client shares one scope; with client-scoped ids each client
+    // has its own.
+    fn seen_key(&self, transaction: &Transaction<A>) -> (u16, u32) {
+        let scope = if self.config.client_scoped_tx_ids { transaction.client } else { 0 };
+        (scope, transaction.tx)
+    }
+
+    // Records a deposit, withdrawal or transfer id as seen, returning whether it already was.
+    fn is_duplicate(&mut self, transaction: &Transaction<A>) -> bool {
+        let key = self.seen_key(transaction);
+        transaction.transaction_type.moves_funds() && !self.seen_transactions.insert(key)
+    }
+
+    // Applies transactions in order, giving up once `deadline` has passed. Returns whether every
+    // transaction was applied; on a timeout the accounts reflect only what was applied so far.
+    pub fn apply_all<I>(&mut self, transactions: I, deadline: Option<Instant>) -> bool
+    where
+        I: IntoIterator<Item = Transaction<A>>,
+    {
+        for (applied, transaction) in transactions.into_iter().enumerate() {
+            if applied > 0 && applied % DEADLINE_CHECK_INTERVAL == 0 {
+                if let Some(deadline) = deadline {
+                    if Instant::now() >= deadline {
+                        return false;
+                    }
+                }
+            }
+            self.apply(transaction);
+        }
+        true
+    }
+
+    // Like `apply_all`, for transactions that are still being read: stops at the first read error
+    // and returns it, keeping whatever was applied before it.
+    pub fn apply_stream<I, E>(&mut self, transactions: I, deadline: Option<Instant>) -> Result<bool, TransactionError>
+    where
+        I: IntoIterator<Item = Result<Transaction<A>, E>>,
+        E: Into<TransactionError>,
+    {
+        let mut error = None;
+        let readable = transactions.into_iter().map_while(|transaction| match transaction {
+            Ok(transaction) => Some(transaction),
+            Err(read_error) => {
+                error = Some(read_error);
+                None
+            }
+        });
+        let finished = self.apply_all(readable, deadline);
+        match error {
+            Some(error) => Err(error.into()),
+            None => Ok(finished),
+        }
+    }
+
+    pub fn apply(&mut self, transaction: Transaction<A>) {
+        let (client_id, tx, transaction_type) = (transaction.client, transaction.tx, transaction.transaction_type);
+        let outcome = self.check_and_apply(transaction);
+        self.settle(transaction_type, client_id, tx, outcome);
+        // A dispute buffered under `buffer_early_disputes` goes in right after its transaction.
+        if transaction_type.is_disputable() {
+            if let Some(dispute) = self.pending_disputes.remove(&(client_id, tx)) {
+                let outcome = self.apply_to_account(dispute);
+                self.settle(TransactionType::Dispute, client_id, tx, outcome);
+            }
+        }
+    }
+
+    // Counts and records what became of a transaction once it has been dealt with.
+    fn settle(&mut self, transaction_type: TransactionType, client: u16, tx: u32, outcome: Outcome) {
+        if let Outcome::Ignored(reason) = outcome {
+            self.anomalies += 1;
+            // Withdrawals refused for lack of funds have an event of their own.
+            let ignored_withdrawal =
+                transaction_type == TransactionType::Withdrawal && reason == SkipReason::InsufficientFunds;
+            if self.config.record_events && !ignored_withdrawal {
+                self.events.push(EngineEvent::Skipped { transaction_type, client, tx, reason });
+            }
+        }
+        if self.config.record_outcomes {
+            let account = self.accounts.get(&client);
+            self.outcomes.push(AppliedEvent {
+                transaction_type,
+                client,
+                tx,
+                outcome,
+                available: account.map_or_else(A::default, |account| account.available),
+                held: account.map_or_else(A::default, |account| account.held),
+            });
+        }
+    }
+
+    // The checks every transaction has to pass before `apply_to_account` gets to it.
+    fn check_and_apply(&mut self, transaction: Transaction<A>) -> Outcome {
+        let client_id = transaction.client;
+        if self.is_duplicate(&transaction) {
+            return Outcome::Ignored(SkipReason::Duplicate);
+        }
+        if let Some(allowed_clients) = &self.config.allowed_clients {
+            if !allowed_clients.contains(&client_id) {
+                self.unlisted_client_transactions += 1;
+                return Outcome::Ignored(SkipReason::UnlistedClient);
+            }
+        }
+        if self.quarantined_clients.contains_key(&client_id) {
+            return Outcome::Ignored(SkipReason::Quarantined);
+        }
+        // One of zero or less never touches the account either, but unlike a missing amount it says
+        // nothing about the client's other transactions, so it never gets the client quarantined.
+        if transaction.transaction_type.moves_funds() && transaction.invalid_amount.is_some() {
+            self.invalid_amounts += 1;
+            return Outcome::Ignored(SkipReason::InvalidAmount);
+        }
+        // A deposit, withdrawal or transfer without an amount is invalid and never touches the account.
+        if transaction.transaction_type.moves_funds() && transaction.amount.is_none() {
+            if self.config.isolate_clients {
+                if let Some(account) = self.accounts.remove(&client_id) {
+                    self.ledger.written_off = self.ledger.written_off.saturating_add(account.total());
+                }
+                self.quarantined_clients.insert(client_id, transaction.tx);
+            }
+            return Outcome::Ignored(SkipReason::MissingAmount);
+        }
+        // A transfer's history is recorded by `apply_transfer`, once it is known to have gone through.
+        if self.config.verify_replay && transaction.transaction_type != TransactionType::Transfer {
+            self.history.entry(client_id).or_default().push(transaction.clone());
+        }
+        self.apply_to_account(transaction)
+    }
+
+    // Moves funds between two clients' available balances. Refused, leaving both accounts as they
+    // were, if either is frozen, the source can't cover it or the target would go over
+    // `max_balance`. The target has to pass the same allow list and quarantine checks as the source.
+    fn apply_transfer(&mut self, transaction: Transaction<A>) -> Outcome {
+        let source = transaction.client;
+        let (target, amount) = match (transaction.target, transaction.amount) {
+            (Some(target), Some(amount)) if target != source => (target, amount),
+            _ => return Outcome::Ignored(SkipReason::NoTarget),
+        };
+        if self.config.allowed_clients.as_ref().is_some_and(|allowed| !allowed.contains(&target)) {
+            self.unlisted_client_transactions += 1;
+            return Outcome::Ignored(SkipReason::UnlistedClient);
+        }
+        if self.quarantined_clients.contains_key(&target) {
+            return Outcome::Ignored(SkipReason::Quarantined);
+        }
+        if !self.accounts.contains_key(&target) {
+            self.client_order.push(target);
+        }
+        let target_account = self.accounts.entry(target).or_default();
+        if target_account.frozen {
+            return Outcome::Ignored(SkipReason::AccountFrozen);
+        }
+        let held = target_account.held;
+        let fits = match target_account.available.checked_add(amount.value()) {
+            Some(balance) if balance.checked_add(held).is_some() => {
+                self.config.max_balance.is_none_or(|max_balance| balance <= max_balance)
+            }
+            _ => false,
+        };
+        if !fits {
+            return Outcome::Ignored(SkipReason::AboveMaxBalance);
+        }
+        let source_account = self.accounts.entry(source).or_default();
+        if source_account.frozen {
+            return Outcome::Ignored(SkipReason::AccountFrozen);
+        }
+        if amount.value() > source_account.available {
+            return Outcome::Ignored(SkipReason::InsufficientFunds);
+        }
+        source_account.available -= amount.value();
+        source_account.total_withdrawn = source_account.total_withdrawn.saturating_add(amount.value());
+        if let Some(target_account) = self.accounts.get_mut(&target) {
+            target_account.available += amount.value();
+            target_account.total_deposited = target_account.total_deposited.saturating_add(amount.value());
+        }
+        if self.config.verify_replay {
+            // Each client is replayed alone, so the two sides are recorded as the withdrawal and
+            // deposit they amount to.
+            let leg = |transaction_type, client| Transaction {
+                transaction_type,
+                client,
+                target: None,
+                ..transaction.clone()
+            };
+            self.history.entry(source).or_default().push(leg(TransactionType::Withdrawal, source));
+            self.history.entry(target).or_default().push(leg(TransactionType::Deposit, target));
+        }
+        Outcome::Applied
+    }
+
+    // The part of `apply` that moves funds, once the transaction has passed every check.
+    fn apply_to_account(&mut self, transaction: Transaction<A>) -> Outcome {
+        let (client_id, tx) = (transaction.client, transaction.tx);
+        // A dispute for an id that hasn't been seen yet may be early rather than bogus.
+        let early_dispute = self.config.buffer_early_disputes
+            && transaction.transaction_type == TransactionType::Dispute
+            && !self.seen_transactions.contains(&self.seen_key(&transaction));
+        if !self.accounts.contains_key(&client_id) {
+            self.client_order.push(client_id);
+        }
+        let user_account = self.accounts.entry(client_id).or_default();
+
+        let skipped = match transaction.transaction_type {
+            TransactionType::Deposit => {
+                let amount = match transaction.amount {
+                    Some(amount) => amount,
+                    None => return Outcome::Ignored(SkipReason::MissingAmount),
+                };
+                match self.config.max_balance {
+                    Some(max_balance)
+                        if user_account
+                            .available
+                            .checked_add(amount.value())
+                            .is_none_or(|balance| balance > max_balance) =>
+                    {
+                        Some(SkipReason::AboveMaxBalance)
+                    }
+                    // Only deposits that actually credited the account can be disputed later.
+                    _ if user_account.deposit(amount) => {
+                        // Saturating like the account's own running total.
+                        self.ledger.deposited = self.ledger.deposited.saturating_add(amount.value());
+                        self.processed_transactions.insert((client_id, transaction.tx), ProcessedTransaction {
+                            transaction_type: transaction.transaction_type,
+                            client: client_id,
+                            amount: amount.value(),
+                        });
+                        None
+                    }
+                    _ if user_account.frozen => Some(SkipReason::AccountFrozen),
+                    // The balance would have overflowed.
+                    _ => Some(SkipReason::AboveMaxBalance),
+                }
+            }
+            TransactionType::Withdrawal => match transaction.amount {
+                // Only withdrawals that actually moved money can be disputed later.
+                Some(amount) if user_account.withdraw(amount) => {
+                    self.ledger.withdrawn = self.ledger.withdrawn.saturating_add(amount.value());
+                    self.processed_transactions.insert((client_id, transaction.tx), ProcessedTransaction {
+                        transaction_type: transaction.transaction_type,
+                        client: client_id,
+                        amount: amount.value(),
+                    });
+                    None
+                }
+                Some(_) if user_account.frozen => Some(SkipReason::AccountFrozen),
+                Some(amount) => {
+                    if self.config.record_events {
+                        let amount = amount.value();
+                        self.events.push(EngineEvent::IgnoredWithdrawal { client: client_id, tx, amount });
+                    }
+                    Some(SkipReason::InsufficientFunds)
+                }
+                None => Some(SkipReason::MissingAmount),
+            },
+            TransactionType::Dispute => {
+                let possible_disputed_transaction = self.processed_transactions.get(&(client_id, tx));
+                match possible_disputed_transaction {
+                    _ if !self.config.allow_redispute && self.resolved_transactions.contains(&(client_id, tx)) => {
+                        Some(SkipReason::AlreadyResolved)
+                    }
+                    Some(disputed_transaction) if disputed_transaction.disputable_by(client_id) =>
+                        {
+                            let amount = disputed_transaction.amount;
+                            let disputed_type = disputed_transaction.transaction_type;
+                            let reduces_available = disputed_type == TransactionType::Deposit;
+                            let disputes = &user_account.disputed_transactions;
+                            if user_account.frozen {
+                                Some(SkipReason::AccountFrozen)
+                            } else if !disputes.contains(tx)
+                                && self.config.max_open_disputes.is_some_and(|max| disputes.len() >= max)
+                            {
+                                Some(SkipReason::TooManyDisputes)
+                            } else if !self.config.allow_negative_available
+                                && reduces_available
+                                && user_account.available < amount
+                            {
+                                Some(SkipReason::InsufficientFunds)
+                            } else if disputes.contains(tx) {
+                                Some(SkipReason::AlreadyDisputed)
+                            } else if user_account.dispute(tx, amount, disputed_type) {
+                                if disputed_type == TransactionType::Withdrawal {
+                                    self.ledger.withdrawals_disputed =
+                                        self.ledger.withdrawals_disputed.saturating_add(amount);
+                                }
+                                None
+                            } else {
+                                // Holding it would have overflowed the balances.
+                                Some(SkipReason::AboveMaxBalance)
+                            }
+                        }
+                    Some(_) => Some(SkipReason::WrongType),
+                    // Counted by `anomalies` for as long as it is waiting.
+                    None if early_dispute => {
+                        self.pending_disputes.insert((client_id, tx), transaction);
+                        return Outcome::Deferred;
+                    }
+                    None => Some(SkipReason::NotFound),
+                }
+            }
+            TransactionType::Resolve => {
+                let possible_transaction = self.processed_transactions.get(&(client_id, tx));
+                let skipped = match possible_transaction {
+                    Some(disputed_transaction) if disputed_transaction.disputable_by(client_id) =>
+                        {
+                            let amount = disputed_transaction.amount;
+                            let disputed_type = disputed_transaction.transaction_type;
+                            if user_account.resolve(tx, amount, disputed_type) {
+                                if disputed_type == TransactionType::Withdrawal {
+                                    self.ledger.settle_disputed_withdrawal(amount);
+                                }
+                                self.resolved_transactions.insert((client_id, tx));
+                                None
+                            } else if user_account.frozen {
+                                Some(SkipReason::AccountFrozen)
+                            } else if !user_account.disputed_transactions.contains(tx) {
+                                Some(SkipReason::NotDisputed)
+                            } else {
+                                Some(SkipReason::AboveMaxBalance)
+                            }
+                        }
+                    Some(_) => Some(SkipReason::WrongType),
+                    None => Some(SkipReason::NotFound),
+                };
+                if skipped.is_some() {
+                    self.ignored_resolves += 1;
+                }
+                skipped
+            }
+            TransactionType::Chargeback => {
+                let possible_transaction = self.processed_transactions.get(&(client_id, tx));
+                let skipped = match possible_transaction {
+                    Some(disputed_transaction) if disputed_transaction.disputable_by(client_id) =>
+                        {
+                            let amount = disputed_transaction.amount;
+                            let disputed_type = disputed_transaction.transaction_type;
+                            if user_account.chargeback(tx, amount, disputed_type) {
+                                if disputed_type == TransactionType::Deposit {
+                                    self.ledger.deposits_charged_back =
+                                        self.ledger.deposits_charged_back.saturating_add(amount);
+                                } else {
+                                    self.ledger.settle_disputed_withdrawal(amount);
+                                    self.ledger.withdrawals_charged_back =
+                                        self.ledger.withdrawals_charged_back.saturating_add(amount);
+                                }
+                                // A chargeback is final. Forgetting the transaction keeps it from being
+                                // disputed and charged back a second time once the account is unfrozen.
+                                self.processed_transactions.remove(&(client_id, tx));
+                                None
+                            } else if user_account.frozen {
+                                Some(SkipReason::AccountFrozen)
+                            } else if !user_account.disputed_transactions.contains(tx) {
+                                Some(SkipReason::NotDisputed)
+                            } else {
+                                Some(SkipReason::AboveMaxBalance)
+                            }
+                        }
+                    Some(_) => Some(SkipReason::WrongType),
+                    None => Some(SkipReason::NotFound),
+                };
+                if skipped.is_some() {
+                    self.ignored_chargebacks += 1;
+                }
+                skipped
+            }
+            TransactionType::Transfer => return self.apply_transfer(transaction),
+        };
+        match skipped {
+            Some(reason) => Outcome::Ignored(reason),
+            None => Outcome::Applied,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TransactionError {
+    // Opening the input failed.
+    Io(io::Error),
+    // Any other CSV-level failure, such as a read error, invalid UTF-8 or a row with the wrong
+    // number of fields.
+    Csv(csv::Error),
+    MalformedRow { line: u64, reason: String },
+    UnknownType { line: u64, value: String },
+    ClientOutOfRange { line: u64, client: u64 },
+    InvalidHeader { reason: String },
+    EmptyLine,
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransactionError::Io(error) => write!(f, "could not read transactions: {}", error),
+            TransactionError::Csv(error) => write!(f, "invalid transaction: {}", error),
+            TransactionError::MalformedRow { line, reason } => {
+                write!(f, "invalid transaction on line {}: {}", line, reason)
+            }
+            TransactionError::UnknownType { line, value } => {
+                write!(f, "invalid transaction on line {}: unknown type {}", line, value)
+            }
+            TransactionError::ClientOutOfRange { line, client } => {
+                write!(f, "client id {} out of range on line {}", client, line)
+            }
+            TransactionError::InvalidHeader { reason } => write!(f, "invalid header: {}", reason),
+            TransactionError::EmptyLine => f.write_str("invalid transaction: empty line"),
+        }
+    }
+}
+
+impl std::error::Error for TransactionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TransactionError::Io(error) => Some(error),
+            TransactionError::Csv(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for TransactionError {
+    fn from(error: io::Error) -> Self {
+        TransactionError::Io(error)
+    }
+}
+
+impl TransactionError {
+    // A row that could not be deserialized, singling out an unknown transaction type.
+    fn row(line: u64, reason: String) -> TransactionError {
+        // serde reports an unrecognised enum value as "unknown variant `value`, expected ...".
+        if let Some(rest) = reason.strip_prefix("unknown variant `") {
+            if let Some(end) = rest.find('`') {
+                return TransactionError::UnknownType { line, value: rest[..end].to_string() };
+            }
+        }
+        TransactionError::MalformedRow { line, reason }
+    }
+
+    // The input line a row-level error was found on. Errors without one concern the input as a
+    // whole rather than a single row.
+    fn line(&self) -> Option<u64> {
+        match self {
+            TransactionError::Csv(error) => error.position().map(|position| position.line()),
+            TransactionError::MalformedRow { line, .. }
+            | TransactionError::UnknownType { line, .. }
+            | TransactionError::ClientOutOfRange { line, .. } => Some(*line),
+            _ => None,
+        }
+    }
+}
+
+// Row-level deserialization failures are pulled out of the csv error so callers can tell a bad
+// row, and in particular an unknown transaction type, from a broken file.
+impl From<csv::Error> for TransactionError {
+    fn from(error: csv::Error) -> Self {
+        match error.kind() {
+            csv::ErrorKind::Deserialize { pos, err } => {
+                TransactionError::row(pos.as_ref().map_or(0, |position| position.line()), err.to_string())
+            }
+            _ => TransactionError::Csv(error),
+        }
+    }
+}
+
+// Parses a single header-less CSV line and applies it to the engine, for REPL-style tools that
+// feed transactions one at a time. Columns are `type, client, tx, amount`, optionally followed by
+// `seq`, `timestamp` and `target` in that order.
+pub fn process_line(engine: &mut TransactionEngine, line: &str) -> Result<(), TransactionError> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .trim(Trim::All)
+        .from_reader(line.as_bytes());
+    let row = rdr.records().next().ok_or(TransactionError::EmptyLine)??;
+    let headers = csv::StringRecord::from(&CSV_COLUMNS[..row.len().min(CSV_COLUMNS.len())]);
+    let transaction: Transaction = row.deserialize(Some(&headers))?;
+    engine.apply(transaction);
+    Ok(())
+}
+
+// One row of a previous run's output, as written by `write_accounts`.
+#[derive(Debug, Deserialize)]
+struct BalanceRecord {
+    client: u16,
+    available: Money,
+    held: Money,
+    locked: bool,
+}
+
+#[derive(Debug)]
+pub enum BaselineError {
+    Csv(csv::Error),
+    DuplicateClient { client: u16, line: u64 },
+}
+
+impl fmt::Display for BaselineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BaselineError::Csv(error) => write!(f, "invalid baseline: {}", error),
+            BaselineError::DuplicateClient { client, line } => {
+                write!(f, "invalid baseline: client {} appears more than once (line {})", client, line)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BaselineError {}
+
+impl From<csv::Error> for BaselineError {
+    fn from(error: csv::Error) -> Self {
+        BaselineError::Csv(error)
+    }
+}
+
+// Loads a previous run's output so today's transactions can be applied on top of it. Only the
+// balances carry over: transactions from earlier runs can't be disputed against the baseline.
+// A client listed twice is rejected since there is no way to tell which row is authoritative.
+pub fn read_baseline<R: io::Read>(reader: R) -> Result<BTreeMap<u16, Account>, BaselineError> {
+    let (delimiter, reader) = sniff_delimiter(reader);
+    let mut rdr = csv::ReaderBuilder::new().trim(Trim::All).delimiter(delimiter).from_reader(reader);
+    let headers = rdr.headers()?.clone();
+    let mut accounts = BTreeMap::new();
+    for row in rdr.records() {
+        let row = row?;
+        let record: BalanceRecord = row.deserialize(Some(&headers))?;
+        if accounts.contains_key(&record.client) {
+            let line = row.position().map_or(0, |position| position.line());
+            return Err(BaselineError::DuplicateClient { client: record.client, line });
+        }
+        accounts.insert(record.client, Account {
+            frozen: record.locked,
+            held: record.held,
+            available: record.available,
+            ..Account::new()
+        });
+    }
+    Ok(accounts)
+}
+
+// Saves the full account state, open disputes included, in bincode so a staged run can pick up
+// where it left off with `read_accounts_bincode`. Like a baseline, only the accounts are kept:
+// transactions from before the checkpoint can't be disputed after resuming.
+pub fn write_accounts_bincode<W: Write>(accounts: &BTreeMap<u16, Account>, writer: W) -> bincode::Result<()> {
+    bincode::serialize_into(writer, accounts)
+}
+
+pub fn read_accounts_bincode<R: io::Read>(reader: R) -> bincode::Result<BTreeMap<u16, Account>> {
+    bincode::deserialize_from(reader)
+}
+
+// Copies of `accounts` with every balance rounded to `decimals` places, for output that wants
+// fewer places than amounts are kept with. Available and held are each rounded and the total
+// is left as their sum, so the printed columns always add up.
+pub fn round_accounts(accounts: &BTreeMap<u16, Account>, decimals: usize) -> BTreeMap<u16, Account> {
+    accounts
+        .iter()
+        .map(|(client, account)| {
+            let rounded = Account {
+                available: account.available.round_to(decimals),
+                held: account.held.round_to(decimals),
+                total_deposited: account.total_deposited.round_to(decimals),
+                total_withdrawn: account.total_withdrawn.round_to(decimals),
+                ..account.clone()
+            };
+            (*client, rounded)
+        })
+        .collect()
+}
+
+#[derive(Debug, PartialEq)]
+pub struct AccountDelta {
+    client: u16,
+    available: Money,
+    held: Money,
+    // `None` once the client has no account at all.
+    locked: Option<bool>,
+}
+
+// Compares the final accounts against the baseline they started from and returns, sorted by
+// client, only the accounts whose balances or locked state changed. A baseline client that no
+// longer has an account, such as one quarantined under `isolate_clients`, is reported with its
+// whole balance gone.
+pub fn diff_accounts(baseline: &BTreeMap<u16, Account>, accounts: &BTreeMap<u16, Account>) -> Vec<AccountDelta> {
+    let state = |account: Option<&Account>| account.map(|account| (account.available, account.held, account.frozen));
+    let mut clients: Vec<u16> = baseline.keys().chain(accounts.keys()).copied().collect();
+    clients.sort_unstable();
+    clients.dedup();
+    clients
+        .into_iter()
+        .filter_map(|client| {
+            let (before, after) = (state(baseline.get(&client)), state(accounts.get(&client)));
+            let (available, held, frozen) = before.unwrap_or((Money::ZERO, Money::ZERO, false));
+            let delta = match after {
+                Some((now_available, now_held, now_frozen)) => AccountDelta {
+                    client,
+                    available: now_available - available,
+                    held: now_held - held,
+                    locked: Some(now_frozen),
+                },
+                None => {
+                    AccountDelta { client, available: Money::ZERO - available, held: Money::ZERO - held, locked: None }
+                }
+            };
+            let unchanged = delta.available == Money::ZERO && delta.held == Money::ZERO && delta.locked == Some(frozen);
+            if unchanged {
+                None
+            } else {
+                Some(delta)
+            }
+        })
+        .collect()
+}
+
+// Written with the header and delimiter from `options`, like `write_accounts`. A client with no
+// account left has an empty locked column.
+pub fn write_deltas<W: Write>(deltas: &[AccountDelta], options: &WriteOptions, writer: &mut W) -> io::Result<()> {
+    let mut csv_writer = csv::WriterBuilder::new().delimiter(options.delimiter).from_writer(writer);
+    if options.header {
+        csv_writer.write_record(["client", "available", "held", "locked"])?;
+    }
+    for delta in deltas {
+        csv_writer.write_record([
+            delta.client.to_string(),
+            options.format(delta.available),
+            options.format(delta.held),
+            delta.locked.map_or_else(String::new, |locked| locked.to_string()),
+        ])?;
+    }
+    csv_writer.flush()
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Divergence {
+    client: u16,
+    expected: Option<(Money, Money, bool)>,
+    actual: Option<(Money, Money, bool)>,
+}
+
+// Compares computed accounts against a reference implementation's expected balances and returns,
+// sorted by client, every client whose state differs or that only one side knows about.
+pub fn diverging_accounts(expected: &BTreeMap<u16, Account>, accounts: &BTreeMap<u16, Account>) -> Vec<Divergence> {
+    let state = |account: &Account| (account.available, account.held, account.frozen);
+    let mut clients: Vec<u16> = expected.keys().chain(accounts.keys()).copied().collect();
+    clients.sort_unstable();
+    clients.dedup();
+    clients
+        .into_iter()
+        .map(|client| Divergence {
+            client,
+            expected: expected.get(&client).map(state),
+            actual: accounts.get(&client).map(state),
+        })
+        .filter(|divergence| divergence.expected != divergence.actual)
+        .collect()
+}
+
+// Written with the header and delimiter from `options`, like `write_accounts`, but always with
+// exact balances: rounding could hide the very difference being reported. A side that has no
+// account for the client leaves its three columns empty.
+pub fn write_divergences<W: Write>(
+    divergences: &[Divergence],
+    options: &WriteOptions,
+    writer: &mut W,
+) -> io::Result<()> {
+    let columns = |state: Option<(Money, Money, bool)>| match state {
+        Some((available, held, locked)) => [available.to_string(), held.to_string(), locked.to_string()],
+        None => Default::default(),
+    };
+    let mut csv_writer = csv::WriterBuilder::new().delimiter(options.delimiter).from_writer(writer);
+    if options.header {
+        csv_writer.write_record([
+            "client",
+            "expected_available",
+            "expected_held",
+            "expected_locked",
+            "available",
+            "held",
+            "locked",
+        ])?;
+    }
+    for divergence in divergences {
+        let mut record = vec![divergence.client.to_string()];
+        record.extend(columns(divergence.expected));
+        record.extend(columns(divergence.actual));
+        csv_writer.write_record(record)?;
+    }
+    csv_writer.flush()
+}
+
+// Accounts whose funds are entirely tied up in disputes: something is held but nothing is left
+// to withdraw. This is a legitimate state, but one support teams want to hear about.
+fn fully_held_clients(accounts: &BTreeMap<u16, Account>) -> Vec<u16> {
+    accounts
+        .iter()
+        .filter(|(_, account)| account.available <= Money::ZERO && account.held > Money::ZERO)
+        .map(|(client, _)| *client)
+        .collect()
+}
+
+pub fn write_fully_held_warnings<W: Write>(accounts: &BTreeMap<u16, Account>, writer: &mut W) -> io::Result<()> {
+    for client in fully_held_clients(accounts) {
+        writeln!(writer, "warning: client {} has {} held and no available funds", client, accounts[&client].held)?;
+    }
+    Ok(())
+}
+
+// Disputing a deposit whose funds were already withdrawn leaves available below zero. The engine
+// allows it by default, but reconciliation usually treats it as a sign of bad data.
+pub fn accounts_with_negative_available(accounts: &BTreeMap<u16, Account>) -> Vec<u16> {
+    accounts
+        .iter()
+        .filter(|(_, account)| account.available < Money::ZERO)
+        .map(|(client, _)| *client)
+        .collect()
+}
+
+pub fn write_negative_available_warnings<W: Write>(
+    accounts: &BTreeMap<u16, Account>,
+    writer: &mut W,
+) -> io::Result<()> {
+    for client in accounts_with_negative_available(accounts) {
+        writeln!(writer, "warning: client {} has negative available funds of {}", client, accounts[&client].available)?;
+    }
+    Ok(())
+}
+
+// Locked accounts, sorted by client id, for compliance reports.
+pub fn frozen_accounts<A>(accounts: &BTreeMap<u16, Account<A>>) -> Vec<(u16, &Account<A>)> {
+    accounts_by_frozen(accounts, true)
+}
+
+// Accounts that are not locked, sorted by client id.
+pub fn active_accounts<A>(accounts: &BTreeMap<u16, Account<A>>) -> Vec<(u16, &Account<A>)> {
+    accounts_by_frozen(accounts, false)
+}
+
+// Grand totals across every account, from `aggregate`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Aggregate<A = Money> {
+    pub available: A,
+    pub held: A,
+    pub total: A,
+    pub frozen_accounts: usize,
+}
+
+// Sums saturate like the accounts' own running totals, so a huge book can't wrap around.
+pub fn aggregate<A: Amount>(accounts: &BTreeMap<u16, Account<A>>) -> Aggregate<A> {
+    accounts.values().fold(Aggregate::default(), |sum, account| Aggregate {
+        available: sum.available.saturating_add(account.available),
+        held: sum.held.saturating_add(account.held),
+        total: sum.total.saturating_add(account.total()),
+        frozen_accounts: sum.frozen_accounts + usize::from(account.frozen),
+    })
+}
+
+fn accounts_by_frozen<A>(accounts: &BTreeMap<u16, Account<A>>, frozen: bool) -> Vec<(u16, &Account<A>)> {
+    accounts
+        .iter()
+        .filter(|(_, account)| account.frozen == frozen)
+        .map(|(client, account)| (*client, account))
+        .collect()
+}
+
+// One row of output, shared by every output format. The amount type is a parameter so the CSV
+// writer can serialize the exact decimal text while JSON keeps numbers.
+#[derive(Debug, Serialize)]
+struct AccountRecord<A = Money> {
+    client: u16,
+    available: A,
+    held: A,
+    total: A,
+    locked: bool,
+    // Only filled in for verbose CSV output.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_deposited: Option<A>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_withdrawn: Option<A>,
+}
+
+impl AccountRecord {
+    fn new(client: u16, account: &Account) -> AccountRecord {
+        AccountRecord {
+            client,
+            available: account.available,
+            held: account.held,
+            total: account.total(),
+            locked: account.frozen,
+            total_deposited: None,
+            total_withdrawn: None,
+        }
+    }
+
+    fn map_amounts<B, F: Fn(Money) -> B>(self, f: F) -> AccountRecord<B> {
+        AccountRecord {
+            client: self.client,
+            available: f(self.available),
+            held: f(self.held),
+            total: f(self.total),
+            locked: self.locked,
+            total_deposited: self.total_deposited.map(&f),
+            total_withdrawn: self.total_withdrawn.map(&f),
+        }
+    }
+}
+
+// Records come in client id order, as the accounts are kept, so every output format is stable from
+// run to run, unless an `order` is given (see `TransactionEngine::into_accounts_with_order`).
+fn account_records(accounts: &BTreeMap<u16, Account>, frozen_only: bool, order: Option<&[u16]>) -> Vec<AccountRecord> {
+    let mut clients: Vec<u16> = match order {
+        Some(order) => order.iter().copied().filter(|client| accounts.contains_key(client)).collect(),
+        None => accounts.keys().copied().collect(),
+    };
+    if frozen_only {
+        clients.retain(|client| accounts[client].frozen);
+    }
+    clients
+        .into_iter()
+        .map(|client| AccountRecord::new(client, &accounts[&client]))
+        .collect()
+}
+
+// How `write_accounts` lays out its CSV. The default writes every account by client id, with a
+// header and comma-separated.
+#[derive(Debug, Clone, Copy)]
+pub struct WriteOptions<'a> {
+    pub frozen_only: bool,
+    // Adds each account's running deposit and withdrawal totals as two extra columns.
+    pub verbose: bool,
+    pub header: bool,
+    pub delimiter: u8,
+    // Clients to write in this order instead of by id.
+    pub order: Option<&'a [u16]>,
+    // Rounds balances to this many places and prints exactly that many, as in `10.00`.
+    pub decimals: Option<usize>,
+}
+
+impl Default for WriteOptions<'_> {
+    fn default() -> Self {
+        WriteOptions { frozen_only: false, verbose: false, header: true, delimiter: b',', order: None, decimals: None }
+    }
+}
+
+impl WriteOptions<'_> {
+    fn format(&self, amount: Money) -> String {
+        match self.decimals {
+            Some(decimals) => format!("{:.*}", decimals, amount),
+            None => amount.to_string(),
+        }
+    }
+}
+
+pub fn write_accounts<W: Write>(
+    accounts: &BTreeMap<u16, Account>,
+    options: &WriteOptions,
+    writer: &mut W,
+) -> io::Result<()> {
+    // The header is written by hand so it is present even when there are no records to serialize.
+    // Rounded up front, like `round_accounts`, so the printed columns still add up.
+    let rounded;
+    let accounts = match options.decimals {
+        Some(decimals) => {
+            rounded = round_accounts(accounts, decimals);
+            &rounded
+        }
+        None => accounts,
+    };
+    let mut csv_writer =
+        csv::WriterBuilder::new().has_headers(false).delimiter(options.delimiter).from_writer(writer);
+    if options.header {
+        let mut columns = vec!["client", "available", "held", "total", "locked"];
+        if options.verbose {
+            columns.extend(["total_deposited", "total_withdrawn"]);
+        }
+        csv_writer.write_record(columns)?;
+    }
+    for mut record in account_records(accounts, options.frozen_only, options.order) {
+        if options.verbose {
+            let account = &accounts[&record.client];
+            record.total_deposited = Some(account.total_deposited);
+            record.total_withdrawn = Some(account.total_withdrawn);
+        }
+        csv_writer.serialize(record.map_amounts(|amount| options.format(amount)))?;
+    }
+    csv_writer.flush()
+}
+
+// Newline-delimited JSON: one self-contained account object per line, no header.
+pub fn write_accounts_ndjson<W: Write>(
+    accounts: &BTreeMap<u16, Account>,
+    frozen_only: bool,
+    order: Option<&[u16]>,
+    writer: &mut W,
+) -> io::Result<()> {
+    for record in account_records(accounts, frozen_only, order) {
+        serde_json::to_writer(&mut *writer, &record)?;
+        writeln!(writer)?;
+    }
+    Ok(())
+}
+
+// A single JSON array of account objects, for consumers that want one document.
+pub fn write_accounts_json<W: Write>(
+    accounts: &BTreeMap<u16, Account>,
+    frozen_only: bool,
+    order: Option<&[u16]>,
+    writer: &mut W,
+) -> io::Result<()> {
+    serde_json::to_writer(&mut *writer, &account_records(accounts, frozen_only, order))?;
+    writeln!(writer)
+}
+
+// JSON output under `--format-version`: the array has nowhere to carry the marker, so it is
+// wrapped in an envelope object as `{"format_version": "1", "accounts": [...]}`.
+pub fn write_accounts_json_versioned<W: Write>(
+    accounts: &BTreeMap<u16, Account>,
+    frozen_only: bool,
+    order: Option<&[u16]>,
+    writer: &mut W,
+) -> io::Result<()> {
+    #[derive(Serialize)]
+    struct Envelope {
+        format_version: &'static str,
+        accounts: Vec<AccountRecord>,
+    }
+    let envelope =
+        Envelope { format_version: OUTPUT_FORMAT_VERSION, accounts: account_records(accounts, frozen_only, order) };
+    serde_json::to_writer(&mut *writer, &envelope)?;
+    writeln!(writer)
+}
+
+fn write_gauge<W, F, V>(writer: &mut W, name: &str, help: &str, records: &[AccountRecord], value: F) -> io::Result<()>
+where
+    W: Write,
+    F: Fn(&AccountRecord) -> V,
+    V: fmt::Display,
+{
+    writeln!(writer, "# HELP {} {}", name, help)?;
+    writeln!(writer, "# TYPE {} gauge", name)?;
+    for record in records {
+        writeln!(writer, "{}{{client=\"{}\"}} {}", name, record.client, value(record))?;
+    }
+    Ok(())
+}
+
+// Prometheus text exposition format: one gauge family per balance column, labelled by client.
+pub fn write_accounts_prometheus<W: Write>(
+    accounts: &BTreeMap<u16, Account>,
+    frozen_only: bool,
+    order: Option<&[u16]>,
+    writer: &mut W,
+) -> io::Result<()> {
+    let records = account_records(accounts, frozen_only, order);
+    write_gauge(writer, "account_available", "Funds available for withdrawal.", &records, |record| {
+        record.available
+    })?;
+    write_gauge(writer, "account_held", "Funds held by open disputes.", &records, |record| record.held)?;
+    write_gauge(writer, "account_total", "Available plus held funds.", &records, |record| record.total)?;
+    write_gauge(writer, "account_locked", "Whether the account is frozen (1) or not (0).", &records, |record| {
+        u8::from(record.locked)
+    })
+}
+
+// What to do instead of the normal output when no transaction in a run took effect.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EmptyOutput {
+    Marker,
+    Fail,
+}
+
+impl FromStr for EmptyOutput {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "marker" => Ok(EmptyOutput::Marker),
+            "fail" => Ok(EmptyOutput::Fail),
+            other => Err(format!("unknown empty output policy {}", other)),
+        }
+    }
+}
+
+// Writes the indicator for an empty run, returning whether the policy treats an empty run as an
+// error.
+pub fn write_empty_indicator<W: Write>(policy: EmptyOutput, writer: &mut W) -> io::Result<bool> {
+    match policy {
+        EmptyOutput::Marker => {
+            writeln!(writer, "no accounts")?;
+            Ok(false)
+        }
+        EmptyOutput::Fail => Ok(true),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Csv,
+    Ndjson,
+    Json,
+    Prometheus,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "csv" => Ok(OutputFormat::Csv),
+            "ndjson" => Ok(OutputFormat::Ndjson),
+            "json" => Ok(OutputFormat::Json),
+            "prometheus" => Ok(OutputFormat::Prometheus),
+            other => Err(format!("unknown output format {}", other)),
+        }
+    }
+}
+
+// Splits accounts into `shards` groups, putting each client in group `client % shards` so
+// downstream workers can each take one file.
+fn shard_accounts(accounts: BTreeMap<u16, Account>, shards: NonZeroUsize) -> Vec<BTreeMap<u16, Account>> {
+    let mut grouped = vec![BTreeMap::new(); shards.get()];
+    for (client, account) in accounts {
+        grouped[usize::from(client) % shards.get()].insert(client, account);
+    }
+    grouped
+}
+
+// Writes one CSV file per shard into `directory`, named `shard-<index>.csv`, laid out as `options` says.
+pub fn write_shards(
+    accounts: BTreeMap<u16, Account>,
+    shards: NonZeroUsize,
+    options: &WriteOptions,
+    directory: &Path,
+) -> io::Result<()> {
+    for (index, shard) in shard_accounts(accounts, shards).iter().enumerate() {
+        let mut file = File::create(directory.join(format!("shard-{}.csv", index)))?;
+        write_accounts(shard, options, &mut file)?;
+    }
+    Ok(())
+}
+
+// Bumped whenever a column or field is added, removed or reinterpreted in any output format.
+const OUTPUT_FORMAT_VERSION: &str = "1";
+
+// Announces the output layout version ahead of the accounts: a comment line for CSV and
+// Prometheus, and a leading envelope object for NDJSON. JSON carries it in the envelope written by
+// `write_accounts_json_versioned` instead.
+pub fn write_format_version<W: Write>(format: OutputFormat, writer: &mut W) -> io::Result<()> {
+    match format {
+        OutputFormat::Csv | OutputFormat::Prometheus => {
+            writeln!(writer, "# format-version: {}", OUTPUT_FORMAT_VERSION)
+        }
+        OutputFormat::Ndjson => {
+            serde_json::to_writer(&mut *writer, &serde_json::json!({ "format_version": OUTPUT_FORMAT_VERSION }))?;
+            writeln!(writer)
+        }
+        OutputFormat::Json => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn money(value: f64) -> Money {
+        Money::try_from(value).unwrap()
+    }
+
+    fn amount(value: f64) -> Option<PositiveAmount> {
+        PositiveAmount::new(money(value))
+    }
+
+    // Shadows the library's entry point so every test that processes a batch also checks that
+    // the accounts reconcile with the engine's ledger.
+    fn process_transactions(transactions: Vec<Transaction>) -> BTreeMap<u16, Account> {
+        process_transactions_from(BTreeMap::new(), transactions, &EngineConfig::default())
+    }
+
+    // Deterministic xorshift generator so shuffles are reproducible from a seed.
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next_below(&mut self, bound: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % bound as u64) as usize
+        }
+    }
+
+    // Shuffles transactions while keeping every ordering the engine depends on: each client's
+    // own transactions stay in file order, and a dispute, resolve or chargeback is never moved
+    // ahead of the deposit or withdrawal it refers to. Everything else is fair game.
+    fn shuffle_preserving_dependencies(transactions: Vec<Transaction>, seed: u64) -> Vec<Transaction> {
+        let originals: HashSet<u32> = transactions
+            .iter()
+            .filter(|t| matches!(t.transaction_type, TransactionType::Deposit | TransactionType::Withdrawal))
+            .map(|t| t.tx)
+            .collect();
+        let mut queues: Vec<std::collections::VecDeque<Transaction>> = vec![];
+        let mut queue_of_client: HashMap<u16, usize> = HashMap::new();
+        for transaction in transactions {
+            let index = *queue_of_client.entry(transaction.client).or_insert_with(|| {
+                queues.push(std::collections::VecDeque::new());
+                queues.len() - 1
+            });
+            queues[index].push_back(transaction);
+        }
+
+        let mut rng = XorShift(seed.max(1));
+        let mut emitted: HashSet<u32> = HashSet::new();
+        let mut shuffled = vec![];
+        loop {
+            let ready: Vec<usize> = (0..queues.len())
+                .filter(|index| match queues[*index].front() {
+                    Some(t) => match t.transaction_type {
+                        TransactionType::Deposit | TransactionType::Withdrawal => true,
+                        _ => !originals.contains(&t.tx) || emitted.contains(&t.tx),
+                    },
+                    None => false,
+                })
+                .collect();
+            if ready.is_empty() {
+                break;
+            }
+            let index = ready[rng.next_below(ready.len())];
+            let transaction = queues[index].pop_front().unwrap();
+            if matches!(transaction.transaction_type, TransactionType::Deposit | TransactionType::Withdrawal) {
+                emitted.insert(transaction.tx);
+            }
+            shuffled.push(transaction);
+        }
+        shuffled
+    }
+
+    // Processes the dataset in full and again filtered to `client`, asserting both runs agree on
+    // that client's account.
+    fn assert_client_isolated(transactions: &[Transaction], client: u16) {
+        let full = process_transactions(transactions.to_vec());
+        let filtered = process_transactions(filter_client(transactions.to_vec(), client));
+        assert_eq!(filtered.len(), 1);
+        assert_eq!(full.get(&client), filtered.get(&client));
+    }
+
+    #[test]
+    fn deposit_gets_processed_successfully() {
+        let t1 = Transaction::new(TransactionType::Deposit, 0, 0, amount(10.0));
+        let t2 = Transaction::new(TransactionType::Deposit, 0, 1, amount(20.0));
+        let accounts = process_transactions(vec![t1, t2]);
+        assert!(accounts.contains_key(&0));
+
+        let user_0_account = accounts.get(&0).unwrap();
+        assert_eq!(user_0_account.available, money(30.0));
+        assert_eq!(user_0_account.held, money(0.0));
+        assert_eq!(user_0_account.total(), money(30.0));
+    }
+
+    #[test]
+    fn withdrawal_is_ignored_if_insufficient_funds() {
+        let t1 = Transaction::new(TransactionType::Withdrawal, 0, 0, amount(10.0));
+        let t2 = Transaction::new(TransactionType::Withdrawal, 0, 1, amount(20.0));
+        let accounts = process_transactions(vec![t1, t2]);
+        assert!(accounts.contains_key(&0));
+
+        let user_0_account = accounts.get(&0).unwrap();
+        assert_eq!(user_0_account.available, money(0.0));
+        assert_eq!(user_0_account.held, money(0.0));
+        assert_eq!(user_0_account.total(), money(0.0));
+    }
+
+    #[test]
+    fn withdrawal_is_ignored_once_amount_exceeds_funds() {
+        let t1 = Transaction::new(TransactionType::Deposit, 0, 0, amount(20.0));
+        let t2 = Transaction::new(TransactionType::Withdrawal, 0, 1, amount(10.0));
+        let t3 = Transaction::new(TransactionType::Withdrawal, 0, 2, amount(12.0));
+        let accounts = process_transactions(vec![t1, t2, t3]);
+        assert!(accounts.contains_key(&0));
+
+        let user_0_account = accounts.get(&0).unwrap();
+        assert_eq!(user_0_account.available, money(10.0));
+        assert_eq!(user_0_account.held, money(0.0));
+        assert_eq!(user_0_account.total(), money(10.0));
+    }
+
+    #[test]
+    fn disputing_a_real_transaction() {
+        let t1 = Transaction::new(TransactionType::Deposit, 0, 0, amount(20.0));
+        let t2 = Transaction::new(TransactionType::Withdrawal, 0, 1, amount(5.0));
+        let t3 = Transaction::new(TransactionType::Dispute, 0, 1, None);
+        let accounts = process_transactions(vec![t1, t2, t3]);
+        assert!(accounts.contains_key(&0));
+
+        let user_0_account = accounts.get(&0).unwrap();
+        assert_eq!(user_0_account.disputed_transactions, vec![1]);
+        assert_eq!(user_0_account.available, money(15.0));
+        assert_eq!(user_0_account.held, money(5.0));
+        assert_eq!(user_0_account.total(), money(20.0));
+    }
+
+    #[test]
+    fn disputing_a_fake_transaction() {
+        let t1 = Transaction::new(TransactionType::Deposit, 0, 0, amount(20.0));
+        let t2 = Transaction::new(TransactionType::Withdrawal, 0, 1, amount(5.0));
+        let t3 = Transaction::new(TransactionType::Dispute, 0, 2, None);
+        let accounts = process_transactions(vec![t1, t2, t3]);
+        assert!(accounts.contains_key(&0));
+
+        let user_0_account = accounts.get(&0).unwrap();
+        assert_eq!(user_0_account.disputed_transactions, Vec::<u32>::new());
+        assert_eq!(user_0_account.available, money(15.0));
+        assert_eq!(user_0_account.held, money(0.0));
+        assert_eq!(user_0_account.total(), money(15.0));
+    }
+
+    #[test]
+    fn resolving_a_disputed_transaction() {
+        let t1 = Transaction::new(TransactionType::Deposit, 0, 0, amount(20.0));
+        let t2 = Transaction::new(TransactionType::Withdrawal, 0, 1, amount(5.0));
+        let t3 = Transaction::new(TransactionType::Dispute, 0, 1, None);
+        let t4 = Transaction::new(TransactionType::Resolve, 0, 1, None);
+        let accounts = process_transactions(vec![t1, t2, t3, t4]);
+        assert!(accounts.contains_key(&0));
+
+        let user_0_account = accounts.get(&0).unwrap();
+        assert_eq!(user_0_account.disputed_transactions, Vec::<u32>::new());
+        assert_eq!(user_0_account.available, money(15.0));
+        assert_eq!(user_0_account.held, money(0.0));
+        assert_eq!(user_0_account.total(), money(15.0));
+    }
+
+    #[test]
+    fn resolving_a_fake_disputed_transaction() {
+        let t1 = Transaction::new(TransactionType::Deposit, 0, 0, amount(20.0));
+        let t2 = Transaction::new(TransactionType::Withdrawal, 0, 1, amount(5.0));
+        let t3 = Transaction::new(TransactionType::Dispute, 0, 1, None);
+        let t4 = Transaction::new(TransactionType::Resolve, 0, 2, None);
+        let accounts = process_transactions(vec![t1, t2, t3, t4]);
+        assert!(accounts.contains_key(&0));
+
+        let user_0_account = accounts.get(&0).unwrap();
+        assert_eq!(user_0_account.disputed_transactions, vec![1]);
+        assert_eq!(user_0_account.available, money(15.0));
+        assert_eq!(user_0_account.held, money(5.0));
+        assert_eq!(user_0_account.total(), money(20.0));
+    }
+
+    // Runs `steps` for client 1 after deposits of 20 (tx 1) and 5 (tx 2), then checks the result.
+    fn assert_after_chargeback_sequence(steps: &[(TransactionType, u32)], available: f64, held: f64, frozen: bool) {
+        let mut transactions = vec![
+            Transaction::new(TransactionType::Deposit, 1, 1, amount(20.0)),
+            Transaction::new(TransactionType::Deposit, 1, 2, amount(5.0)),
+        ];
+        for &(transaction_type, tx) in steps {
+            transactions.push(Transaction::new(transaction_type, 1, tx, None));
+        }
+        let accounts = process_transactions(transactions);
+        let account = &accounts[&1];
+        let state = (account.available, account.held, account.frozen);
+        assert_eq!(state, (money(available), money(held), frozen), "{:?}", steps);
+    }
+
+    #[test]
+    fn disputes_follow_the_state_machine_around_chargebacks() {
+        use TransactionType::{Chargeback, Dispute, Resolve};
+        // A charged back transaction is no longer disputed, so there is nothing to resolve.
+        assert_after_chargeback_sequence(&[(Dispute, 1), (Chargeback, 1), (Resolve, 1)], 5.0, 0.0, true);
+        // The account is frozen, so another deposit can't be put under dispute.
+        assert_after_chargeback_sequence(&[(Dispute, 1), (Chargeback, 1), (Dispute, 2)], 5.0, 0.0, true);
+        // A chargeback without a dispute is ignored and doesn't freeze, so a later dispute applies.
+        assert_after_chargeback_sequence(&[(Chargeback, 1), (Dispute, 1)], 5.0, 20.0, false);
+        assert_after_chargeback_sequence(&[(Chargeback, 1), (Dispute, 1), (Chargeback, 1)], 5.0, 0.0, true);
+    }
+
+    #[test]
+    fn charged_back_transactions_stay_final_after_unfreezing() {
+        let mut engine = TransactionEngine::new(EngineConfig::default());
+        for (transaction_type, tx, value) in [
+            (TransactionType::Deposit, 1, Some(20.0)),
+            (TransactionType::Dispute, 1, None),
+            (TransactionType::Chargeback, 1, None),
+        ] {
+            engine.apply(Transaction::new(transaction_type, 1, tx, value.and_then(amount)));
+        }
+        engine.accounts.get_mut(&1).unwrap().unfreeze();
+        for transaction_type in [TransactionType::Dispute, TransactionType::Chargeback] {
+            engine.apply(Transaction::new(transaction_type, 1, 1, None));
+        }
+        let account = &engine.accounts[&1];
+        assert_eq!(account.available, money(0.0));
+        assert_eq!(account.held, money(0.0));
+        assert!(!account.frozen);
+        assert_eq!(engine.ignored_chargebacks(), 1);
+    }
+
+    #[test]
+    fn chargeback_a_disputed_transaction() {
+        let t1 = Transaction::new(TransactionType::Deposit, 0, 0, amount(20.0));
+        let t2 = Transaction::new(TransactionType::Withdrawal, 0, 1, amount(5.0));
+        let t3 = Transaction::new(TransactionType::Dispute, 0, 1, None);
+        let t4 = Transaction::new(TransactionType::Chargeback, 0, 1, None);
+        let accounts = process_transactions(vec![t1, t2, t3, t4]);
+        assert!(accounts.contains_key(&0));
+
+        let user_0_account = accounts.get(&0).unwrap();
+        assert_eq!(user_0_account.disputed_transactions, Vec::<u32>::new());
+        assert!(user_0_account.frozen);
+        assert_eq!(user_0_account.available, money(20.0));
+        assert_eq!(user_0_account.held, money(0.0));
+        assert_eq!(user_0_account.total(), money(20.0));
+    }
+
+    #[test]
+    fn chargeback_records_why_the_account_froze_until_it_is_unfrozen() {
+        let mut engine = TransactionEngine::new(EngineConfig::default());
+        for (transaction_type, tx, value) in [
+            (TransactionType::Deposit, 1, Some(20.0)),
+            (TransactionType::Deposit, 2, Some(5.0)),
+            (TransactionType::Dispute, 1, None),
+            (TransactionType::Dispute, 2, None),
+            (TransactionType::Chargeback, 1, None),
+        ] {
+            engine.apply(Transaction::new(transaction_type, 1, tx, value.and_then(amount)));
+        }
+        let mut account = engine.into_accounts().remove(&1).unwrap();
+        assert!(account.is_frozen());
+        assert_eq!(account.freeze_reason(), Some(FreezeReason::Chargeback));
+
+        account.unfreeze();
+        assert!(!account.is_frozen());
+        assert_eq!(account.freeze_reason(), None);
+        // The dispute left open by the freeze can be settled now.
+        assert!(account.resolve(2, money(5.0), TransactionType::Deposit));
+        assert_eq!(account.available(), money(5.0));
+        assert_eq!(account.held(), money(0.0));
+    }
+
+    #[test]
+    fn chargeback_an_existing_non_disputed_transaction() {
+        let t1 = Transaction::new(TransactionType::Deposit, 0, 0, amount(20.0));
+        let t2 = Transaction::new(TransactionType::Withdrawal, 0, 1, amount(5.0));
+        let t3 = Transaction::new(TransactionType::Dispute, 0, 1, None);
+        let t4 = Transaction::new(TransactionType::Chargeback, 0, 0, None);
+        let accounts = process_transactions(vec![t1, t2, t3, t4]);
+        assert!(accounts.contains_key(&0));
+
+        let user_0_account = accounts.get(&0).unwrap();
+        assert_eq!(user_0_account.disputed_transactions, vec![1]);
+        assert!(!user_0_account.frozen);
+        assert_eq!(user_0_account.available, money(15.0));
+        assert_eq!(user_0_account.held, money(5.0));
+        assert_eq!(user_0_account.total(), money(20.0));
+    }
+
+    #[test]
+    fn chargeback_a_non_existing_disputed_transaction() {
+        let t1 = Transaction::new(TransactionType::Deposit, 0, 0, amount(20.0));
+        let t2 = Transaction::new(TransactionType::Withdrawal, 0, 1, amount(5.0));
+        let t3 = Transaction::new(TransactionType::Dispute, 0, 1, None);
+        let t4 = Transaction::new(TransactionType::Chargeback, 0, 5, None);
+        let accounts = process_transactions(vec![t1, t2, t3, t4]);
+        assert!(accounts.contains_key(&0));
+
+        let user_0_account = accounts.get(&0).unwrap();
+        assert_eq!(user_0_account.disputed_transactions, vec![1]);
+        assert_eq!(user_0_account.available, money(15.0));
+        assert_eq!(user_0_account.held, money(5.0));
+        assert_eq!(user_0_account.total(), money(20.0));
+    }
+
+    #[test]
+    fn read_non_existent_csv_file() {
+        assert!(read_csv_file("NoSuchFile").is_err());
+    }
+
+    #[test]
+    fn read_existent_csv_file() {
+        assert!(read_csv_file("transaction.csv").is_ok());
+    }
+
+    #[test]
+    fn deposits_and_withdrawals_need_an_amount_when_read() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 1.0\n\
+                    deposit, 1, 2,\n";
+        match read_csv(data.as_bytes()) {
+            Err(TransactionError::MalformedRow { line, reason }) => {
+                assert_eq!(line, 3);
+                assert_eq!(reason, "deposit without an amount");
+            }
+            other => panic!("expected a malformed row error, got {:?}", other),
+        }
+        let data = "type, client, tx, amount\nwithdrawal, 1, 1,\n";
+        assert!(read_csv(data.as_bytes()).is_err());
+
+        // Streaming leaves the missing amount for the engine, which may quarantine the client.
+        let transaction = stream_csv(data.as_bytes()).next().unwrap().unwrap();
+        assert_eq!(transaction.amount, None);
+    }
+
+    #[test]
+    fn amounts_on_disputes_are_dropped_when_read() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 1.0\n\
+                    dispute, 1, 1, 99.0\n\
+                    resolve, 1, 1, 5.0\n";
+        let transactions = read_csv(data.as_bytes()).unwrap();
+        assert_eq!(transactions[0].amount, amount(1.0));
+        assert_eq!(transactions[1].amount, None);
+        assert_eq!(transactions[2].amount, None);
+        assert_eq!(stream_csv(data.as_bytes()).collect::<Result<Vec<_>, _>>().unwrap(), transactions);
+    }
+
+    #[test]
+    fn semicolon_separated_files_are_read() {
+        assert_eq!(read_csv_file("semicolon.csv").unwrap(), read_csv_file("test.csv").unwrap());
+    }
+
+    #[test]
+    fn byte_order_marks_are_skipped() {
+        let transactions = read_csv_file("bom.csv").unwrap();
+        assert_eq!(transactions[0], Transaction::new(TransactionType::Deposit, 1, 1, amount(1.0)));
+        assert_eq!(transactions, read_csv_file("test.csv").unwrap());
+
+        let ndjson = "\u{feff}{\"type\": \"deposit\", \"client\": 1, \"tx\": 1, \"amount\": 1.0}\n";
+        let transactions: Vec<Transaction> =
+            stream_transactions(ndjson.as_bytes(), InputFormat::Ndjson, ExcessPrecision::Round)
+                .collect::<Result<_, _>>()
+                .unwrap();
+        assert_eq!(transactions, read_csv_file("bom.csv").unwrap()[..1]);
+    }
+
+    #[test]
+    fn delimiter_is_taken_from_the_header() {
+        let comma = "type, client, tx, amount\ndeposit, 1, 1, 1.5\nwithdrawal, 1, 2, 0.5\n";
+        let semicolon = "type; client; tx; amount\ndeposit; 1; 1; 1.5\nwithdrawal; 1; 2; 0.5\n";
+        let tab = "type\tclient\ttx\tamount\ndeposit\t1\t1\t1.5\nwithdrawal\t1\t2\t0.5\n";
+        let expected = read_csv(comma.as_bytes()).unwrap();
+        assert_eq!(read_csv(semicolon.as_bytes()).unwrap(), expected);
+        assert_eq!(read_csv(tab.as_bytes()).unwrap(), expected);
+
+        let accounts = process_transactions_streaming(semicolon.as_bytes()).unwrap();
+        assert_eq!(accounts[&1].available, money(1.0));
+        let accounts = process_transactions_streaming(tab.as_bytes()).unwrap();
+        assert_eq!(accounts[&1].available, money(1.0));
+    }
+
+    #[test]
+    fn ensure_parsed_transactions_are_correct() {
+        let parsed_transactions = read_csv_file("test.csv");
+        assert!(parsed_transactions.is_ok());
+        let transactions = parsed_transactions.unwrap();
+        assert_eq!(transactions.len(), 6);
+        assert_eq!(transactions[0], Transaction::new(TransactionType::Deposit, 1, 1, amount(1.0)));
+
+        assert_eq!(transactions[1], Transaction::new(TransactionType::Withdrawal, 2, 2, amount(2.0)));
+
+        assert_eq!(transactions[2], Transaction::new(TransactionType::Dispute, 1, 1, None));
+
+        assert_eq!(transactions[3], Transaction::new(TransactionType::Resolve, 1, 4, None));
+
+        assert_eq!(transactions[4], Transaction::new(TransactionType::Dispute, 2, 2, None));
+
+        assert_eq!(transactions[5], Transaction::new(TransactionType::Chargeback, 2, 2, None));
+    }
+
+    #[test]
+    fn frozen_only_output_skips_open_accounts() {
+        let transactions = vec![
+            Transaction::new(TransactionType::Deposit, 1, 0, amount(20.0)),
+            Transaction::new(TransactionType::Deposit, 2, 1, amount(5.0)),
+            Transaction::new(TransactionType::Dispute, 2, 1, None),
+            Transaction::new(TransactionType::Chargeback, 2, 1, None),
+        ];
+        let accounts = process_transactions(transactions);
+        let frozen: Vec<u16> = account_records(&accounts, true, None).iter().map(|record| record.client).collect();
+        assert_eq!(frozen, vec![2]);
+
+        let mut output = Vec::new();
+        write_accounts(&accounts, &WriteOptions { frozen_only: true, ..WriteOptions::default() }, &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n2,0,0,0,true\n"
+        );
+    }
+
+    #[test]
+    fn positive_amount_rejects_invalid_values() {
+        assert_eq!(PositiveAmount::new(money(-1.0)), None);
+        assert_eq!(PositiveAmount::new(money(-0.0001)), None);
+        assert_eq!(PositiveAmount::new(money(0.0)), None);
+    }
+
+    #[test]
+    fn positive_amount_accepts_valid_values() {
+        assert_eq!(PositiveAmount::new(money(0.0001)).map(PositiveAmount::value), Some(money(0.0001)));
+        assert_eq!(PositiveAmount::new(money(12.5)).map(PositiveAmount::value), Some(money(12.5)));
+    }
+
+    #[test]
+    fn money_parses_and_formats_exactly() {
+        assert_eq!("1.2345".parse::<Money>(), Ok(Money(12345)));
+        assert_eq!("-0.5".parse::<Money>(), Ok(Money(-5000)));
+        assert_eq!("7".parse::<Money>(), Ok(Money(70000)));
+        assert_eq!(".25".parse::<Money>(), Ok(Money(2500)));
+        assert_eq!("1.23456".parse::<Money>(), Ok(Money(12346)));
+        assert_eq!("1.23454".parse::<Money>(), Ok(Money(12345)));
+        assert_eq!("-1.00005".parse::<Money>(), Ok(Money(-10001)));
+        assert_eq!("0.99995".parse::<Money>(), Ok(Money(10000)));
+        assert!("1.2.3".parse::<Money>().is_err());
+        assert!("abc".parse::<Money>().is_err());
+        assert!("".parse::<Money>().is_err());
+
+        assert_eq!(Money(70000).to_string(), "7");
+        assert_eq!(Money(15000).to_string(), "1.5");
+        assert_eq!(Money(12345).to_string(), "1.2345");
+        assert_eq!(Money(-1).to_string(), "-0.0001");
+
+        let tenth: Money = "0.1".parse().unwrap();
+        let sum: Money = std::iter::repeat_n(tenth, 3).sum();
+        assert_eq!(sum, "0.3".parse().unwrap());
+    }
+
+    #[test]
+    fn negative_amount_is_read_but_never_positive() {
+        let data = "type, client, tx, amount\ndeposit, 1, 1, -5.0\n";
+        let mut rdr = csv::ReaderBuilder::new().trim(Trim::All).from_reader(data.as_bytes());
+        let transactions: Vec<Transaction> = rdr.deserialize().collect::<Result<_, _>>().unwrap();
+        assert_eq!(transactions[0].amount, None);
+        assert_eq!(transactions[0].invalid_amount, Some(money(-5.0)));
+    }
+
+    #[test]
+    fn negative_and_zero_amounts_never_change_funds() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 10.0\n\
+                    deposit, 1, 2, -50.0\n\
+                    withdrawal, 1, 3, -50.0\n\
+                    deposit, 1, 4, 0\n\
+                    withdrawal, 1, 5, 0.0\n\
+                    deposit, 2, 6, -1.0\n";
+        // Each is skipped on its own; the rest of the file is still applied.
+        let accounts = process_transactions_streaming(data.as_bytes()).unwrap();
+        let account = accounts.get(&1).unwrap();
+        assert_eq!(account.available, money(10.0));
+        assert_eq!(account.total(), money(10.0));
+        // A client with nothing but a skipped deposit never gets an account.
+        assert!(!accounts.contains_key(&2));
+
+        let mut engine = TransactionEngine::new(EngineConfig::default());
+        engine.apply_stream(stream_csv(data.as_bytes()), None).unwrap();
+        assert_eq!(engine.anomalies(), 5);
+        assert_eq!(engine.invalid_amounts(), 5);
+
+        // Read up front, they are skipped rows like any other bad one.
+        let (transactions, errors) = read_transactions_skipping_errors(data.as_bytes()).unwrap();
+        assert_eq!(transactions.len(), 1);
+        assert_eq!(errors.get(&RowErrorKind::InvalidAmount), Some(&5));
+    }
+
+    #[test]
+    fn zero_amounts_are_never_disputable() {
+        // Amounts that round to zero are refused along with an explicit zero.
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 0\n\
+                    deposit, 1, 2, 0.00001\n\
+                    dispute, 1, 1,\n\
+                    dispute, 1, 2,\n";
+        let mut engine = TransactionEngine::new(EngineConfig::default());
+        engine.apply_stream(stream_csv(data.as_bytes()), None).unwrap();
+        assert!(engine.processed_transactions.is_empty());
+        assert_eq!(engine.accounts[&1].disputed_transactions.len(), 0);
+        assert_eq!(engine.accounts[&1].held, money(0.0));
+    }
+
+    #[test]
+    fn zero_amounts_can_fail_the_row_instead() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 10.0\n\
+                    withdrawal, 1, 2, -1.0\n\
+                    deposit, 1, 3, 0.00001\n";
+        let (format, precision) = (InputFormat::Csv, ExcessPrecision::Round);
+        let read = |zero_amounts| {
+            stream_transactions_noting_truncations(data.as_bytes(), format, precision, zero_amounts, |_| {})
+                .collect::<Vec<_>>()
+        };
+        assert!(read(ZeroAmounts::Skip).iter().all(Result::is_ok));
+
+        // Only the zero fails; a negative amount is still left for the engine to skip.
+        let results = read(ZeroAmounts::Reject);
+        assert!(results[1].is_ok());
+        let error = results[2].as_ref().unwrap_err();
+        assert!(matches!(error, TransactionError::MalformedRow { line: 4, .. }), "{}", error);
+    }
+
+    #[test]
+    fn deltas_against_baseline_only_include_changed_accounts() {
+        let baseline_csv = "client, available, held, total, locked\n\
+                            1, 10, 0, 10, false\n\
+                            2, 5, 0, 5, false\n\
+                            3, 7, 0, 7, false\n";
+        let baseline = read_baseline(baseline_csv.as_bytes()).unwrap();
+        let transactions = vec![
+            Transaction::new(TransactionType::Deposit, 1, 10, amount(2.5)),
+            Transaction::new(TransactionType::Withdrawal, 2, 11, amount(5.0)),
+            Transaction::new(TransactionType::Withdrawal, 3, 12, amount(100.0)),
+            Transaction::new(TransactionType::Deposit, 4, 13, amount(1.0)),
+        ];
+        let accounts = process_transactions_from(baseline.clone(), transactions, &EngineConfig::default());
+        assert_eq!(accounts.get(&1).unwrap().available, money(12.5));
+
+        let deltas = diff_accounts(&baseline, &accounts);
+        assert_eq!(deltas, vec![
+            AccountDelta { client: 1, available: money(2.5), held: money(0.0), locked: Some(false) },
+            AccountDelta { client: 2, available: money(-5.0), held: money(0.0), locked: Some(false) },
+            AccountDelta { client: 4, available: money(1.0), held: money(0.0), locked: Some(false) },
+        ]);
+
+        let mut output = Vec::new();
+        write_deltas(&deltas, &WriteOptions::default(), &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,locked\n1,2.5,0,false\n2,-5,0,false\n4,1,0,false\n"
+        );
+
+        let options = WriteOptions { header: false, delimiter: b';', ..WriteOptions::default() };
+        let mut output = Vec::new();
+        write_deltas(&deltas, &options, &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "1;2.5;0;false\n2;-5;0;false\n4;1;0;false\n");
+    }
+
+    #[test]
+    fn deltas_report_baseline_clients_that_were_removed() {
+        let baseline_csv = "client, available, held, total, locked\n\
+                            1, 10, 0, 10, false\n\
+                            2, 5, 0, 5, false\n";
+        let baseline = read_baseline(baseline_csv.as_bytes()).unwrap();
+        // A deposit without an amount gets client 2 quarantined, and its account dropped.
+        let transactions = vec![Transaction::new(TransactionType::Deposit, 2, 1, None)];
+        let config = EngineConfig { isolate_clients: true, ..EngineConfig::default() };
+        let accounts = process_transactions_from(baseline.clone(), transactions, &config);
+        assert!(!accounts.contains_key(&2));
+
+        let deltas = diff_accounts(&baseline, &accounts);
+        assert_eq!(deltas, vec![AccountDelta { client: 2, available: money(-5.0), held: money(0.0), locked: None }]);
+        let mut output = Vec::new();
+        write_deltas(&deltas, &WriteOptions::default(), &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "client,available,held,locked\n2,-5,0,\n");
+    }
+
+    #[test]
+    fn baseline_with_duplicate_client_is_rejected() {
+        let baseline_csv = "client, available, held, total, locked\n\
+                            1, 10, 0, 10, false\n\
+                            2, 5, 0, 5, false\n\
+                            1, 3, 0, 3, false\n";
+        match read_baseline(baseline_csv.as_bytes()) {
+            Err(BaselineError::DuplicateClient { client, line }) => {
+                assert_eq!(client, 1);
+                assert_eq!(line, 4);
+            }
+            other => panic!("expected a duplicate client error, got {:?}", other.map(|accounts| accounts.len())),
+        }
+    }
+
+    #[test]
+    fn accounts_round_trip_through_bincode() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 10.1234\n\
+                    deposit, 1, 2, 5.0\n\
+                    dispute, 1, 2,\n\
+                    deposit, 2, 3, 7.0\n\
+                    withdrawal, 2, 4, 2.5\n\
+                    dispute, 2, 3,\n\
+                    chargeback, 2, 3,\n";
+        let transactions = stream_csv(data.as_bytes()).collect::<Result<Vec<_>, _>>().unwrap();
+        let accounts = process_transactions(transactions);
+
+        let mut checkpoint = vec![];
+        write_accounts_bincode(&accounts, &mut checkpoint).unwrap();
+        let restored = read_accounts_bincode(checkpoint.as_slice()).unwrap();
+        assert_eq!(restored, accounts);
+        assert_eq!(restored[&1].disputed_transactions, vec![2]);
+        assert_eq!(restored[&2].freeze_reason(), Some(FreezeReason::Chargeback));
+
+        // Resuming from the checkpoint carries on from the saved balances.
+        let resumed = process_transactions_from(
+            restored,
+            vec![Transaction::new(TransactionType::Deposit, 1, 5, amount(1.0))],
+            &EngineConfig::default(),
+        );
+        assert_eq!(resumed[&1].available, money(11.1234));
+        assert_eq!(resumed[&1].held, money(5.0));
+    }
+
+    #[test]
+    fn deposit_above_max_balance_is_rejected() {
+        let t1 = Transaction::new(TransactionType::Deposit, 0, 0, amount(50.0));
+        let t2 = Transaction::new(TransactionType::Deposit, 0, 1, amount(1_000_000.0));
+        let t3 = Transaction::new(TransactionType::Deposit, 0, 2, amount(50.0));
+        let t4 = Transaction::new(TransactionType::Dispute, 0, 1, None);
+        let config = EngineConfig {
+            max_balance: Some(money(100.0)),
+            ..EngineConfig::default()
+        };
+        let accounts = process_transactions_from(BTreeMap::new(), vec![t1, t2, t3, t4], &config);
+
+        let user_0_account = accounts.get(&0).unwrap();
+        assert_eq!(user_0_account.available, money(100.0));
+        assert_eq!(user_0_account.held, money(0.0));
+        assert_eq!(user_0_account.disputed_transactions, Vec::<u32>::new());
+    }
+
+    #[test]
+    fn clients_are_processed_independently() {
+        let transactions = vec![
+            Transaction::new(TransactionType::Deposit, 1, 1, amount(20.0)),
+            Transaction::new(TransactionType::Deposit, 2, 2, amount(15.0)),
+            Transaction::new(TransactionType::Withdrawal, 1, 3, amount(5.0)),
+            Transaction::new(TransactionType::Dispute, 2, 2, None),
+            Transaction::new(TransactionType::Deposit, 3, 4, amount(7.5)),
+            Transaction::new(TransactionType::Dispute, 1, 1, None),
+            Transaction::new(TransactionType::Resolve, 1, 1, None),
+            Transaction::new(TransactionType::Chargeback, 2, 2, None),
+            Transaction::new(TransactionType::Withdrawal, 3, 5, amount(2.5)),
+        ];
+        for client in 1..=3 {
+            assert_client_isolated(&transactions, client);
+        }
+    }
+
+    #[test]
+    fn control_trailer_is_extracted_not_processed() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 10.0\n\
+                    withdrawal, 1, 2, 2.5\n\
+                    total, , , 12.5\n";
+        let (transactions, control_total) = read_transactions_with_trailer(data.as_bytes(), "total").unwrap();
+        assert_eq!(control_total, Some(money(12.5)));
+        assert_eq!(transactions.len(), 2);
+        assert!(transactions.iter().all(|transaction| transaction.client == 1));
+        assert_eq!(amount_total(&transactions), money(12.5));
+
+        let accounts = process_transactions(transactions);
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts.get(&1).unwrap().available, money(7.5));
+    }
+
+    #[test]
+    fn missing_control_trailer_is_reported_as_none() {
+        let data = "type, client, tx, amount\ndeposit, 1, 1, 10.0\n";
+        let (transactions, control_total) = read_transactions_with_trailer(data.as_bytes(), "total").unwrap();
+        assert_eq!(transactions.len(), 1);
+        assert_eq!(control_total, None);
+    }
+
+    #[test]
+    fn trailer_files_check_amounts_like_any_other_read() {
+        // A dispute's own amount is dropped, as it is everywhere else.
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 10.0\n\
+                    dispute, 1, 1, 10.0\n\
+                    total, , , 10.0\n";
+        let (transactions, _) = read_transactions_with_trailer(data.as_bytes(), "total").unwrap();
+        assert_eq!(transactions[1].amount, None);
+        assert_eq!(amount_total(&transactions), money(10.0));
+
+        // And, like `read_csv_file`, a deposit without one fails the read.
+        let data = "type, client, tx, amount\ndeposit, 1, 1,\ntotal, , , 0\n";
+        let error = read_transactions_with_trailer(data.as_bytes(), "total").unwrap_err();
+        assert!(matches!(error, TransactionError::MalformedRow { line: 2, .. }), "{}", error);
+    }
+
+    #[test]
+    fn ndjson_output_writes_one_object_per_line() {
+        let t1 = Transaction::new(TransactionType::Deposit, 1, 1, amount(10.0));
+        let t2 = Transaction::new(TransactionType::Deposit, 2, 2, amount(2.5));
+        let accounts = process_transactions(vec![t1, t2]);
+
+        let mut output = Vec::new();
+        write_accounts_ndjson(&accounts, false, None, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let mut lines: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        lines.sort_by_key(|line| line["client"].as_u64());
+        assert_eq!(lines, vec![
+            serde_json::json!({"client": 1, "available": 10.0, "held": 0.0, "total": 10.0, "locked": false}),
+            serde_json::json!({"client": 2, "available": 2.5, "held": 0.0, "total": 2.5, "locked": false}),
+        ]);
+    }
+
+    fn dispute_resolve_dispute() -> Vec<Transaction> {
+        vec![
+            Transaction::new(TransactionType::Deposit, 0, 0, amount(20.0)),
+            Transaction::new(TransactionType::Dispute, 0, 0, None),
+            Transaction::new(TransactionType::Resolve, 0, 0, None),
+            Transaction::new(TransactionType::Dispute, 0, 0, None),
+        ]
+    }
+
+    #[test]
+    fn redispute_after_resolve_holds_funds_again_when_allowed() {
+        let config = EngineConfig::default();
+        assert!(config.allow_redispute);
+        let accounts = process_transactions_from(BTreeMap::new(), dispute_resolve_dispute(), &config);
+
+        let user_0_account = accounts.get(&0).unwrap();
+        assert_eq!(user_0_account.disputed_transactions, vec![0]);
+        assert_eq!(user_0_account.held, money(20.0));
+    }
+
+    #[test]
+    fn redispute_after_resolve_is_ignored_when_rejected() {
+        let config = EngineConfig {
+            allow_redispute: false,
+            ..EngineConfig::default()
+        };
+        let accounts = process_transactions_from(BTreeMap::new(), dispute_resolve_dispute(), &config);
+
+        let user_0_account = accounts.get(&0).unwrap();
+        assert_eq!(user_0_account.disputed_transactions, Vec::<u32>::new());
+        assert_eq!(user_0_account.held, money(0.0));
+    }
+
+    #[test]
+    fn open_disputes_can_be_capped_per_client() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 10.0\n\
+                    deposit, 1, 2, 5.0\n\
+                    deposit, 2, 3, 1.0\n\
+                    dispute, 1, 1,\n\
+                    dispute, 1, 2,\n\
+                    dispute, 2, 3,\n";
+        let config = EngineConfig { max_open_disputes: Some(1), record_events: true, ..EngineConfig::default() };
+        let mut engine = TransactionEngine::new(config);
+        engine.apply_stream(stream_csv(data.as_bytes()), None).unwrap();
+        // Client 1's second dispute is over the limit; client 2 has a limit of its own.
+        assert_eq!(engine.anomalies(), 1);
+        assert_eq!(
+            engine.events()[0].to_string(),
+            "ignored dispute tx 2 for client 1: client has too many open disputes"
+        );
+        let accounts = engine.into_accounts();
+        assert_eq!(accounts[&1].disputed_transactions, vec![1]);
+        assert_eq!(accounts[&1].held, money(10.0));
+        assert_eq!(accounts[&2].held, money(1.0));
+
+        // Without a limit both disputes are open.
+        let accounts = process_transactions_streaming(data.as_bytes()).unwrap();
+        assert_eq!(accounts[&1].held, money(15.0));
+
+        // Once the open dispute is settled there is room for another.
+        let data = format!("{}resolve, 1, 1,\ndispute, 1, 2,\n", data);
+        let config = EngineConfig { max_open_disputes: Some(1), ..EngineConfig::default() };
+        let accounts = process_transactions_from(BTreeMap::new(), read_csv(data.as_bytes()).unwrap(), &config);
+        assert_eq!(accounts[&1].disputed_transactions, vec![2]);
+        assert_eq!(accounts[&1].held, money(5.0));
+    }
+
+    #[test]
+    fn transactions_are_processed_in_sequence_order() {
+        let data = "type, client, tx, amount, seq\n\
+                    withdrawal, 1, 2, 15.0, 2\n\
+                    dispute, 1, 1, , 3\n\
+                    deposit, 1, 1, 20.0, 1\n";
+        let mut rdr = csv::ReaderBuilder::new().trim(Trim::All).from_reader(data.as_bytes());
+        let mut transactions: Vec<Transaction> = rdr.deserialize().collect::<Result<_, _>>().unwrap();
+
+        // In file order the withdrawal and dispute arrive before the deposit and are ignored.
+        let unsorted = process_transactions(transactions.clone());
+        assert_eq!(unsorted.get(&1).unwrap().available, money(20.0));
+        assert_eq!(unsorted.get(&1).unwrap().held, money(0.0));
+
+        sort_by_sequence(&mut transactions);
+        assert_eq!(transactions.iter().map(|t| t.tx).collect::<Vec<_>>(), vec![1, 2, 1]);
+        let accounts = process_transactions(transactions);
+        let user_1_account = accounts.get(&1).unwrap();
+        assert_eq!(user_1_account.available, money(-15.0));
+        assert_eq!(user_1_account.held, money(20.0));
+        assert_eq!(user_1_account.disputed_transactions, vec![1]);
+    }
+
+    #[test]
+    fn sequence_column_is_optional() {
+        let data = "type, client, tx, amount\ndeposit, 1, 1, 20.0\n";
+        let mut rdr = csv::ReaderBuilder::new().trim(Trim::All).from_reader(data.as_bytes());
+        let transactions: Vec<Transaction> = rdr.deserialize().collect::<Result<_, _>>().unwrap();
+        assert_eq!(transactions[0].seq, None);
+    }
+
+    #[test]
+    fn transactions_are_processed_in_timestamp_order() {
+        let data = "type, client, tx, amount, timestamp\n\
+                    withdrawal, 1, 2, 15.0, 2024-03-01T10:00:00Z\n\
+                    dispute, 1, 1, , 1709287200000\n\
+                    deposit, 1, 1, 20.0, 2024-03-01T09:00:00+00:00\n";
+        let mut rdr = csv::ReaderBuilder::new().trim(Trim::All).from_reader(data.as_bytes());
+        let mut transactions: Vec<Transaction> = rdr.deserialize().collect::<Result<_, _>>().unwrap();
+        assert_eq!(transactions[1].timestamp, Some(Timestamp::from_millis(1_709_287_200_000)));
+
+        sort_by_timestamp(&mut transactions);
+        assert_eq!(transactions.iter().map(|t| t.tx).collect::<Vec<_>>(), vec![1, 2, 1]);
+        let accounts = process_transactions(transactions);
+        let user_1_account = accounts.get(&1).unwrap();
+        assert_eq!(user_1_account.available, money(-15.0));
+        assert_eq!(user_1_account.held, money(20.0));
+
+        // Without timestamps the sort leaves the file order alone.
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 2, 1.0\n\
+                    deposit, 1, 1, 1.0\n";
+        let mut rdr = csv::ReaderBuilder::new().trim(Trim::All).from_reader(data.as_bytes());
+        let mut transactions: Vec<Transaction> = rdr.deserialize().collect::<Result<_, _>>().unwrap();
+        sort_by_timestamp(&mut transactions);
+        assert_eq!(transactions.iter().map(|t| t.tx).collect::<Vec<_>>(), vec![2, 1]);
+
+        assert!("yesterday".parse::<Timestamp>().is_err());
+    }
+
+    #[test]
+    fn skipped_rows_are_tallied_by_kind() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 10.0\n\
+                    deposit, 1, 2, ten\n\
+                    deposit, 1, 3,\n\
+                    withdrawal, 2, 4,\n\
+                    deposit, 70000, 5, 1.0\n\
+                    refund, 1, 6, 1.0\n\
+                    withdrawal, 1, 7, 2.0\n\
+                    dispute, 1, 1,\n";
+        let (transactions, errors) = read_transactions_skipping_errors(data.as_bytes()).unwrap();
+        assert_eq!(transactions.iter().map(|t| t.tx).collect::<Vec<_>>(), vec![1, 7, 1]);
+        assert_eq!(errors.get(&RowErrorKind::Parse), Some(&1));
+        assert_eq!(errors.get(&RowErrorKind::MissingAmount), Some(&2));
+        assert_eq!(errors.get(&RowErrorKind::ClientOutOfRange), Some(&1));
+        assert_eq!(errors.get(&RowErrorKind::UnknownType), Some(&1));
+
+        let mut summary = Vec::new();
+        write_error_summary(&errors, "skipped rows", &mut summary).unwrap();
+        assert_eq!(
+            String::from_utf8(summary).unwrap(),
+            "skipped rows: 5\n  parse error: 1\n  missing amount: 2\n  client id out of range: 1\n  unknown type: 1\n"
+        );
+    }
+
+    #[test]
+    fn fully_held_accounts_are_warned_about() {
+        let mut accounts = BTreeMap::new();
+        accounts.insert(1, Account {
+            disputed_transactions: vec![7].into(),
+            frozen: false,
+            held: money(25.0),
+            available: money(0.0),
+            ..Account::new()
+        });
+        accounts.insert(2, Account {
+            disputed_transactions: vec![8].into(),
+            frozen: false,
+            held: money(5.0),
+            available: money(10.0),
+            ..Account::new()
+        });
+        accounts.insert(3, Account {
+            disputed_transactions: DisputedTransactions::default(),
+            frozen: false,
+            held: Money::ZERO,
+            available: Money::ZERO,
+            ..Account::new()
+        });
+        assert_eq!(fully_held_clients(&accounts), vec![1]);
+
+        let mut warnings = Vec::new();
+        write_fully_held_warnings(&accounts, &mut warnings).unwrap();
+        assert_eq!(
+            String::from_utf8(warnings).unwrap(),
+            "warning: client 1 has 25 held and no available funds\n"
+        );
+    }
+
+    #[test]
+    fn lines_are_applied_one_at_a_time() {
+        let mut engine = TransactionEngine::new(EngineConfig::default());
+        process_line(&mut engine, "deposit, 1, 1, 10.0").unwrap();
+        assert_eq!(engine.accounts.get(&1).unwrap().available, money(10.0));
+
+        process_line(&mut engine, "withdrawal, 1, 2, 4.0").unwrap();
+        let user_1_account = engine.accounts.get(&1).unwrap();
+        assert_eq!(user_1_account.available, money(6.0));
+        assert_eq!(user_1_account.total(), money(6.0));
+    }
+
+    #[test]
+    fn invalid_lines_are_rejected() {
+        let mut engine = TransactionEngine::new(EngineConfig::default());
+        assert!(matches!(process_line(&mut engine, ""), Err(TransactionError::EmptyLine)));
+        assert!(matches!(process_line(&mut engine, "deposit, one, 1, 10.0"), Err(TransactionError::MalformedRow { .. })));
+        assert!(engine.accounts.is_empty());
+    }
+
+    #[test]
+    fn processing_stops_at_the_deadline() {
+        let transactions: Vec<Transaction> = (0..100_000u32)
+            .map(|tx| Transaction::new(TransactionType::Deposit, (tx % 5000) as u16, tx, amount(1.0)))
+            .collect();
+        let mut engine = TransactionEngine::new(EngineConfig::default());
+        let finished = engine.apply_all(transactions, Some(Instant::now()));
+        assert!(!finished);
+
+        let accounts = engine.into_accounts();
+        assert_eq!(accounts.len(), DEADLINE_CHECK_INTERVAL);
+        let total: Money = accounts.values().map(Account::total).sum();
+        assert_eq!(total, money(DEADLINE_CHECK_INTERVAL as f64));
+    }
+
+    #[test]
+    fn processing_without_a_deadline_finishes() {
+        let transactions: Vec<Transaction> = (0..3000u32)
+            .map(|tx| Transaction::new(TransactionType::Deposit, 1, tx, amount(1.0)))
+            .collect();
+        let mut engine = TransactionEngine::new(EngineConfig::default());
+        assert!(engine.apply_all(transactions, None));
+        assert_eq!(engine.accounts.get(&1).unwrap().available, money(3000.0));
+    }
+
+    #[test]
+    fn prometheus_output_writes_gauges_per_client() {
+        let transactions = vec![
+            Transaction::new(TransactionType::Deposit, 2, 1, amount(5.0)),
+            Transaction::new(TransactionType::Deposit, 1, 2, amount(30.0)),
+            Transaction::new(TransactionType::Dispute, 2, 1, None),
+            Transaction::new(TransactionType::Chargeback, 2, 1, None),
+        ];
+        let accounts = process_transactions(transactions);
+
+        let mut output = Vec::new();
+        write_accounts_prometheus(&accounts, false, None, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let samples: Vec<&str> = output.lines().filter(|line| !line.starts_with('#')).collect();
+        assert_eq!(samples, vec![
+            "account_available{client=\"1\"} 30",
+            "account_available{client=\"2\"} 0",
+            "account_held{client=\"1\"} 0",
+            "account_held{client=\"2\"} 0",
+            "account_total{client=\"1\"} 30",
+            "account_total{client=\"2\"} 0",
+            "account_locked{client=\"1\"} 0",
+            "account_locked{client=\"2\"} 1",
+        ]);
+        assert!(output.contains("# TYPE account_locked gauge\n"));
+    }
+
+    #[test]
+    fn resolve_restores_available_to_its_pre_dispute_value() {
+        let t1 = Transaction::new(TransactionType::Deposit, 0, 0, amount(20.0));
+        let t2 = Transaction::new(TransactionType::Deposit, 0, 1, amount(7.5));
+        let before = process_transactions(vec![t1.clone(), t2.clone()]);
+        let before = before.get(&0).unwrap();
+        assert_eq!(before.available, money(27.5));
+
+        let t3 = Transaction::new(TransactionType::Dispute, 0, 1, None);
+        let disputed = process_transactions(vec![t1.clone(), t2.clone(), t3.clone()]);
+        let disputed = disputed.get(&0).unwrap();
+        assert_eq!(disputed.available, money(20.0));
+        assert_eq!(disputed.held, money(7.5));
+        assert_eq!(disputed.total(), money(27.5));
+
+        let t4 = Transaction::new(TransactionType::Resolve, 0, 1, None);
+        let resolved = process_transactions(vec![t1, t2, t3, t4]);
+        let resolved = resolved.get(&0).unwrap();
+        assert_eq!(resolved.available, before.available);
+        assert_eq!(resolved.held, before.held);
+        assert_eq!(resolved.total(), before.total());
+    }
+
+    #[test]
+    fn transactions_for_clients_off_the_allow_list_are_rejected() {
+        let allowed_clients = read_client_list("1\n2\n\n".as_bytes()).unwrap();
+        let config = EngineConfig {
+            allowed_clients: Some(allowed_clients),
+            ..EngineConfig::default()
+        };
+        let mut engine = TransactionEngine::new(config);
+        for (client, tx) in [(1, 1), (2, 2), (3, 3), (3, 4), (1, 5)].iter() {
+            engine.apply(Transaction::new(TransactionType::Deposit, *client, *tx, amount(10.0)));
+        }
+        assert_eq!(engine.unlisted_client_transactions, 2);
+
+        let accounts = engine.into_accounts();
+        assert!(!accounts.contains_key(&3));
+        assert_eq!(accounts.get(&1).unwrap().available, money(20.0));
+        assert_eq!(accounts.get(&2).unwrap().available, money(10.0));
+    }
+
+    #[test]
+    fn invalid_allow_list_entries_are_rejected() {
+        assert!(read_client_list("1\nseventy\n".as_bytes()).is_err());
+    }
+
+    fn deposit_withdraw_then_dispute_deposit() -> Vec<Transaction> {
+        vec![
+            Transaction::new(TransactionType::Deposit, 0, 0, amount(100.0)),
+            Transaction::new(TransactionType::Withdrawal, 0, 1, amount(100.0)),
+            Transaction::new(TransactionType::Dispute, 0, 0, None),
+        ]
+    }
+
+    #[test]
+    fn disputing_withdrawn_deposit_goes_negative_when_allowed() {
+        let config = EngineConfig::default();
+        assert!(config.allow_negative_available);
+        let accounts = process_transactions_from(BTreeMap::new(), deposit_withdraw_then_dispute_deposit(), &config);
+
+        let user_0_account = accounts.get(&0).unwrap();
+        assert_eq!(user_0_account.available, money(-100.0));
+        assert_eq!(user_0_account.held, money(100.0));
+        assert_eq!(user_0_account.total(), money(0.0));
+        assert_eq!(user_0_account.disputed_transactions, vec![0]);
+    }
+
+    #[test]
+    fn disputing_withdrawn_deposit_is_ignored_when_negative_rejected() {
+        let config = EngineConfig {
+            allow_negative_available: false,
+            ..EngineConfig::default()
+        };
+        let accounts = process_transactions_from(BTreeMap::new(), deposit_withdraw_then_dispute_deposit(), &config);
+
+        let user_0_account = accounts.get(&0).unwrap();
+        assert_eq!(user_0_account.available, money(0.0));
+        assert_eq!(user_0_account.held, money(0.0));
+        assert_eq!(user_0_account.disputed_transactions, Vec::<u32>::new());
+    }
+
+    #[test]
+    fn describe_lists_balances_and_open_disputes() {
+        let mut engine = TransactionEngine::new(EngineConfig::default());
+        engine.apply(Transaction::new(TransactionType::Deposit, 4, 1, amount(20.0)));
+        engine.apply(Transaction::new(TransactionType::Deposit, 4, 2, amount(5.0)));
+        engine.apply(Transaction::new(TransactionType::Dispute, 4, 2, None));
+        assert_eq!(
+            engine.describe(4),
+            "client 4\n  available: 20\n  held: 5\n  total: 25\n  locked: no\n  open disputes: 2"
+        );
+        assert_eq!(engine.describe(9), "client 9: no account");
+
+        engine.apply(Transaction::new(TransactionType::Chargeback, 4, 2, None));
+        assert!(engine.describe(4).contains("  locked: yes (chargeback)\n"));
+
+        let frozen = Account { frozen: true, ..Account::new() };
+        let engine = TransactionEngine::with_accounts(BTreeMap::from([(4, frozen)]), EngineConfig::default());
+        assert!(engine.describe(4).contains("  locked: yes\n"));
+    }
+
+    #[test]
+    fn empty_input_writes_the_chosen_indicator() {
+        let (transactions, _) = read_transactions_skipping_errors("type, client, tx, amount\n".as_bytes()).unwrap();
+        let accounts = process_transactions(transactions);
+        assert!(accounts.is_empty());
+
+        let mut output = Vec::new();
+        assert!(!write_empty_indicator(EmptyOutput::Marker, &mut output).unwrap());
+        assert_eq!(String::from_utf8(output).unwrap(), "no accounts\n");
+
+        let mut output = Vec::new();
+        assert!(write_empty_indicator(EmptyOutput::Fail, &mut output).unwrap());
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn disputed_transactions_behave_like_a_list_of_ids() {
+        let mut disputed = DisputedTransactions::default();
+        let mut reference: Vec<u32> = vec![];
+        assert!(disputed.is_empty());
+        for id in [5, 1, 9, 3].iter() {
+            disputed.insert(*id);
+            reference.push(*id);
+        }
+        for id in [1, 9].iter() {
+            disputed.remove(*id);
+            reference.retain(|x| x != id);
+        }
+        disputed.remove(42);
+        for id in 0..12 {
+            assert_eq!(disputed.contains(id), reference.contains(&id), "membership of {}", id);
+        }
+        let mut ids: Vec<u32> = disputed.iter().collect();
+        ids.sort_unstable();
+        assert_eq!(ids, vec![3, 5]);
+        assert!(!disputed.is_empty());
+    }
+
+    #[test]
+    fn disputed_transactions_memory_for_clustered_ids() {
+        let ids: Vec<u32> = (1_000_000..1_100_000).collect();
+        let list_size = ids.len() * std::mem::size_of::<u32>();
+        let disputed = DisputedTransactions::from(ids);
+        if cfg!(feature = "compact-disputes") {
+            assert!(disputed.heap_size() * 10 < list_size, "{} vs {}", disputed.heap_size(), list_size);
+        } else {
+            assert!(disputed.heap_size() >= list_size);
+        }
+    }
+
+    #[test]
+    fn shuffling_independent_clients_does_not_change_balances() {
+        let mut transactions = vec![];
+        let mut tx = 0;
+        for round in 0..10u16 {
+            for client in 0..20u16 {
+                tx += 1;
+                transactions.push(Transaction::new(
+                    TransactionType::Deposit,
+                    client,
+                    tx,
+                    amount(f64::from(client + round + 1)),
+                ));
+            }
+        }
+        for client in (0..20u16).step_by(3) {
+            transactions.push(Transaction::new(TransactionType::Dispute, client, u32::from(client) + 1, None));
+        }
+        let expected = process_transactions(transactions.clone());
+
+        for seed in 1..=5 {
+            let shuffled = shuffle_preserving_dependencies(transactions.clone(), seed);
+            assert_eq!(shuffled.len(), transactions.len());
+            assert_ne!(shuffled, transactions);
+            for client in 0..20u16 {
+                let own = |list: &[Transaction]| -> Vec<u32> {
+                    list.iter().filter(|t| t.client == client).map(|t| t.tx).collect()
+                };
+                assert_eq!(own(&shuffled), own(&transactions));
+            }
+            assert_eq!(process_transactions(shuffled), expected, "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn withdrawing_the_full_balance_is_exact() {
+        let deposits = |value: f64, count: u32| -> Vec<Transaction> {
+            (0..count)
+                .map(|tx| Transaction::new(TransactionType::Deposit, 0, tx, amount(value)))
+                .collect()
+        };
+        let withdrawal = |value: f64| Transaction::new(TransactionType::Withdrawal, 0, 100, amount(value));
+
+        let mut transactions = deposits(0.1, 3);
+        transactions.push(withdrawal(0.3));
+        assert_eq!(process_transactions(transactions)[&0].available, money(0.0));
+
+        // As f32, ten deposits of 0.3 summed to one ULP under 3.0.
+        let mut transactions = deposits(0.3, 10);
+        transactions.push(withdrawal(3.0));
+        assert_eq!(process_transactions(transactions)[&0].available, money(0.0));
+
+        let mut transactions = deposits(0.1, 3);
+        transactions.push(withdrawal(0.3001));
+        assert_eq!(process_transactions(transactions)[&0].available, money(0.3));
+    }
+
+    #[test]
+    fn accounts_can_be_written_with_another_delimiter() {
+        let accounts = process_transactions_streaming("type,client,tx,amount\ndeposit,1,1,1.5\n".as_bytes()).unwrap();
+        let mut output = vec![];
+        write_accounts(&accounts, &WriteOptions { delimiter: b';', ..WriteOptions::default() }, &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "client;available;held;total;locked\n1;1.5;0;1.5;false\n");
+    }
+
+    #[test]
+    fn many_small_deposits_do_not_drift() {
+        let transactions: Vec<Transaction> = (0..100_000)
+            .map(|tx| Transaction::new(TransactionType::Deposit, 0, tx, amount(0.0001)))
+            .collect();
+        let account = &process_transactions(transactions)[&0];
+        assert_eq!(account.available, money(10.0));
+        assert_eq!(account.total().to_string(), "10");
+    }
+
+    #[test]
+    fn only_clients_diverging_from_expected_state_are_reported() {
+        let expected = read_baseline(
+            "client, available, held, total, locked\n\
+             1, 10, 0, 10, false\n\
+             2, 5, 0, 5, false\n\
+             3, 0, 0, 0, true\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let transactions = read_transactions_skipping_errors(
+            "type, client, tx, amount\n\
+             deposit, 1, 1, 10.0\n\
+             deposit, 2, 2, 7.0\n\
+             deposit, 3, 3, 4.0\n\
+             dispute, 3, 3,\n\
+             chargeback, 3, 3,\n"
+                .as_bytes(),
+        )
+        .unwrap()
+        .0;
+        let divergences = diverging_accounts(&expected, &process_transactions(transactions));
+        assert_eq!(divergences, vec![Divergence {
+            client: 2,
+            expected: Some((money(5.0), money(0.0), false)),
+            actual: Some((money(7.0), money(0.0), false)),
+        }]);
+
+        let mut output = vec![];
+        write_divergences(&divergences, &WriteOptions::default(), &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,expected_available,expected_held,expected_locked,available,held,locked\n\
+             2,5,0,false,7,0,false\n"
+        );
+
+        let missing = Divergence { client: 4, expected: None, actual: Some((money(1.0), money(0.0), false)) };
+        let options = WriteOptions { header: false, delimiter: b'\t', ..WriteOptions::default() };
+        let mut output = vec![];
+        write_divergences(&[missing], &options, &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "4\t\t\t\t1\t0\tfalse\n");
+    }
+
+    #[test]
+    fn control_operations_cannot_be_disputed() {
+        let mut engine = TransactionEngine::new(EngineConfig::default());
+        engine.apply(Transaction::new(TransactionType::Deposit, 1, 1, amount(10.0)));
+        // Simulate a future change that records control operations alongside the originals.
+        engine.processed_transactions.insert((1, 2), ProcessedTransaction {
+            transaction_type: TransactionType::Dispute,
+            client: 1,
+            amount: money(10.0),
+        });
+        for transaction_type in [TransactionType::Dispute, TransactionType::Chargeback] {
+            engine.apply(Transaction::new(transaction_type, 1, 2, None));
+        }
+        let account = &engine.accounts[&1];
+        assert_eq!(account.available, money(10.0));
+        assert_eq!(account.held, money(0.0));
+        assert!(!account.frozen);
+        assert!(account.disputed_transactions.is_empty());
+    }
+
+    #[test]
+    fn format_version_marker_is_written_for_csv_and_json() {
+        let mut accounts = BTreeMap::new();
+        accounts.insert(1, Account {
+            disputed_transactions: DisputedTransactions::default(),
+            frozen: false,
+            held: money(0.0),
+            available: money(3.0),
+            ..Account::new()
+        });
+
+        let mut csv = vec![];
+        write_format_version(OutputFormat::Csv, &mut csv).unwrap();
+        write_accounts(&accounts, &WriteOptions::default(), &mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "# format-version: 1\nclient,available,held,total,locked\n1,3,0,3,false\n"
+        );
+
+        let mut ndjson = vec![];
+        write_format_version(OutputFormat::Ndjson, &mut ndjson).unwrap();
+        write_accounts_ndjson(&accounts, false, None, &mut ndjson).unwrap();
+        let output = String::from_utf8(ndjson).unwrap();
+        let envelope: serde_json::Value = serde_json::from_str(output.lines().next().unwrap()).unwrap();
+        assert_eq!(envelope["format_version"], "1");
+        assert_eq!(output.lines().count(), 2);
+
+        let mut json = vec![];
+        write_accounts_json_versioned(&accounts, false, None, &mut json).unwrap();
+        assert_eq!(
+            String::from_utf8(json).unwrap(),
+            "{\"format_version\":\"1\",\
+             \"accounts\":[{\"client\":1,\"available\":3.0,\"held\":0.0,\"total\":3.0,\"locked\":false}]}\n"
+        );
+    }
+
+    #[test]
+    fn replayed_accounts_match_incremental_state() {
+        let transactions = read_transactions_skipping_errors(
+            "type, client, tx, amount\n\
+             deposit, 1, 1, 10.0\n\
+             deposit, 2, 2, 20.0\n\
+             dispute, 1, 1,\n\
+             withdrawal, 2, 3, 5.0\n\
+             dispute, 2, 3,\n\
+             resolve, 1, 1,\n\
+             dispute, 1, 1,\n\
+             deposit, 3, 4, 7.5\n\
+             dispute, 3, 4,\n\
+             chargeback, 3, 4,\n\
+             dispute, 2, 2,\n\
+             resolve, 2, 3,\n"
+                .as_bytes(),
+        )
+        .unwrap()
+        .0;
+        let config = EngineConfig { verify_replay: true, ..EngineConfig::default() };
+        let mut engine = TransactionEngine::new(config);
+        engine.apply_all(transactions, None);
+        assert!(engine.verify_replay().is_ok());
+
+        engine.accounts.get_mut(&2).unwrap().available += money(1.0);
+        let mismatch = engine.verify_replay().unwrap_err();
+        assert_eq!(mismatch.client, 2);
+    }
+
+    #[test]
+    fn corrupt_client_is_quarantined_without_affecting_others() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 10.0\n\
+                    deposit, 2, 2, 20.0\n\
+                    deposit, 2, 3,\n\
+                    withdrawal, 1, 4, 4.0\n\
+                    deposit, 2, 5, 1.0\n\
+                    deposit, 3, 6, 3.0\n";
+        let mut rdr = csv::ReaderBuilder::new().trim(Trim::All).from_reader(data.as_bytes());
+        let transactions: Vec<Transaction> = rdr.deserialize().collect::<Result<_, _>>().unwrap();
+        let config = EngineConfig { isolate_clients: true, ..EngineConfig::default() };
+        let mut engine = TransactionEngine::new(config);
+        engine.apply_all(transactions, None);
+
+        assert_eq!(engine.quarantined_clients.get(&2), Some(&3));
+        let accounts = engine.into_accounts();
+        let mut clients: Vec<u16> = accounts.keys().copied().collect();
+        clients.sort_unstable();
+        assert_eq!(clients, vec![1, 3]);
+        assert_eq!(accounts[&1].available, money(6.0));
+        assert_eq!(accounts[&3].available, money(3.0));
+    }
+
+    #[test]
+    fn accounts_are_written_to_the_shard_matching_their_client() {
+        let mut accounts = BTreeMap::new();
+        for client in 1..=5u16 {
+            accounts.insert(client, Account {
+                disputed_transactions: DisputedTransactions::default(),
+                frozen: false,
+                held: money(0.0),
+                available: money(f64::from(client)),
+                ..Account::new()
+            });
+        }
+        let directory = std::env::temp_dir().join(format!("transactions-shards-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        write_shards(accounts, NonZeroUsize::new(2).unwrap(), &WriteOptions::default(), &directory).unwrap();
+
+        let read_shard = |index: usize| -> Vec<String> {
+            let contents = std::fs::read_to_string(directory.join(format!("shard-{}.csv", index))).unwrap();
+            let mut rows: Vec<String> = contents.lines().skip(1).map(str::to_string).collect();
+            rows.sort();
+            rows
+        };
+        let (shard_0, shard_1) = (read_shard(0), read_shard(1));
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(shard_0, vec!["2,2,0,2,false", "4,4,0,4,false"]);
+        assert_eq!(shard_1, vec!["1,1,0,1,false", "3,3,0,3,false", "5,5,0,5,false"]);
+    }
+
+    #[test]
+    fn output_amounts_are_rounded_to_four_places() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 1.00005\n\
+                    deposit, 1, 2, 1.00005\n\
+                    dispute, 1, 2,\n\
+                    deposit, 2, 3, 0.123449\n";
+        let mut rdr = csv::ReaderBuilder::new().trim(Trim::All).from_reader(data.as_bytes());
+        let transactions: Vec<Transaction> = rdr.deserialize().collect::<Result<_, _>>().unwrap();
+        let accounts = process_transactions(transactions);
+
+        let mut output = vec![];
+        write_accounts(&accounts, &WriteOptions::default(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let mut rows: Vec<&str> = output.lines().skip(1).collect();
+        rows.sort_unstable();
+        // The total is the sum of the rounded components, not a separately rounded 2.0001.
+        assert_eq!(rows, vec!["1,1.0001,1.0001,2.0002,false", "2,0.1234,0,0.1234,false"]);
+    }
+
+    #[test]
+    fn deposit_without_amount_is_skipped() {
+        let t1 = Transaction::new(TransactionType::Deposit, 1, 1, amount(10.0));
+        let t2 = Transaction::new(TransactionType::Deposit, 1, 2, None);
+        let t3 = Transaction::new(TransactionType::Dispute, 1, 2, None);
+        let accounts = process_transactions(vec![t1, t2, t3]);
+        let account = accounts.get(&1).unwrap();
+        assert_eq!(account.available, money(10.0));
+        assert_eq!(account.held, money(0.0));
+    }
+
+    #[test]
+    fn streaming_matches_processing_a_collected_vec() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 10.0\n\
+                    deposit, 2, 2, 5.0\n\
+                    withdrawal, 1, 3, 2.5\n\
+                    dispute, 2, 2,\n\
+                    deposit, 1, 4, 1.0\n";
+        let collected = stream_csv(data.as_bytes()).collect::<Result<Vec<Transaction>, _>>().unwrap();
+        let streamed = process_transactions_streaming(data.as_bytes()).unwrap();
+        assert_eq!(streamed, process_transactions(collected));
+        assert_eq!(streamed[&1].available, money(8.5));
+        assert_eq!(streamed[&2].held, money(5.0));
+    }
+
+    #[test]
+    fn streaming_stops_at_the_first_bad_row() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 10.0\n\
+                    deposit, one, 2, 5.0\n\
+                    deposit, 1, 3, 1.0\n";
+        let mut engine = TransactionEngine::new(EngineConfig::default());
+        assert!(engine.apply_stream(stream_csv(data.as_bytes()), None).is_err());
+        assert_eq!(engine.accounts[&1].available, money(10.0));
+    }
+
+    #[test]
+    fn processed_transactions_keep_only_what_disputes_need() {
+        assert!(std::mem::size_of::<ProcessedTransaction>() * 2 <= std::mem::size_of::<Transaction>());
+
+        let mut engine = TransactionEngine::new(EngineConfig::default());
+        engine.apply(Transaction { seq: Some(3), ..Transaction::new(TransactionType::Deposit, 1, 7, amount(2.5)) });
+        assert_eq!(engine.processed_transactions[&(1, 7)], ProcessedTransaction {
+            transaction_type: TransactionType::Deposit,
+            client: 1,
+            amount: money(2.5),
+        });
+    }
+
+    #[test]
+    fn disputes_hold_funds_according_to_the_original_type() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 20.0\n\
+                    withdrawal, 1, 2, 5.0\n\
+                    dispute, 1, 1,\n\
+                    deposit, 2, 3, 20.0\n\
+                    withdrawal, 2, 4, 5.0\n\
+                    dispute, 2, 4,\n\
+                    withdrawal, 3, 5, 5.0\n\
+                    dispute, 3, 5,\n";
+        let accounts = process_transactions_streaming(data.as_bytes()).unwrap();
+
+        // The deposit's funds move out of available into held.
+        assert_eq!(accounts[&1].available, money(-5.0));
+        assert_eq!(accounts[&1].held, money(20.0));
+        assert_eq!(accounts[&1].total(), money(15.0));
+
+        // The withdrawn funds already left available, so they are held on top of the balance.
+        assert_eq!(accounts[&2].available, money(15.0));
+        assert_eq!(accounts[&2].held, money(5.0));
+        assert_eq!(accounts[&2].total(), money(20.0));
+
+        // A withdrawal rejected for insufficient funds moved nothing and can't be disputed.
+        assert_eq!(accounts[&3].held, money(0.0));
+        assert!(accounts[&3].disputed_transactions.is_empty());
+    }
+
+    #[test]
+    fn disputing_twice_holds_funds_once() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 20.0\n\
+                    dispute, 1, 1,\n\
+                    dispute, 1, 1,\n";
+        let accounts = process_transactions_streaming(data.as_bytes()).unwrap();
+        assert_eq!(accounts[&1].available, money(0.0));
+        assert_eq!(accounts[&1].held, money(20.0));
+        assert_eq!(accounts[&1].disputed_transactions, vec![1]);
+    }
+
+    #[test]
+    fn clients_cannot_dispute_each_others_transactions() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 20.0\n\
+                    deposit, 2, 2, 5.0\n\
+                    dispute, 2, 1,\n\
+                    resolve, 2, 1,\n\
+                    chargeback, 2, 1,\n";
+        let accounts = process_transactions_streaming(data.as_bytes()).unwrap();
+        assert_eq!(accounts[&1].available, money(20.0));
+        assert_eq!(accounts[&1].held, money(0.0));
+        assert!(!accounts[&1].frozen);
+        assert_eq!(accounts[&2].available, money(5.0));
+        assert_eq!(accounts[&2].held, money(0.0));
+        assert!(!accounts[&2].frozen);
+    }
+
+    #[test]
+    fn output_is_sorted_by_client() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 30, 1, 3.0\n\
+                    deposit, 1, 2, 1.0\n\
+                    deposit, 7, 3, 2.0\n";
+        let accounts = process_transactions_streaming(data.as_bytes()).unwrap();
+        let mut output = vec![];
+        write_accounts(&accounts, &WriteOptions::default(), &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n\
+             1,1,0,1,false\n\
+             7,2,0,2,false\n\
+             30,3,0,3,false\n"
+        );
+    }
+
+    #[test]
+    fn accounts_iterate_in_client_order() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 300, 1, 3.0\n\
+                    deposit, 2, 2, 1.0\n\
+                    withdrawal, 65535, 3, 2.0\n\
+                    deposit, 40, 4, 2.0\n";
+        let transactions = stream_csv(data.as_bytes()).collect::<Result<Vec<_>, _>>().unwrap();
+        let accounts = process_transactions(transactions);
+        assert_eq!(accounts.keys().copied().collect::<Vec<_>>(), vec![2, 40, 300, 65535]);
+    }
+
+    #[test]
+    fn disputing_partly_withdrawn_deposit_holds_the_full_amount() {
+        let transactions = vec![
+            Transaction::new(TransactionType::Deposit, 1, 1, amount(100.0)),
+            Transaction::new(TransactionType::Withdrawal, 1, 2, amount(80.0)),
+            Transaction::new(TransactionType::Dispute, 1, 1, None),
+        ];
+        let accounts = process_transactions(transactions);
+
+        let account = &accounts[&1];
+        assert_eq!(account.available, money(-80.0));
+        assert_eq!(account.held, money(100.0));
+        assert_eq!(account.total(), money(20.0));
+        assert!(!account.frozen);
+    }
+
+    #[test]
+    fn parallel_processing_matches_sequential() {
+        let mut rng = XorShift(7);
+        let mut transactions = vec![];
+        for tx in 1..=5000u32 {
+            let client = rng.next_below(50) as u16;
+            let transaction_type = match rng.next_below(10) {
+                0..=4 => TransactionType::Deposit,
+                5..=6 => TransactionType::Withdrawal,
+                7 => TransactionType::Dispute,
+                8 => TransactionType::Resolve,
+                _ => TransactionType::Chargeback,
+            };
+            let (tx, amount) = if transaction_type.is_disputable() {
+                // Now and then reuse an earlier id, which has to be skipped wherever it lands.
+                let tx = if rng.next_below(50) == 0 { rng.next_below(tx as usize) as u32 + 1 } else { tx };
+                (tx, amount(rng.next_below(10_000) as f64 / 100.0 + 0.01))
+            } else {
+                // Point at another transaction, which may well belong to another client or not
+                // have arrived yet.
+                (rng.next_below(tx as usize + 20) as u32 + 1, None)
+            };
+            transactions.push(Transaction::new(transaction_type, client, tx, amount));
+        }
+
+        for (client_scoped_tx_ids, buffer_early_disputes) in [(false, false), (true, true), (false, true)] {
+            let config = EngineConfig {
+                client_scoped_tx_ids,
+                buffer_early_disputes,
+                record_events: true,
+                record_outcomes: true,
+                ..EngineConfig::default()
+            };
+            let mut sequential = TransactionEngine::new(config.clone());
+            assert!(sequential.apply_all(transactions.clone(), None));
+            let ledger = *sequential.ledger();
+            check_invariants(&sequential.accounts, &ledger).unwrap();
+
+            for threads in [1, 3, 8] {
+                let label = format!("{} threads with {:?}", threads, config);
+                // Start part-way through so existing state has to be split across the threads too.
+                let (before, after) = transactions.split_at(2500);
+                let mut parallel = TransactionEngine::new(config.clone());
+                assert!(parallel.apply_all(before.to_vec(), None));
+                assert!(parallel.apply_all_parallel(after.to_vec(), NonZeroUsize::new(threads).unwrap(), None));
+                assert_eq!(parallel.ledger(), &ledger, "{}", label);
+                assert_eq!(parallel.anomalies(), sequential.anomalies(), "{}", label);
+                // What was recorded comes out in input order, not thread by thread.
+                assert_eq!(parallel.events(), sequential.events(), "{}", label);
+                assert_eq!(parallel.outcomes(), sequential.outcomes(), "{}", label);
+                assert_eq!(parallel.accounts, sequential.accounts, "{}", label);
+            }
+        }
+    }
+
+    #[test]
+    fn duplicate_transaction_ids_keep_the_first() {
+        let transactions = vec![
+            Transaction::new(TransactionType::Deposit, 1, 7, amount(10.0)),
+            Transaction::new(TransactionType::Deposit, 1, 7, amount(5.0)),
+            Transaction::new(TransactionType::Dispute, 1, 7, None),
+        ];
+        let accounts = process_transactions(transactions);
+
+        let account = &accounts[&1];
+        assert_eq!(account.available, money(0.0));
+        assert_eq!(account.held, money(10.0));
+        assert_eq!(account.total(), money(10.0));
+        assert_eq!(account.disputed_transactions, vec![7]);
+    }
+
+    #[test]
+    fn frozen_account_ignores_further_disputes() {
+        let transaction = |transaction_type, tx, value: Option<f64>| Transaction::new(
+            transaction_type,
+            1,
+            tx,
+            value.and_then(amount),
+        );
+        let transactions = vec![
+            transaction(TransactionType::Deposit, 1, Some(10.0)),
+            transaction(TransactionType::Deposit, 2, Some(5.0)),
+            transaction(TransactionType::Deposit, 3, Some(2.0)),
+            transaction(TransactionType::Dispute, 1, None),
+            transaction(TransactionType::Dispute, 3, None),
+            transaction(TransactionType::Chargeback, 1, None),
+            // The account is frozen from here on.
+            transaction(TransactionType::Dispute, 2, None),
+            transaction(TransactionType::Resolve, 3, None),
+        ];
+        let accounts = process_transactions(transactions);
+
+        let account = &accounts[&1];
+        assert!(account.frozen);
+        assert_eq!(account.available, money(5.0));
+        assert_eq!(account.held, money(2.0));
+        assert_eq!(account.disputed_transactions, vec![3]);
+    }
+
+    #[test]
+    fn frozen_account_refuses_withdrawals() {
+        let transaction = |transaction_type, tx, value: Option<f64>| Transaction::new(
+            transaction_type,
+            1,
+            tx,
+            value.and_then(amount),
+        );
+        let accounts = process_transactions(vec![
+            transaction(TransactionType::Deposit, 1, Some(10.0)),
+            transaction(TransactionType::Deposit, 2, Some(5.0)),
+            transaction(TransactionType::Dispute, 1, None),
+            transaction(TransactionType::Chargeback, 1, None),
+            // Well within the remaining funds, but the account is frozen.
+            transaction(TransactionType::Withdrawal, 3, Some(2.0)),
+            // More than is left, which must not overdraw a frozen account either.
+            transaction(TransactionType::Withdrawal, 4, Some(8.0)),
+        ]);
+
+        let account = &accounts[&1];
+        assert!(account.frozen);
+        assert_eq!(account.available, money(5.0));
+        assert_eq!(account.total_withdrawn, money(0.0));
+    }
+
+    #[test]
+    fn dispute_then_resolve_restores_the_account() {
+        for (disputed_tx, disputed_type) in [(1, TransactionType::Deposit), (2, TransactionType::Withdrawal)] {
+            let mut engine = TransactionEngine::new(EngineConfig::default());
+            engine.apply(Transaction::new(TransactionType::Deposit, 1, 1, amount(20.0)));
+            engine.apply(Transaction::new(TransactionType::Withdrawal, 1, 2, amount(5.0)));
+            let before = engine.accounts[&1].clone();
+            for transaction_type in [TransactionType::Dispute, TransactionType::Resolve] {
+                engine.apply(Transaction::new(transaction_type, 1, disputed_tx, None));
+            }
+            assert_eq!(engine.accounts[&1], before, "{:?}", disputed_type);
+        }
+    }
+
+    #[test]
+    fn read_errors_name_the_offending_row() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 1.0\n\
+                    refund, 1, 2, 1.0\n";
+        match process_transactions_streaming(data.as_bytes()) {
+            Err(TransactionError::UnknownType { line, value }) => {
+                assert_eq!(line, 3);
+                assert_eq!(value, "refund");
+            }
+            other => panic!("expected an unknown type error, got {:?}", other),
+        }
+
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, one, 1.0\n";
+        match process_transactions_streaming(data.as_bytes()) {
+            Err(TransactionError::MalformedRow { line, .. }) => assert_eq!(line, 2),
+            other => panic!("expected a malformed row error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn transaction_types_are_read_case_insensitively() {
+        let data = "type, client, tx, amount\n\
+                    Deposit, 1, 1, 10\n\
+                    WITHDRAWAL, 1, 2, 4\n\
+                    deposit, 1, 3, 2\n\
+                    DisPute, 1, 3,\n";
+        let types: Vec<TransactionType> = stream_csv(data.as_bytes())
+            .map(|transaction| transaction.unwrap().transaction_type)
+            .collect();
+        assert_eq!(
+            types,
+            [TransactionType::Deposit, TransactionType::Withdrawal, TransactionType::Deposit, TransactionType::Dispute]
+        );
+        let errors = validate_transactions(data.as_bytes(), &EngineConfig::default(), ExcessPrecision::Round);
+        assert!(errors.unwrap().is_empty());
+
+        let ndjson = "{\"type\":\"Chargeback\",\"client\":1,\"tx\":3}\n";
+        let transaction = stream_ndjson(ndjson.as_bytes()).next().unwrap().unwrap();
+        assert_eq!(transaction.transaction_type, TransactionType::Chargeback);
+        // Output keeps the lowercase spelling.
+        assert_eq!(serde_json::to_string(&TransactionType::Chargeback).unwrap(), "\"chargeback\"");
+    }
+
+    #[test]
+    fn ndjson_input_matches_csv_input() {
+        let csv = "type, client, tx, amount\n\
+                   deposit, 1, 1, 10.5\n\
+                   withdrawal, 1, 2, 0.25\n\
+                   deposit, 2, 3, 3\n\
+                   dispute, 2, 3,\n";
+        let ndjson = r#"{"type":"deposit","client":1,"tx":1,"amount":10.5}
+{"type":"withdrawal","client":1,"tx":2,"amount":"0.25"}
+
+{"type":"deposit","client":2,"tx":3,"amount":3}
+{"type":"dispute","client":2,"tx":3}
+"#;
+        let precision = ExcessPrecision::Round;
+        let from_csv: Vec<Transaction> = stream_transactions(csv.as_bytes(), InputFormat::Csv, precision)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let from_ndjson: Vec<Transaction> = stream_transactions(ndjson.as_bytes(), InputFormat::Ndjson, precision)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(from_ndjson, from_csv);
+
+        let bad = "{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":1}\n{\"type\":\"refund\",\"client\":1,\"tx\":2}\n";
+        match stream_ndjson(bad.as_bytes()).collect::<Result<Vec<_>, _>>() {
+            Err(TransactionError::UnknownType { line, value }) => {
+                assert_eq!(line, 2);
+                assert_eq!(value, "refund");
+            }
+            other => panic!("expected an unknown type error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn input_format_follows_the_file_extension() {
+        assert_eq!(InputFormat::from_path(Path::new("feed.ndjson")), InputFormat::Ndjson);
+        assert_eq!(InputFormat::from_path(Path::new("feed.json")), InputFormat::Ndjson);
+        assert_eq!(InputFormat::from_path(Path::new("feed.csv")), InputFormat::Csv);
+        assert_eq!(InputFormat::from_path(Path::new("feed")), InputFormat::Csv);
+    }
+
+    #[test]
+    fn client_ids_beyond_u16_are_reported_as_out_of_range() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 1.0\n\
+                    deposit, 70000, 2, 1.0\n";
+        let error = stream_csv(data.as_bytes()).collect::<Result<Vec<_>, _>>().unwrap_err();
+        assert!(matches!(error, TransactionError::ClientOutOfRange { line: 3, client: 70000 }));
+        assert_eq!(error.to_string(), "client id 70000 out of range on line 3");
+
+        let data = "{\"type\":\"deposit\",\"client\":70000,\"tx\":1,\"amount\":1}\n";
+        let error = stream_ndjson(data.as_bytes()).collect::<Result<Vec<_>, _>>().unwrap_err();
+        assert!(matches!(error, TransactionError::ClientOutOfRange { line: 1, client: 70000 }));
+    }
+
+    #[test]
+    fn validation_counts_duplicate_ids_alongside_bad_rows() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 10.0\n\
+                    deposit, 2, 1, 5.0\n\
+                    withdrawal, 1, 2, x\n\
+                    withdrawal, 1, 3, -1.0\n\
+                    dispute, 1, 1,\n\
+                    resolve, 1, 1,\n";
+        let errors = validate_transactions(data.as_bytes(), &EngineConfig::default(), ExcessPrecision::Round).unwrap();
+        assert_eq!(errors.get(&RowErrorKind::DuplicateTransaction), Some(&1));
+        assert_eq!(errors.get(&RowErrorKind::Parse), Some(&1));
+        assert_eq!(errors.get(&RowErrorKind::InvalidAmount), Some(&1));
+        assert_eq!(errors.values().sum::<usize>(), 3);
+
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 10.0\n";
+        let errors = validate_transactions(data.as_bytes(), &EngineConfig::default(), ExcessPrecision::Round);
+        assert!(errors.unwrap().is_empty());
+    }
+
+    #[test]
+    fn validation_follows_the_precision_policy() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 1.123456\n\
+                    deposit, 1, 2, 2.5\n";
+        let config = EngineConfig::default();
+        assert!(validate_transactions(data.as_bytes(), &config, ExcessPrecision::Round).unwrap().is_empty());
+        assert!(validate_transactions(data.as_bytes(), &config, ExcessPrecision::Truncate).unwrap().is_empty());
+        let errors = validate_transactions(data.as_bytes(), &config, ExcessPrecision::Reject).unwrap();
+        assert_eq!(errors.get(&RowErrorKind::ExcessPrecision), Some(&1));
+        assert_eq!(errors.values().sum::<usize>(), 1);
+    }
+
+    #[test]
+    fn validation_scopes_ids_like_the_engine() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 10.0\n\
+                    deposit, 2, 1, 5.0\n\
+                    deposit, 2, 1, 5.0\n";
+        let errors = validate_transactions(data.as_bytes(), &EngineConfig::default(), ExcessPrecision::Round).unwrap();
+        assert_eq!(errors.get(&RowErrorKind::DuplicateTransaction), Some(&2));
+
+        let config = EngineConfig { client_scoped_tx_ids: true, ..EngineConfig::default() };
+        let errors = validate_transactions(data.as_bytes(), &config, ExcessPrecision::Round).unwrap();
+        assert_eq!(errors.get(&RowErrorKind::DuplicateTransaction), Some(&1));
+    }
+
+    #[test]
+    fn deposit_and_withdrawal_totals_are_tracked() {
+        let transaction = |transaction_type, tx, value: f64| Transaction::new(transaction_type, 1, tx, amount(value));
+        let accounts = process_transactions(vec![
+            transaction(TransactionType::Deposit, 1, 10.0),
+            transaction(TransactionType::Deposit, 2, 5.5),
+            transaction(TransactionType::Withdrawal, 3, 3.0),
+            // Refused for insufficient funds, so it doesn't count.
+            transaction(TransactionType::Withdrawal, 4, 100.0),
+            Transaction::new(TransactionType::Dispute, 1, 2, None),
+        ]);
+        let account = &accounts[&1];
+        assert_eq!(account.total_deposited(), money(15.5));
+        assert_eq!(account.total_withdrawn(), money(3.0));
+
+        let mut output = vec![];
+        write_accounts(&accounts, &WriteOptions { verbose: true, ..WriteOptions::default() }, &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked,total_deposited,total_withdrawn\n1,7,5.5,12.5,false,15.5,3\n"
+        );
+    }
+
+    #[test]
+    fn client_scoped_tx_ids_let_clients_share_an_id() {
+        let transactions = || {
+            vec![
+                Transaction::new(TransactionType::Deposit, 1, 1, amount(10.0)),
+                Transaction::new(TransactionType::Deposit, 2, 1, amount(4.0)),
+                Transaction::new(TransactionType::Dispute, 2, 1, None),
+            ]
+        };
+
+        let config = EngineConfig {
+            client_scoped_tx_ids: true,
+            ..EngineConfig::default()
+        };
+        let accounts = process_transactions_from(BTreeMap::new(), transactions(), &config);
+        assert_eq!(accounts[&1].available, money(10.0));
+        assert_eq!(accounts[&2].available, money(0.0));
+        assert_eq!(accounts[&2].held, money(4.0));
+
+        // With global ids the second deposit is a duplicate, leaving nothing to dispute.
+        let accounts = process_transactions_from(BTreeMap::new(), transactions(), &EngineConfig::default());
+        assert_eq!(accounts[&1].available, money(10.0));
+        assert_eq!(accounts[&2].total(), money(0.0));
+    }
+
+    #[test]
+    fn deposit_that_would_overflow_is_refused() {
+        let near_max = PositiveAmount::new(Money(i64::MAX / 2 + 1));
+        let transactions = (1..=2)
+            .map(|tx| Transaction::new(TransactionType::Deposit, 1, tx, near_max))
+            .collect();
+        let accounts = process_transactions(transactions);
+
+        let account = &accounts[&1];
+        assert_eq!(account.available, Money(i64::MAX / 2 + 1));
+        assert!(account.available > Money::ZERO);
+        assert_eq!(account.total_deposited, Money(i64::MAX / 2 + 1));
+    }
+
+    #[test]
+    fn disputes_that_would_overflow_the_total_are_refused() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 922337203685477\n\
+                    withdrawal, 1, 2, 1\n\
+                    deposit, 1, 3, 1\n\
+                    dispute, 1, 2,\n";
+        let config = EngineConfig { record_outcomes: true, ..EngineConfig::default() };
+        let mut engine = TransactionEngine::new(config);
+        engine.apply_stream(stream_csv(data.as_bytes()), None).unwrap();
+        // Holding the withdrawn amount on top of a full balance would wrap the total around.
+        assert_eq!(engine.outcomes()[3].outcome, Outcome::Ignored(SkipReason::AboveMaxBalance));
+        let account = &engine.into_accounts()[&1];
+        assert_eq!(account.held, money(0.0));
+        assert_eq!(account.total().to_string(), "922337203685477");
+
+        // With the withdrawal under dispute, a deposit that would overflow the total is refused too.
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 922337203685477\n\
+                    withdrawal, 1, 2, 1\n\
+                    dispute, 1, 2,\n\
+                    deposit, 1, 3, 1\n";
+        let account = &process_transactions_streaming(data.as_bytes()).unwrap()[&1];
+        assert_eq!(account.available.to_string(), "922337203685476");
+        assert_eq!(account.total().to_string(), "922337203685477");
+    }
+
+    #[test]
+    fn json_output_is_a_single_array() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 2, 1, 2.5\n\
+                    deposit, 1, 2, 10.12345\n\
+                    dispute, 1, 2,\n";
+        let accounts = process_transactions_streaming(data.as_bytes()).unwrap();
+
+        let mut output = Vec::new();
+        write_accounts_json(&accounts, false, None, &mut output).unwrap();
+        let output: serde_json::Value = serde_json::from_slice(&output).unwrap();
+        assert_eq!(output, serde_json::json!([
+            {"client": 1, "available": 0.0, "held": 10.1235, "total": 10.1235, "locked": false},
+            {"client": 2, "available": 2.5, "held": 0.0, "total": 2.5, "locked": false},
+        ]));
+    }
+
+    #[test]
+    fn accounts_can_be_listed_in_first_seen_order() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 5, 1, 1.0\n\
+                    deposit, 1, 2, 2.0\n\
+                    deposit, 5, 3, 3.0\n\
+                    deposit, 3, 4, 4.0\n";
+        let mut engine = TransactionEngine::new(EngineConfig::default());
+        engine.apply_stream(stream_csv(data.as_bytes()), None).unwrap();
+        let (accounts, order) = engine.into_accounts_with_order();
+        assert_eq!(order, vec![5, 1, 3]);
+
+        let mut output = Vec::new();
+        let options = WriteOptions { order: Some(&order), ..WriteOptions::default() };
+        write_accounts(&accounts, &options, &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n5,4,0,4,false\n1,2,0,2,false\n3,4,0,4,false\n"
+        );
+
+        let mut output = Vec::new();
+        write_accounts(&accounts, &WriteOptions::default(), &mut output).unwrap();
+        assert!(String::from_utf8(output).unwrap().starts_with("client,available,held,total,locked\n1,"));
+    }
+
+    #[test]
+    fn csv_output_header_can_be_left_out() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 1.5\n\
+                    deposit, 2, 2, 2.0\n";
+        let accounts = process_transactions_streaming(data.as_bytes()).unwrap();
+        let mut output = Vec::new();
+        write_accounts(&accounts, &WriteOptions::default(), &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n1,1.5,0,1.5,false\n2,2,0,2,false\n"
+        );
+
+        let options = WriteOptions { header: false, ..WriteOptions::default() };
+        let mut output = Vec::new();
+        write_accounts(&accounts, &options, &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "1,1.5,0,1.5,false\n2,2,0,2,false\n");
+
+        // With no accounts there is nothing at all to write.
+        let mut output = Vec::new();
+        write_accounts(&BTreeMap::new(), &options, &mut output).unwrap();
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn parallel_runs_know_which_ids_were_seen_before() {
+        let before = "type, client, tx, amount\nwithdrawal, 1, 1, 5.0\n";
+        let batch = "type, client, tx, amount\n\
+                     dispute, 1, 1,\n\
+                     dispute, 2, 5,\n\
+                     deposit, 2, 5, 3.0\n\
+                     deposit, 1, 1, 1.0\n";
+        let config = EngineConfig {
+            client_scoped_tx_ids: true,
+            buffer_early_disputes: true,
+            record_outcomes: true,
+            ..EngineConfig::default()
+        };
+        let mut engine = TransactionEngine::new(config);
+        engine.apply_stream(stream_csv(before.as_bytes()), None).unwrap();
+        let batch = read_csv(batch.as_bytes()).unwrap();
+        assert!(engine.apply_all_parallel(batch, NonZeroUsize::new(2).unwrap(), None));
+        let outcomes: Vec<(u16, Outcome)> =
+            engine.outcomes().iter().map(|event| (event.client, event.outcome)).collect();
+        assert_eq!(outcomes, vec![
+            (1, Outcome::Ignored(SkipReason::InsufficientFunds)),
+            // The refused withdrawal's id was seen, so this dispute isn't early, just unfounded.
+            (1, Outcome::Ignored(SkipReason::NotFound)),
+            (2, Outcome::Deferred),
+            (2, Outcome::Applied),
+            (2, Outcome::Applied),
+            (1, Outcome::Ignored(SkipReason::Duplicate)),
+        ]);
+        assert_eq!(engine.anomalies(), 3);
+        assert_eq!(engine.into_accounts()[&2].held, money(3.0));
+    }
+
+    #[test]
+    fn parallel_runs_keep_first_seen_order() {
+        let transactions: Vec<Transaction> = [(7, 1), (2, 2), (7, 3), (4, 4), (2, 5)]
+            .iter()
+            .map(|&(client, tx)| Transaction::new(TransactionType::Deposit, client, tx, amount(1.0)))
+            .collect();
+        let mut engine = TransactionEngine::new(EngineConfig::default());
+        assert!(engine.apply_all_parallel(transactions, NonZeroUsize::new(3).unwrap(), None));
+        let (_, order) = engine.into_accounts_with_order();
+        assert_eq!(order, vec![7, 2, 4]);
+    }
+
+    #[test]
+    fn early_disputes_are_applied_once_their_transaction_arrives() {
+        let data = "type, client, tx, amount\n\
+                    dispute, 1, 1,\n\
+                    deposit, 1, 1, 5.0\n\
+                    dispute, 1, 9,\n\
+                    withdrawal, 1, 9, 50.0\n";
+        let config = EngineConfig { buffer_early_disputes: true, ..EngineConfig::default() };
+        let mut engine = TransactionEngine::new(config);
+        engine.apply_stream(stream_csv(data.as_bytes()), None).unwrap();
+        assert!(engine.pending_disputes.is_empty());
+        let accounts = engine.into_accounts();
+        assert_eq!(accounts[&1].available, money(0.0));
+        assert_eq!(accounts[&1].held, money(5.0));
+
+        // Without buffering the early dispute is dropped as before.
+        let accounts = process_transactions_streaming(data.as_bytes()).unwrap();
+        assert_eq!(accounts[&1].available, money(5.0));
+        assert_eq!(accounts[&1].held, money(0.0));
+    }
+
+    #[test]
+    fn disputes_for_seen_transactions_are_not_buffered() {
+        let data = "type, client, tx, amount\n\
+                    withdrawal, 1, 1, 5.0\n\
+                    dispute, 1, 1,\n\
+                    dispute, 1, 2,\n";
+        let config = EngineConfig { buffer_early_disputes: true, ..EngineConfig::default() };
+        let mut engine = TransactionEngine::new(config);
+        engine.apply_stream(stream_csv(data.as_bytes()), None).unwrap();
+        assert_eq!(engine.pending_disputes.keys().collect::<Vec<_>>(), vec![&(1, 2)]);
+    }
+
+    #[test]
+    fn excess_precision_can_be_rejected() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 1.5\n\
+                    deposit, 1, 2, 1.123456\n";
+        let results: Vec<_> =
+            stream_transactions(data.as_bytes(), InputFormat::Csv, ExcessPrecision::Reject).collect();
+        assert!(results[0].is_ok());
+        match &results[1] {
+            Err(TransactionError::MalformedRow { line, reason }) => {
+                assert_eq!(*line, 3);
+                assert_eq!(reason, "amount 1.123456 has more than 4 decimal places");
+            }
+            other => panic!("expected a malformed row, got {:?}", other),
+        }
+
+        let data = "{\"type\": \"deposit\", \"client\": 1, \"tx\": 1, \"amount\": 1.123456}\n";
+        let mut results = stream_transactions(data.as_bytes(), InputFormat::Ndjson, ExcessPrecision::Reject);
+        assert!(matches!(results.next(), Some(Err(TransactionError::MalformedRow { line: 1, .. }))));
+    }
+
+    #[test]
+    fn excess_precision_can_be_truncated() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 1.123456\n\
+                    deposit, 1, 2, 2.00009\n";
+        let amounts: Vec<Money> = stream_transactions(data.as_bytes(), InputFormat::Csv, ExcessPrecision::Truncate)
+            .map(|transaction| transaction.unwrap().amount.unwrap().value())
+            .collect();
+        assert_eq!(amounts, vec![money(1.1234), money(2.0)]);
+
+        let mut truncations = vec![];
+        let on_truncate = |truncation: Truncation| truncations.push(truncation.to_string());
+        let (format, precision) = (InputFormat::Csv, ExcessPrecision::Truncate);
+        let zero_amounts = ZeroAmounts::Skip;
+        stream_transactions_noting_truncations(data.as_bytes(), format, precision, zero_amounts, on_truncate)
+            .for_each(drop);
+        assert_eq!(
+            truncations,
+            ["amount 1.123456 on line 2 truncated to 1.1234", "amount 2.00009 on line 3 truncated to 2.0000"]
+        );
+
+        // Rounding stays the default.
+        let amounts: Vec<Money> = stream_csv(data.as_bytes())
+            .map(|transaction| transaction.unwrap().amount.unwrap().value())
+            .collect();
+        assert_eq!(amounts, vec![money(1.1235), money(2.0001)]);
+    }
+
+    #[test]
+    fn gzipped_input_reads_like_plain_text() {
+        assert_eq!(read_csv_file("transaction.csv.gz").unwrap(), read_csv_file("transaction.csv").unwrap());
+        assert_eq!(InputFormat::from_path(Path::new("feed.ndjson.gz")), InputFormat::Ndjson);
+        assert_eq!(InputFormat::from_path(Path::new("feed.csv.gz")), InputFormat::Csv);
+    }
+
+    #[test]
+    fn balances_never_print_in_scientific_notation() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 10000000\n\
+                    deposit, 1, 2, 90000000000.5\n\
+                    deposit, 2, 3, 0.0001\n\
+                    dispute, 2, 3,\n";
+        let accounts = process_transactions_streaming(data.as_bytes()).unwrap();
+
+        let mut output = Vec::new();
+        write_accounts(&accounts, &WriteOptions::default(), &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n\
+             1,90010000000.5,0,90010000000.5,false\n\
+             2,0,0.0001,0.0001,false\n"
+        );
+
+        let mut output = Vec::new();
+        write_accounts_json(&accounts, false, None, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("\"total\":90010000000.5"), "{}", output);
+        assert!(output.contains("\"held\":0.0001"), "{}", output);
+
+        let mut output = Vec::new();
+        write_accounts_prometheus(&accounts, false, None, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("account_total{client=\"1\"} 90010000000.5\n"), "{}", output);
+        assert!(output.contains("account_held{client=\"2\"} 0.0001\n"), "{}", output);
+    }
+
+    #[test]
+    fn accounts_with_negative_available_are_reported() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 10.0\n\
+                    deposit, 2, 2, 10.0\n\
+                    withdrawal, 2, 3, 8.0\n\
+                    dispute, 2, 2,\n\
+                    deposit, 3, 4, 1.0\n\
+                    withdrawal, 3, 5, 1.0\n\
+                    dispute, 3, 4,\n\
+                    dispute, 1, 1,\n";
+        let accounts = process_transactions_streaming(data.as_bytes()).unwrap();
+        assert_eq!(accounts_with_negative_available(&accounts), vec![2, 3]);
+
+        let mut warnings = Vec::new();
+        write_negative_available_warnings(&accounts, &mut warnings).unwrap();
+        assert_eq!(
+            String::from_utf8(warnings).unwrap(),
+            "warning: client 2 has negative available funds of -8\n\
+             warning: client 3 has negative available funds of -1\n"
+        );
+    }
+
+    #[test]
+    fn accounts_are_split_by_frozen_status() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 3, 1, 10.0\n\
+                    deposit, 1, 2, 5.0\n\
+                    deposit, 4, 3, 2.0\n\
+                    deposit, 2, 4, 1.0\n\
+                    dispute, 4, 3,\n\
+                    chargeback, 4, 3,\n\
+                    dispute, 1, 2,\n\
+                    chargeback, 1, 2,\n";
+        let accounts = process_transactions_streaming(data.as_bytes()).unwrap();
+        let clients =
+            |selected: Vec<(u16, &Account)>| selected.into_iter().map(|(client, _)| client).collect::<Vec<_>>();
+        assert_eq!(clients(frozen_accounts(&accounts)), vec![1, 4]);
+        assert_eq!(clients(active_accounts(&accounts)), vec![2, 3]);
+        assert!(frozen_accounts(&accounts).iter().all(|(_, account)| account.is_frozen()));
+        assert_eq!(active_accounts(&accounts)[1].1.available(), money(10.0));
+    }
+
+    #[test]
+    fn aggregate_sums_every_account() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 10.0\n\
+                    withdrawal, 1, 2, 2.5\n\
+                    deposit, 2, 3, 4.0\n\
+                    dispute, 2, 3,\n\
+                    deposit, 3, 4, 6.0\n\
+                    deposit, 3, 5, 1.0\n\
+                    dispute, 3, 5,\n\
+                    chargeback, 3, 5,\n";
+        let accounts = process_transactions_streaming(data.as_bytes()).unwrap();
+        assert_eq!(
+            aggregate(&accounts),
+            Aggregate { available: money(13.5), held: money(4.0), total: money(17.5), frozen_accounts: 1 }
+        );
+        assert_eq!(aggregate::<Money>(&BTreeMap::new()), Aggregate::default());
+    }
+
+    #[test]
+    fn settlements_without_an_open_dispute_are_counted() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 10.0\n\
+                    deposit, 1, 2, 5.0\n\
+                    resolve, 1, 1,\n\
+                    chargeback, 1, 2,\n\
+                    resolve, 1, 99,\n\
+                    dispute, 1, 1,\n\
+                    resolve, 1, 1,\n\
+                    resolve, 1, 1,\n";
+        let mut engine = TransactionEngine::new(EngineConfig::default());
+        engine.apply_stream(stream_csv(data.as_bytes()), None).unwrap();
+        assert_eq!(engine.ignored_resolves(), 3);
+        assert_eq!(engine.ignored_chargebacks(), 1);
+        assert_eq!(engine.accounts[&1].available, money(15.0));
+        assert!(!engine.accounts[&1].frozen);
+    }
+
+    #[test]
+    fn transactions_that_change_nothing_are_counted_as_anomalies() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 10.0\n\
+                    withdrawal, 1, 2, 4.0\n\
+                    withdrawal, 1, 3, 50.0\n\
+                    deposit, 1, 1, 10.0\n\
+                    dispute, 1, 99,\n\
+                    deposit, 1, 4,\n\
+                    resolve, 1, 2,\n";
+        let mut engine = TransactionEngine::new(EngineConfig::default());
+        engine.apply_stream(stream_csv(data.as_bytes()), None).unwrap();
+        // The over-withdrawal, the duplicate, the unknown dispute, the missing amount and the resolve.
+        assert_eq!(engine.anomalies(), 5);
+        assert_eq!(engine.accounts[&1].available, money(6.0));
+
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 10.0\n\
+                    dispute, 1, 1,\n\
+                    resolve, 1, 1,\n";
+        let mut engine = TransactionEngine::new(EngineConfig::default());
+        engine.apply_stream(stream_csv(data.as_bytes()), None).unwrap();
+        assert_eq!(engine.anomalies(), 0);
+
+        // A dispute waiting for its transaction is only an anomaly until the transaction arrives.
+        let config = EngineConfig { buffer_early_disputes: true, ..EngineConfig::default() };
+        let mut engine = TransactionEngine::new(config);
+        let dispute = "type, client, tx, amount\ndispute, 1, 1,\n";
+        engine.apply_stream(stream_csv(dispute.as_bytes()), None).unwrap();
+        assert_eq!(engine.anomalies(), 1);
+        let deposit = "type, client, tx, amount\ndeposit, 1, 1, 5.0\n";
+        engine.apply_stream(stream_csv(deposit.as_bytes()), None).unwrap();
+        assert_eq!(engine.anomalies(), 0);
+    }
+
+    #[test]
+    fn headers_are_validated_by_name() {
+        let data = "type, client, tx\n\
+                    deposit, 1, 1\n";
+        let error = process_transactions_streaming(data.as_bytes()).unwrap_err();
+        assert_eq!(error.to_string(), "invalid header: missing column amount");
+
+        let data = "type, client, tx, amuont\n\
+                    deposit, 1, 1, 1.0\n";
+        let error = read_transactions_skipping_errors(data.as_bytes()).unwrap_err();
+        assert_eq!(error.to_string(), "invalid header: unknown column \"amuont\"");
+
+        // Column order doesn't matter, and `seq` is optional.
+        let data = "client, amount, seq, tx, type\n\
+                    1, 2.5, 7, 1, deposit\n";
+        let accounts = process_transactions_streaming(data.as_bytes()).unwrap();
+        assert_eq!(accounts[&1].available, money(2.5));
+        assert!(process_transactions_streaming("".as_bytes()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn chargebacks_reverse_the_original_transaction() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 20.0\n\
+                    deposit, 1, 2, 5.0\n\
+                    dispute, 1, 2,\n\
+                    chargeback, 1, 2,\n\
+                    deposit, 2, 3, 20.0\n\
+                    withdrawal, 2, 4, 5.0\n\
+                    dispute, 2, 4,\n\
+                    chargeback, 2, 4,\n";
+        let accounts = process_transactions_streaming(data.as_bytes()).unwrap();
+
+        // The charged-back deposit is taken back out: the account is left as if it never arrived.
+        assert_eq!(accounts[&1].available, money(20.0));
+        assert_eq!(accounts[&1].held, money(0.0));
+        assert_eq!(accounts[&1].total(), money(20.0));
+        assert!(accounts[&1].frozen);
+
+        // The charged-back withdrawal is returned: the account is left as if it never went out.
+        assert_eq!(accounts[&2].available, money(20.0));
+        assert_eq!(accounts[&2].held, money(0.0));
+        assert_eq!(accounts[&2].total(), money(20.0));
+        assert!(accounts[&2].frozen);
+    }
+
+    #[test]
+    fn money_rounds_half_away_from_zero() {
+        assert_eq!(money(1.2345).round_to(2), money(1.23));
+        assert_eq!(money(1.235).round_to(2), money(1.24));
+        assert_eq!(money(-1.235).round_to(2), money(-1.24));
+        assert_eq!(money(0.4999).round_to(0), money(0.0));
+        assert_eq!(money(1.2345).round_to(4), money(1.2345));
+
+        // A display precision rounds the same way and pads to exactly that many places.
+        assert_eq!(format!("{:.2}", money(10.0)), "10.00");
+        assert_eq!(format!("{:.2}", money(-1.235)), "-1.24");
+        assert_eq!(format!("{:.2}", money(-0.001)), "0.00");
+        assert_eq!(format!("{:.0}", money(1.5)), "2");
+        assert_eq!(format!("{:.4}", money(1.5)), "1.5000");
+
+        // A `Decimal` brought back to `Money` rounds exactly like parsing the same digits.
+        for digits in ["1.00025", "-1.00025", "1.00015", "0.00005"] {
+            let decimal: Decimal = digits.parse().unwrap();
+            assert_eq!(Money::try_from(decimal), digits.parse::<Money>(), "{}", digits);
+        }
+    }
+
+    #[test]
+    fn balances_can_be_written_at_two_or_four_places() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 10.0\n\
+                    deposit, 1, 2, 0.1251\n\
+                    dispute, 1, 2,\n\
+                    withdrawal, 1, 3, 0.0049\n";
+        let accounts = process_transactions_streaming(data.as_bytes()).unwrap();
+
+        let mut output = Vec::new();
+        write_accounts(&accounts, &WriteOptions::default(), &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n1,9.9951,0.1251,10.1202,false\n"
+        );
+
+        let mut output = Vec::new();
+        write_accounts(&accounts, &WriteOptions { decimals: Some(2), ..WriteOptions::default() }, &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n1,10.00,0.13,10.13,false\n"
+        );
+
+        let mut output = Vec::new();
+        write_accounts_json(&round_accounts(&accounts, 2), false, None, &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "[{\"client\":1,\"available\":10.0,\"held\":0.13,\"total\":10.13,\"locked\":false}]\n"
+        );
+    }
+
+    #[test]
+    fn every_bad_row_is_collected() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 10.0\n\
+                    deposit, one, 2, 5.0\n\
+                    withdrawal, 1, 3, 2.0\n\
+                    refund, 1, 4, 1.0\n\
+                    deposit, 2, 5, 3.0\n";
+        let (transactions, errors) = read_transactions_collecting_errors(data.as_bytes()).unwrap();
+        let ids: Vec<u32> = transactions.iter().map(|transaction| transaction.tx).collect();
+        assert_eq!(ids, vec![1, 3, 5]);
+        assert_eq!(errors.iter().map(|error| error.line).collect::<Vec<_>>(), vec![3, 5]);
+        assert_eq!(errors[1].to_string(), "invalid transaction on line 5: unknown type refund");
+
+        let data = "type, client, tx\n\
+                    deposit, 1, 1\n";
+        assert!(matches!(
+            read_transactions_collecting_errors(data.as_bytes()),
+            Err(TransactionError::InvalidHeader { .. })
+        ));
+    }
+
+    #[test]
+    fn ledger_reconciles_disputes_chargebacks_and_quarantine() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 10.0\n\
+                    withdrawal, 1, 2, 4.0\n\
+                    dispute, 1, 2,\n\
+                    deposit, 2, 3, 6.0\n\
+                    dispute, 2, 3,\n\
+                    chargeback, 2, 3,\n\
+                    deposit, 3, 4, 1.0\n\
+                    deposit, 3, 5,\n";
+        let transactions = stream_csv(data.as_bytes()).collect::<Result<Vec<_>, _>>().unwrap();
+        let config = EngineConfig { isolate_clients: true, ..EngineConfig::default() };
+        let mut engine = TransactionEngine::with_accounts(BTreeMap::new(), config);
+        engine.apply_all(transactions, None);
+        let ledger = *engine.ledger();
+        assert_eq!(ledger.withdrawals_disputed, money(4.0));
+        assert_eq!(ledger.deposits_charged_back, money(6.0));
+        assert_eq!(ledger.written_off, money(1.0));
+
+        // The disputed withdrawal is held on top of the balance, so the accounts hold 10.
+        let mut accounts = engine.into_accounts();
+        assert_eq!(ledger.expected_total(), Some(money(10.0)));
+        assert_eq!(check_invariants(&accounts, &ledger), Ok(()));
+
+        accounts.get_mut(&1).unwrap().available += money(0.5);
+        assert_eq!(
+            check_invariants(&accounts, &ledger),
+            Err(InvariantViolation { expected: Some(money(10.0)), actual: money(10.5) })
+        );
+    }
+
+    #[test]
+    fn ledger_saturates_instead_of_overflowing() {
+        // Each account holds its own amount, but between them they go past what `Money` can hold.
+        let near_max = PositiveAmount::new(Money(i64::MAX / 2 + 1));
+        let transactions = (1..=2)
+            .flat_map(|client| {
+                let tx = u32::from(client) * 2;
+                [
+                    Transaction::new(TransactionType::Deposit, client, tx, near_max),
+                    Transaction::new(TransactionType::Withdrawal, client, tx + 1, near_max),
+                ]
+            })
+            .collect();
+        // Checks the invariants too.
+        let accounts = process_transactions(transactions);
+        assert_eq!(accounts[&1].total(), Money::ZERO);
+
+        let ledger = Ledger { deposited: Money(i64::MAX), withdrawn: Money(-1), ..Ledger::default() };
+        assert_eq!(ledger.expected_total(), None);
+        let violation = check_invariants(&BTreeMap::new(), &ledger).unwrap_err();
+        assert_eq!(violation.to_string(), "accounts hold 0 in total but the ledger's total is out of range");
+    }
+
+    #[test]
+    fn ignored_withdrawals_are_recorded_when_asked() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 10.0\n\
+                    withdrawal, 1, 2, 12.5\n\
+                    withdrawal, 1, 3, 4.0\n";
+        let transactions = stream_csv(data.as_bytes()).collect::<Result<Vec<_>, _>>().unwrap();
+
+        let mut engine = TransactionEngine::new(EngineConfig::default());
+        engine.apply_all(transactions.clone(), None);
+        assert!(engine.events().is_empty());
+
+        let mut engine = TransactionEngine::new(EngineConfig { record_events: true, ..EngineConfig::default() });
+        engine.apply_all(transactions, None);
+        assert_eq!(engine.events(), &[EngineEvent::IgnoredWithdrawal { client: 1, tx: 2, amount: money(12.5) }]);
+        assert_eq!(
+            engine.events()[0].to_string(),
+            "ignored withdrawal tx 2 for client 1: insufficient funds for 12.5"
+        );
+    }
+
+    #[test]
+    fn refused_deposits_are_recorded_rather_than_printed() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 10.0\n\
+                    deposit, 1, 1, 5.0\n\
+                    deposit, 1, 2,\n\
+                    deposit, 1, 3, 100.0\n";
+        let transactions = stream_csv(data.as_bytes()).collect::<Result<Vec<_>, _>>().unwrap();
+        let config = EngineConfig { record_events: true, max_balance: Some(money(50.0)), ..EngineConfig::default() };
+        let mut engine = TransactionEngine::new(config);
+        engine.apply_all(transactions, None);
+        let events: Vec<String> = engine.events().iter().map(EngineEvent::to_string).collect();
+        assert_eq!(
+            events,
+            [
+                "ignored deposit tx 1 for client 1: duplicate transaction id",
+                "ignored deposit tx 2 for client 1: missing amount",
+                "ignored deposit tx 3 for client 1: balance would go over the maximum",
+            ]
+        );
+    }
+
+    #[test]
+    fn skipped_disputes_record_why() {
+        let skip_reasons = |engine: &TransactionEngine| -> Vec<(TransactionType, u32, SkipReason)> {
+            engine
+                .events()
+                .iter()
+                .filter_map(|event| match event {
+                    EngineEvent::Skipped { transaction_type, tx, reason, .. } => {
+                        Some((*transaction_type, *tx, *reason))
+                    }
+                    _ => None,
+                })
+                .collect()
+        };
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 10.0\n\
+                    deposit, 1, 2, 5.0\n\
+                    dispute, 1, 9,\n\
+                    resolve, 1, 1,\n\
+                    dispute, 1, 1,\n\
+                    dispute, 1, 1,\n\
+                    resolve, 1, 1,\n\
+                    dispute, 1, 1,\n\
+                    chargeback, 2, 2,\n\
+                    dispute, 1, 2,\n\
+                    chargeback, 1, 2,\n\
+                    resolve, 1, 2,\n\
+                    dispute, 1, 1,\n";
+        let config = EngineConfig { record_events: true, allow_redispute: false, ..EngineConfig::default() };
+        let mut engine = TransactionEngine::new(config);
+        engine.apply_stream(stream_csv(data.as_bytes()), None).unwrap();
+        assert_eq!(skip_reasons(&engine), vec![
+            (TransactionType::Dispute, 9, SkipReason::NotFound),
+            (TransactionType::Resolve, 1, SkipReason::NotDisputed),
+            (TransactionType::Dispute, 1, SkipReason::AlreadyDisputed),
+            (TransactionType::Dispute, 1, SkipReason::AlreadyResolved),
+            // Client 2 can't charge back client 1's deposit.
+            (TransactionType::Chargeback, 2, SkipReason::NotFound),
+            // Charged back, so forgotten.
+            (TransactionType::Resolve, 2, SkipReason::NotFound),
+            (TransactionType::Dispute, 1, SkipReason::AlreadyResolved),
+        ]);
+        assert_eq!(engine.anomalies(), 7);
+        assert_eq!(
+            engine.events().last().unwrap().to_string(),
+            "ignored dispute tx 1 for client 1: transaction was already resolved"
+        );
+
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 10.0\n\
+                    deposit, 1, 2, 5.0\n\
+                    dispute, 1, 2,\n\
+                    chargeback, 1, 2,\n\
+                    dispute, 1, 1,\n\
+                    deposit, 2, 3, 4.0\n\
+                    withdrawal, 2, 4, 3.0\n\
+                    dispute, 2, 3,\n";
+        let config = EngineConfig { record_events: true, allow_negative_available: false, ..EngineConfig::default() };
+        let mut engine = TransactionEngine::new(config);
+        engine.apply_stream(stream_csv(data.as_bytes()), None).unwrap();
+        assert_eq!(skip_reasons(&engine), vec![
+            (TransactionType::Dispute, 1, SkipReason::AccountFrozen),
+            (TransactionType::Dispute, 3, SkipReason::InsufficientFunds),
+        ]);
+
+        // Only deposits and withdrawals are ever kept, so this takes a hand-built entry.
+        let mut engine = TransactionEngine::new(EngineConfig { record_events: true, ..EngineConfig::default() });
+        engine.processed_transactions.insert((1, 5), ProcessedTransaction {
+            transaction_type: TransactionType::Dispute,
+            client: 1,
+            amount: money(1.0),
+        });
+        let data = "type, client, tx, amount\ndispute, 1, 5,\n";
+        engine.apply_stream(stream_csv(data.as_bytes()), None).unwrap();
+        assert_eq!(skip_reasons(&engine), vec![(TransactionType::Dispute, 5, SkipReason::WrongType)]);
+    }
+
+    #[test]
+    fn every_transaction_has_its_outcome_logged() {
+        let data = "type, client, tx, amount\n\
+                    deposit, 1, 1, 10.0\n\
+                    withdrawal, 1, 2, 25.0\n\
+                    dispute, 1, 1,\n\
+                    resolve, 1, 1,\n";
+        let transactions = stream_csv(data.as_bytes()).collect::<Result<Vec<_>, _>>().unwrap();
+        let (accounts, outcomes) = process_transactions_with_outcomes(transactions);
+        let event = |transaction_type, tx, outcome, available: f64, held: f64| AppliedEvent {
+            transaction_type,
+            client: 1,
+            tx,
+            outcome,
+            available: money(available),
+            held: money(held),
+        };
+        assert_eq!(outcomes, vec![
+            event(TransactionType::Deposit, 1, Outcome::Applied, 10.0, 0.0),
+            event(TransactionType::Withdrawal, 2, Outcome::Ignored(SkipReason::InsufficientFunds), 10.0, 0.0),
+            event(TransactionType::Dispute, 1, Outcome::Applied, 0.0, 10.0),
+            event(TransactionType::Resolve, 1, Outcome::Applied, 10.0, 0.0),
+        ]);
+        assert_eq!(accounts[&1].available, money(10.0));
+
+        // An early dispute is deferred, then logged again once its deposit arrives.
+        let data = "type, client, tx, amount\n\
+                    dispute, 1, 1,\n\
+                    deposit, 1, 1, 4.0\n\
+                    deposit, 1, 1, 4.0\n";
+        let config = EngineConfig { record_outcomes: true, buffer_early_disputes: true, ..EngineConfig::default() };
+        let mut engine = TransactionEngine::new(config);
+        engine.apply_stream(stream_csv(data.as_bytes()), None).unwrap();
+        let outcomes: Vec<(TransactionType, Outcome)> =
+            engine.outcomes().iter().map(|event| (event.transaction_type, event.outcome)).collect();
+        assert_eq!(outcomes, vec![
+            (TransactionType::Dispute, Outcome::Deferred),
+            (TransactionType::Deposit, Outcome::Applied),
+            (TransactionType::Dispute, Outcome::Applied),
+            (TransactionType::Deposit, Outcome::Ignored(SkipReason::Duplicate)),
+        ]);
+        assert_eq!(engine.outcomes()[2].held, money(4.0));
+
+        // Nothing is kept unless asked for.
+        let mut engine = TransactionEngine::new(EngineConfig::default());
+        engine.apply_stream(stream_csv(data.as_bytes()), None).unwrap();
+        assert!(engine.outcomes().is_empty());
+    }
+
+    #[test]
+    fn transfers_move_available_funds_between_clients() {
+        let data = "type, client, tx, amount, target\n\
+                    deposit, 1, 1, 10.0,\n\
+                    deposit, 2, 2, 1.0,\n\
+                    transfer, 1, 3, 4.0, 2\n\
+                    transfer, 2, 4, 3.0, 3\n";
+        let transactions = stream_csv(data.as_bytes()).collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(transactions[2].target, Some(2));
+        let accounts = process_transactions(transactions);
+        assert_eq!(accounts[&1].available, money(6.0));
+        assert_eq!(accounts[&2].available, money(2.0));
+        assert_eq!(accounts[&3].available, money(3.0));
+        assert_eq!(accounts[&1].total_withdrawn, money(4.0));
+        assert_eq!(accounts[&3].total_deposited, money(3.0));
+    }
+
+    #[test]
+    fn transfers_are_refused_without_funds_or_on_frozen_accounts() {
+        let data = "type, client, tx, amount, target\n\
+                    deposit, 1, 1, 5.0,\n\
+                    deposit, 2, 2, 5.0,\n\
+                    deposit, 3, 3, 5.0,\n\
+                    dispute, 3, 3, ,\n\
+                    chargeback, 3, 3, ,\n\
+                    transfer, 1, 4, 5.5, 2\n\
+                    transfer, 1, 5, 1.0, 3\n\
+                    transfer, 3, 6, 1.0, 1\n\
+                    transfer, 2, 7, 1.0,\n";
+        let transactions = stream_csv(data.as_bytes()).collect::<Result<Vec<_>, _>>().unwrap();
+        let accounts = process_transactions(transactions);
+        // Too little in the source, a frozen target, a frozen source and no target at all.
+        assert_eq!(accounts[&1].available, money(5.0));
+        assert_eq!(accounts[&2].available, money(5.0));
+        assert_eq!(accounts[&3].available, money(0.0));
+    }
+}
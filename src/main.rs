@@ -1,173 +1,786 @@
-use std::collections::HashMap;
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
 use std::env;
 use std::fs::File;
-use std::io::Error;
-
-use csv::Trim;
-use serde::Deserialize;
-
-#[derive(Debug, Deserialize, PartialEq)]
-#[serde(rename_all = "lowercase")]
-enum TransactionType {
-    Deposit,
-    Withdrawal,
-    Dispute,
-    Resolve,
-    Chargeback,
+use std::io::{self, BufRead, Error, ErrorKind, IsTerminal, Read, Write};
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::process;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use transactions::{
+    amount_total, diff_accounts, diverging_accounts, filter_client, open_transactions, process_line, read_baseline,
+    read_client_list, read_transactions_collecting_errors, read_transactions_skipping_errors,
+    read_transactions_with_trailer, round_accounts, sort_by_sequence, sort_by_timestamp, stream_csv_decimal,
+    stream_transactions_noting_truncations, validate_transactions, write_accounts, write_accounts_json,
+    write_accounts_json_versioned, write_accounts_ndjson, write_accounts_prometheus, write_deltas, write_divergences,
+    write_empty_indicator, write_error_summary, write_format_version, write_fully_held_warnings,
+    write_negative_available_warnings, write_shards, Account, Amount, BaselineError, Decimal, EmptyOutput, EngineConfig,
+    ExcessPrecision, InputFormat, Money, OutputFormat, Transaction, TransactionEngine, TransactionError, WriteOptions,
+    ZeroAmounts,
+};
+
+// With `--progress`, a line goes to stderr every time this many more rows have been read.
+const PROGRESS_INTERVAL: u64 = 1_000_000;
+
+// Exit code used when `--timeout` cuts processing short.
+const TIMEOUT_EXIT_CODE: i32 = 2;
+
+// Exit code used when `--check` finds invalid rows.
+const INVALID_INPUT_EXIT_CODE: i32 = 1;
+
+// Exit code used with `--strict` when any transaction was ignored or refused.
+const ANOMALIES_EXIT_CODE: i32 = 3;
+
+// Exit code used by `--on-empty fail` when nothing was processed.
+const EMPTY_INPUT_EXIT_CODE: i32 = 4;
+
+// Exit codes for a run that fails outright, following sysexits.h: EX_USAGE for invalid options
+// or nothing to read, EX_DATAERR for unusable input, EX_SOFTWARE for a failed self-check and
+// EX_IOERR when reading or writing fails.
+const USAGE_EXIT_CODE: i32 = 64;
+const DATA_ERROR_EXIT_CODE: i32 = 65;
+const SOFTWARE_ERROR_EXIT_CODE: i32 = 70;
+const IO_ERROR_EXIT_CODE: i32 = 74;
+
+const USAGE: &str = "Usage: transactions <input.csv>...";
+
+struct Options {
+    // Processed in order as one stream, so a dispute can refer to a transaction in an earlier file.
+    filenames: Vec<String>,
+    frozen_only: bool,
+    baseline: Option<String>,
+    max_balance: Option<Money>,
+    max_open_disputes: Option<usize>,
+    client: Option<u16>,
+    trailer: Option<String>,
+    format: OutputFormat,
+    allow_redispute: bool,
+    sort_by_seq: bool,
+    sort_by_timestamp: bool,
+    skip_errors: bool,
+    warn_fully_held: bool,
+    warn_negative_available: bool,
+    interactive: bool,
+    timeout: Option<u64>,
+    allowlist: Option<String>,
+    allow_negative_available: bool,
+    describe: Option<u16>,
+    on_empty: Option<EmptyOutput>,
+    expected: Option<String>,
+    format_version: bool,
+    verify_replay: bool,
+    isolate_clients: bool,
+    shards: Option<NonZeroUsize>,
+    output_file: Option<String>,
+    parallel: Option<NonZeroUsize>,
+    input_format: InputFormat,
+    check: bool,
+    // Adds the running deposit and withdrawal totals to CSV output.
+    verbose: bool,
+    // Lists every refused transaction on stderr once processing is done.
+    log_events: bool,
+    client_scoped_tx_ids: bool,
+    first_seen_order: bool,
+    buffer_early_disputes: bool,
+    excess_precision: ExcessPrecision,
+    // Whether a zero amount is skipped like a negative one or fails the row.
+    zero_amounts: ZeroAmounts,
+    precision: Option<usize>,
+    report_errors: bool,
+    progress: bool,
+    strict: bool,
+    header: bool,
+    output_delimiter: u8,
+    // Only the first this many input rows are processed.
+    limit: Option<usize>,
+    // Runs the engine on `Decimal` amounts, rounding only the final balances to four places.
+    decimal: bool,
 }
 
-#[derive(Debug, Deserialize, PartialEq)]
-struct Transaction {
-    #[serde(rename(deserialize = "type"))]
-    transaction_type: TransactionType,
-    client: u16,
-    tx: u32,
-    amount: Option<f32>,
+fn option_value<'a, I: Iterator<Item = &'a String>>(args: &mut I, flag: &str) -> Result<String, Error> {
+    args.next()
+        .cloned()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("missing value for {}", flag)))
 }
 
-#[derive(Clone)]
-struct Account {
-    disputed_transactions: Vec<u32>,
-    frozen: bool,
-    held: f32,
-    available: f32,
+fn parsed_option_value<'a, I, T>(args: &mut I, flag: &str) -> Result<T, Error>
+where
+    I: Iterator<Item = &'a String>,
+    T: FromStr,
+{
+    let value = option_value(args, flag)?;
+    value
+        .parse::<T>()
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, format!("invalid value for {}: {}", flag, value)))
 }
 
-impl Account {
-    fn deposit(&mut self, amount: f32) {
-        // Assuming that if the account is frozen, all deposit/withdrawal operations are blocked.
-        if !&self.frozen {
-            self.available += amount;
+fn parse_args(args: &[String]) -> Result<Options, Error> {
+    let mut filenames = vec![];
+    let mut frozen_only = false;
+    let mut baseline = None;
+    let mut max_balance = None;
+    let mut max_open_disputes = None;
+    let mut client = None;
+    let mut trailer = None;
+    let mut format = OutputFormat::Csv;
+    let mut allow_redispute = true;
+    let mut sort_by_seq = false;
+    let mut sort_by_timestamp = false;
+    let mut skip_errors = false;
+    let mut warn_fully_held = false;
+    let mut warn_negative_available = false;
+    let mut interactive = false;
+    let mut timeout = None;
+    let mut allowlist = None;
+    let mut allow_negative_available = true;
+    let mut describe = None;
+    let mut on_empty = None;
+    let mut expected = None;
+    let mut format_version = false;
+    let mut verify_replay = false;
+    let mut isolate_clients = false;
+    let mut shards = None;
+    let mut output_file = None;
+    let mut parallel = None;
+    let mut input_format = None;
+    let mut check = false;
+    let mut verbose = false;
+    let mut decimal = false;
+    let mut log_events = false;
+    let mut client_scoped_tx_ids = false;
+    let mut first_seen_order = false;
+    let mut buffer_early_disputes = false;
+    let mut excess_precision = ExcessPrecision::default();
+    let mut zero_amounts = ZeroAmounts::default();
+    let mut precision = None;
+    let mut report_errors = false;
+    let mut progress = false;
+    let mut strict = false;
+    let mut header = true;
+    let mut output_delimiter = None;
+    let mut limit = None;
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--frozen-only" => frozen_only = true,
+            "--baseline" => baseline = Some(option_value(&mut args, "--baseline")?),
+            "--max-balance" => max_balance = Some(parsed_option_value(&mut args, "--max-balance")?),
+            "--max-open-disputes" => max_open_disputes = Some(parsed_option_value(&mut args, "--max-open-disputes")?),
+            "--client" => client = Some(parsed_option_value(&mut args, "--client")?),
+            "--trailer" => trailer = Some(option_value(&mut args, "--trailer")?),
+            "--format" | "--output" => format = parsed_option_value(&mut args, arg)?,
+            "--reject-redisputes" => allow_redispute = false,
+            "--sort-by-seq" => sort_by_seq = true,
+            "--sort-by-timestamp" => sort_by_timestamp = true,
+            "--skip-errors" => skip_errors = true,
+            "--warn-fully-held" => warn_fully_held = true,
+            "--warn-negative-available" => warn_negative_available = true,
+            "--interactive" => interactive = true,
+            "--timeout" => timeout = Some(parsed_option_value(&mut args, "--timeout")?),
+            "--allowlist" => allowlist = Some(option_value(&mut args, "--allowlist")?),
+            "--reject-negative-available" => allow_negative_available = false,
+            "--describe" => describe = Some(parsed_option_value(&mut args, "--describe")?),
+            "--on-empty" => on_empty = Some(parsed_option_value(&mut args, "--on-empty")?),
+            "--expected" => expected = Some(option_value(&mut args, "--expected")?),
+            "--format-version" => format_version = true,
+            "--verify-replay" => verify_replay = true,
+            "--isolate-clients" => isolate_clients = true,
+            "--shards" => shards = Some(parsed_option_value(&mut args, "--shards")?),
+            "--output-file" => output_file = Some(option_value(&mut args, "--output-file")?),
+            "--parallel" => parallel = Some(parsed_option_value(&mut args, "--parallel")?),
+            "--input-format" => input_format = Some(parsed_option_value(&mut args, "--input-format")?),
+            "--check" => check = true,
+            "--verbose" => verbose = true,
+            "--decimal" => decimal = true,
+            "--log-events" => log_events = true,
+            "--client-scoped-tx-ids" => client_scoped_tx_ids = true,
+            "--first-seen-order" => first_seen_order = true,
+            "--buffer-early-disputes" => buffer_early_disputes = true,
+            "--excess-precision" => excess_precision = parsed_option_value(&mut args, "--excess-precision")?,
+            "--zero-amounts" => zero_amounts = parsed_option_value(&mut args, "--zero-amounts")?,
+            "--precision" => precision = Some(parsed_option_value(&mut args, "--precision")?),
+            "--report-errors" => report_errors = true,
+            "--progress" => progress = true,
+            "--strict" => strict = true,
+            "--no-header" => header = false,
+            "--limit" => limit = Some(parsed_option_value(&mut args, "--limit")?),
+            "--output-delimiter" => output_delimiter = Some(parsed_option_value::<_, char>(&mut args, arg)?),
+            flag if flag.starts_with("--") => {
+                return Err(Error::new(ErrorKind::InvalidInput, format!("unknown option {}", flag)));
+            }
+            _ => filenames.push(arg.clone()),
         }
     }
-
-    fn withdraw(&mut self, amount: f32) {
-        // Assuming that if the account is frozen, all deposit/withdrawal operations are blocked.
-        if amount > self.available || self.frozen {
-            return;
+    // Without an explicit format the first file's extension decides for all of them; stdin
+    // defaults to CSV.
+    let input_format = input_format.unwrap_or_else(|| match filenames.first() {
+        Some(filename) => InputFormat::from_path(Path::new(filename)),
+        None => InputFormat::Csv,
+    });
+    if precision.is_some_and(|precision| precision > 4) {
+        return Err(Error::new(ErrorKind::InvalidInput, "--precision must be between 0 and 4"));
+    }
+    if sort_by_seq && sort_by_timestamp {
+        return Err(Error::new(ErrorKind::InvalidInput, "--sort-by-seq and --sort-by-timestamp can't be combined"));
+    }
+    if !header && format != OutputFormat::Csv {
+        return Err(Error::new(ErrorKind::InvalidInput, "--no-header only applies to CSV output"));
+    }
+    // Input delimiters are detected from the header line, so only the output one is chosen here.
+    let output_delimiter = match output_delimiter {
+        Some(_) if format != OutputFormat::Csv => {
+            return Err(Error::new(ErrorKind::InvalidInput, "--output-delimiter only applies to CSV output"));
         }
-        self.available -= amount;
+        Some(delimiter) if !delimiter.is_ascii() || delimiter == '"' || delimiter == '\n' => {
+            let message = format!("invalid value for --output-delimiter: {}", delimiter);
+            return Err(Error::new(ErrorKind::InvalidInput, message));
+        }
+        Some(delimiter) => delimiter as u8,
+        None => b',',
+    };
+    if limit.is_some() && (interactive || check) {
+        return Err(Error::new(ErrorKind::InvalidInput, "--limit can't be combined with --interactive or --check"));
+    }
+    if excess_precision != ExcessPrecision::Round && (trailer.is_some() || skip_errors) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--excess-precision can't be combined with --trailer or --skip-errors",
+        ));
+    }
+    // `--skip-errors` and `--check` count every amount of zero or less as a bad row either way.
+    if zero_amounts == ZeroAmounts::Reject && (trailer.is_some() || report_errors || interactive || decimal) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--zero-amounts reject can't be combined with --trailer, --report-errors, --interactive or --decimal",
+        ));
     }
+    // Only the streaming CSV path runs on `Decimal`; everything else reads and checks `Money`.
+    let buffered = trailer.is_some() || skip_errors || report_errors || sort_by_seq || sort_by_timestamp;
+    let whole_run = interactive || check || baseline.is_some() || describe.is_some() || verify_replay;
+    if decimal && (buffered || whole_run || parallel.is_some() || input_format != InputFormat::Csv) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--decimal only applies to plain CSV runs: no --interactive, --check, --baseline, --describe, \
+             --verify-replay, --parallel, sorting or error handling options",
+        ));
+    }
+    if decimal && excess_precision != ExcessPrecision::Round {
+        let message = "--decimal keeps every decimal place, so --excess-precision does not apply";
+        return Err(Error::new(ErrorKind::InvalidInput, message));
+    }
+    if input_format != InputFormat::Csv && (trailer.is_some() || skip_errors || check || report_errors) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--trailer, --skip-errors, --report-errors and --check only apply to CSV input",
+        ));
+    }
+    Ok(Options {
+        filenames,
+        frozen_only,
+        baseline,
+        max_balance,
+        max_open_disputes,
+        client,
+        trailer,
+        format,
+        allow_redispute,
+        sort_by_seq,
+        sort_by_timestamp,
+        skip_errors,
+        warn_fully_held,
+        warn_negative_available,
+        interactive,
+        timeout,
+        allowlist,
+        allow_negative_available,
+        describe,
+        on_empty,
+        expected,
+        format_version,
+        verify_replay,
+        isolate_clients,
+        shards,
+        output_file,
+        parallel,
+        input_format,
+        check,
+        verbose,
+        log_events,
+        client_scoped_tx_ids,
+        first_seen_order,
+        buffer_early_disputes,
+        excess_precision,
+        zero_amounts,
+        precision,
+        report_errors,
+        progress,
+        strict,
+        header,
+        output_delimiter,
+        limit,
+        decimal,
+    })
+}
 
-    fn dispute(&mut self, transaction_id: u32, amount: f32) {
-        self.disputed_transactions.push(transaction_id);
-        self.held += amount;
+// A run without an input file reads stdin, but a terminal on stdin means nothing was piped in and
+// the run would just hang waiting for input.
+fn missing_input(options: &Options, stdin_is_terminal: bool) -> bool {
+    options.filenames.is_empty() && !options.interactive && stdin_is_terminal
+}
+
+// Transactions come from the named files, or from stdin when no file is given. Every file is
+// opened up front so a missing one fails the run before anything is applied.
+fn open_inputs(paths: &[String]) -> io::Result<Vec<Box<dyn Read>>> {
+    if paths.is_empty() {
+        return Ok(vec![Box::new(io::stdin().lock())]);
+    }
+    paths.iter().map(|path| open_transactions(Path::new(path))).collect()
+}
+
+// Counts rows across every input as they are read and, with `--progress`, reports the count on
+// stderr. Every way of reading input goes through it, so each kind of run reports the same way.
+struct RowCounter {
+    rows: Cell<u64>,
+    report: bool,
+}
+
+impl RowCounter {
+    fn new(report: bool) -> Self {
+        RowCounter { rows: Cell::new(0), report }
     }
 
-    fn resolve(&mut self, transaction_id: u32, amount: f32) {
-        if self.disputed_transactions.contains(&transaction_id) {
-            self.disputed_transactions.retain(|x| x != &transaction_id);
-            self.held -= amount;
-            self.available += amount;
+    fn count<'a, I: Iterator + 'a>(&'a self, rows: I) -> impl Iterator<Item = I::Item> + 'a {
+        rows.inspect(move |_| self.add(1))
+    }
+
+    // For readers that hand back a whole input at once.
+    fn add(&self, rows: u64) {
+        let (before, after) = (self.rows.get(), self.rows.get() + rows);
+        self.rows.set(after);
+        if self.report && after / PROGRESS_INTERVAL > before / PROGRESS_INTERVAL {
+            eprintln!("read {} rows", after);
         }
     }
 
-    fn chargeback(&mut self, transaction_id: u32, amount: f32) {
-        if self.disputed_transactions.contains(&transaction_id) {
-            self.disputed_transactions.retain(|x| x != &transaction_id);
-            self.held -= amount;
-            self.frozen = true;
+    fn finish(&self) {
+        if self.report {
+            eprintln!("read {} rows in total", self.rows.get());
         }
     }
-    fn total_funds(&self) -> f32 {
-        &self.available + &self.held
+}
+
+// Streams an input in the format and amount policies asked for, warning about every truncated
+// amount.
+fn read_transactions<'a>(
+    input: Box<dyn Read>,
+    options: &Options,
+    rows: &'a RowCounter,
+) -> Box<dyn Iterator<Item = Result<Transaction, TransactionError>> + 'a> {
+    let (format, precision, zero_amounts) = (options.input_format, options.excess_precision, options.zero_amounts);
+    let transactions = stream_transactions_noting_truncations(input, format, precision, zero_amounts, |truncation| {
+        eprintln!("{}", truncation)
+    });
+    Box::new(rows.count(transactions))
+}
+
+// Applies each input's rows as they are read, stopping for good once `--limit` rows have been
+// read or the deadline has passed. Returns whether it timed out and how many transactions it
+// handed to the engine.
+fn apply_streaming<A, I, T>(
+    engine: &mut TransactionEngine<A>,
+    inputs: I,
+    options: &Options,
+    deadline: Option<Instant>,
+) -> Result<(bool, usize), TransactionError>
+where
+    A: Amount,
+    I: IntoIterator<Item = T>,
+    T: Iterator<Item = Result<Transaction<A>, TransactionError>>,
+{
+    let mut timed_out = false;
+    let mut handed = 0;
+    let mut remaining = options.limit.unwrap_or(usize::MAX);
+    for transactions in inputs {
+        if remaining == 0 {
+            break;
+        }
+        let transactions = transactions
+            .take(remaining)
+            .inspect(|_| remaining -= 1)
+            .filter(|transaction| match (transaction, options.client) {
+                (Ok(transaction), Some(client)) => transaction.client == client,
+                _ => true,
+            })
+            .inspect(|transaction| handed += usize::from(transaction.is_ok()));
+        timed_out = !engine.apply_stream(transactions, deadline)?;
+        if timed_out {
+            break;
+        }
     }
+    Ok((timed_out, handed))
 }
 
-fn read_csv_file(filename: &str) -> std::io::Result<Vec<Transaction>> {
-    let file = File::open(filename)?;
-    let mut rdr = csv::ReaderBuilder::new().trim(Trim::All).from_reader(file);
-    Ok(rdr.deserialize()
+// The accounts a run ends with, the order their clients were first seen in, the number of
+// anomalies and the number of transactions handed to the engine, anomalies included.
+type Processed = (BTreeMap<u16, Account>, Vec<u16>, usize, usize);
+
+// Runs the engine on `Decimal` amounts so digits past the fourth place are carried through every
+// sum, then rounds the final balances to four places with `round_dp` for the usual output.
+fn run_decimal(
+    options: &Options,
+    config: EngineConfig,
+    deadline: Option<Instant>,
+    timed_out: &mut bool,
+) -> Result<Processed, Box<dyn std::error::Error>> {
+    let mut engine = TransactionEngine::with_accounts(BTreeMap::new(), config.map_amounts(Decimal::from));
+    let rows = RowCounter::new(options.progress);
+    let inputs = open_inputs(&options.filenames)?.into_iter().map(|input| rows.count(stream_csv_decimal(input)));
+    let (streaming_timed_out, handed) = apply_streaming(&mut engine, inputs, options, deadline)?;
+    rows.finish();
+    *timed_out = streaming_timed_out;
+    report_rejected_clients(&engine);
+    let anomalies = engine.anomalies();
+    let (accounts, client_order) = engine.into_accounts_with_order();
+    let accounts = accounts
         .into_iter()
-        .map(|result| {
-            result.unwrap()
-        })
-        .collect())
+        .map(|(client, account)| Ok((client, account.to_money()?)))
+        .collect::<Result<_, String>>()?;
+    Ok((accounts, client_order, anomalies, handed))
 }
 
-fn process_transactions(transactions: Vec<Transaction>) -> HashMap<u16, Account> {
-    let mut accounts: HashMap<u16, Account> = HashMap::new();
-    let mut processed_transactions: HashMap<u32, Transaction> = HashMap::new();
-
-    for transaction in transactions.into_iter() {
-        let client_id = transaction.client;
-        let user_account = accounts.entry(client_id).or_insert(Account {
-            disputed_transactions: vec![],
-            frozen: false,
-            held: 0.0,
-            available: 0.0,
-        });
-
-        match transaction.transaction_type {
-            TransactionType::Deposit => {
-                user_account.deposit(transaction.amount.unwrap());
-                processed_transactions.insert(transaction.tx, transaction);
-            }
-            TransactionType::Withdrawal => {
-                user_account.withdraw(transaction.amount.unwrap());
-                processed_transactions.insert(transaction.tx, transaction);
-            }
-            TransactionType::Dispute => {
-                let possible_disputed_transaction = processed_transactions.get(&transaction.tx);
-                match possible_disputed_transaction {
-                    Some(disputed_transaction)
-                    if disputed_transaction.transaction_type == TransactionType::Deposit
-                        || disputed_transaction.transaction_type == TransactionType::Withdrawal =>
-                        {
-                            user_account.dispute(
-                                disputed_transaction.tx,
-                                disputed_transaction.amount.unwrap(),
-                            )
-                        }
-                    _ => {}
+// Reads a whole input for the paths that need every transaction before applying any.
+fn read_input(
+    input: Box<dyn Read>,
+    options: &Options,
+    rows: &RowCounter,
+) -> Result<Vec<Transaction>, Box<dyn std::error::Error>> {
+    let transactions = match &options.trailer {
+        Some(marker) => {
+            let (transactions, control_total) = read_transactions_with_trailer(input, marker)?;
+            if let Some(control_total) = control_total {
+                let total = amount_total(&transactions);
+                if total != control_total {
+                    eprintln!("control total {} does not match transaction total {}", control_total, total);
                 }
             }
-            TransactionType::Resolve => {
-                let possible_transaction = processed_transactions.get(&transaction.tx);
-                match possible_transaction {
-                    Some(disputed_transaction)
-                    if disputed_transaction.transaction_type == TransactionType::Deposit
-                        || disputed_transaction.transaction_type
-                        == TransactionType::Withdrawal =>
-                        {
-                            user_account.resolve(
-                                disputed_transaction.tx,
-                                disputed_transaction.amount.unwrap(),
-                            )
-                        }
-                    _ => {}
-                }
+            transactions
+        }
+        None if options.skip_errors => {
+            let (transactions, errors) = read_transactions_skipping_errors(input)?;
+            if !errors.is_empty() {
+                write_error_summary(&errors, "skipped rows", &mut io::stderr().lock())?;
             }
-            TransactionType::Chargeback => {
-                let possible_transaction = processed_transactions.get(&transaction.tx);
-                match possible_transaction {
-                    Some(disputed_transaction)
-                    if disputed_transaction.transaction_type == TransactionType::Deposit
-                        || disputed_transaction.transaction_type
-                        == TransactionType::Withdrawal =>
-                        {
-                            user_account.chargeback(
-                                disputed_transaction.tx,
-                                disputed_transaction.amount.unwrap(),
-                            )
-                        }
-                    _ => {}
-                }
+            transactions
+        }
+        None if options.report_errors => {
+            // Good rows are still applied; every bad one is listed once the input is read.
+            let (transactions, errors) = read_transactions_collecting_errors(input)?;
+            for error in &errors {
+                eprintln!("{}", error);
             }
+            transactions
         }
+        None => return Ok(read_transactions(input, options, rows).collect::<Result<Vec<_>, _>>()?),
+    };
+    // These readers hand back the whole input, so its rows are counted in one go.
+    rows.add(transactions.len() as u64);
+    Ok(transactions)
+}
+
+// Where results go: stdout by default, or the file given with `--output-file`. Stdout is line
+// buffered on its own, which costs a write per account, so it is buffered here like a file and
+// flushed once the output is complete.
+fn open_output(path: Option<&str>) -> io::Result<Box<dyn Write>> {
+    Ok(match path {
+        Some(path) => Box::new(io::BufWriter::new(File::create(path)?)),
+        None => Box::new(io::BufWriter::new(io::stdout().lock())),
+    })
+}
+
+// Applies header-less transaction lines from stdin as they arrive. Bad lines are reported and
+// skipped so a typo doesn't end the session.
+fn run_interactive(config: EngineConfig) -> io::Result<Processed> {
+    let mut engine = TransactionEngine::new(config);
+    let mut handed = 0;
+    for line in io::stdin().lock().lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match process_line(&mut engine, &line) {
+            Ok(()) => handed += 1,
+            Err(error) => eprintln!("{}", error),
+        }
+    }
+    report_rejected_clients(&engine);
+    let anomalies = engine.anomalies();
+    let (accounts, client_order) = engine.into_accounts_with_order();
+    Ok((accounts, client_order, anomalies, handed))
+}
+
+// The CSV layout asked for on the command line. Callers fill in the client order if they need one.
+fn write_options(options: &Options) -> WriteOptions<'static> {
+    WriteOptions {
+        frozen_only: options.frozen_only,
+        verbose: options.verbose,
+        header: options.header,
+        delimiter: options.output_delimiter,
+        order: None,
+        decimals: options.precision,
+    }
+}
+
+// Summarises on stderr what the engine turned away, followed by the recorded events if any.
+fn report_rejected_clients<A: Amount>(engine: &TransactionEngine<A>) {
+    if engine.unlisted_client_transactions() > 0 {
+        eprintln!(
+            "rejected {} transactions for clients not on the allow list",
+            engine.unlisted_client_transactions()
+        );
+    }
+    for (client, tx) in engine.quarantined_clients() {
+        eprintln!("client {} quarantined: tx {} has no amount", client, tx);
+    }
+    if engine.invalid_amounts() > 0 {
+        eprintln!("skipped {} transactions with an amount of zero or less", engine.invalid_amounts());
+    }
+    if engine.ignored_resolves() > 0 || engine.ignored_chargebacks() > 0 {
+        eprintln!(
+            "ignored {} resolves and {} chargebacks without an open dispute",
+            engine.ignored_resolves(),
+            engine.ignored_chargebacks()
+        );
+    }
+    for event in engine.events() {
+        eprintln!("{}", event);
+    }
+    if engine.pending_disputes() > 0 {
+        eprintln!("dropped {} disputes for transactions that never arrived", engine.pending_disputes());
     }
-    return accounts;
 }
 
-fn main() -> Result<(), Error> {
+// Writes what `--on-empty` asks for in place of the balances, exiting if it treats the run as failed.
+fn write_empty_output(policy: EmptyOutput, output: &mut Box<dyn Write>) -> io::Result<()> {
+    let fail = write_empty_indicator(policy, output)?;
+    output.flush()?;
+    if fail {
+        eprintln!("no transactions were processed");
+        process::exit(EMPTY_INPUT_EXIT_CODE);
+    }
+    Ok(())
+}
+
+// Exits with the code for whatever went wrong during processing, once the output is written.
+fn exit_on_failure(timed_out: bool, anomalies: usize, strict: bool) {
+    if timed_out {
+        eprintln!("processing timed out; output is partial");
+        process::exit(TIMEOUT_EXIT_CODE);
+    }
+    if strict && anomalies > 0 {
+        eprintln!("{} transactions were ignored or refused", anomalies);
+        process::exit(ANOMALIES_EXIT_CODE);
+    }
+}
+
+// The exit code for an error that ended the run.
+fn error_exit_code(error: &(dyn std::error::Error + 'static)) -> i32 {
+    if let Some(error) = error.downcast_ref::<TransactionError>() {
+        return match error {
+            TransactionError::Io(_) => IO_ERROR_EXIT_CODE,
+            _ => DATA_ERROR_EXIT_CODE,
+        };
+    }
+    if error.is::<BaselineError>() {
+        return DATA_ERROR_EXIT_CODE;
+    }
+    if error.is::<io::Error>() {
+        return IO_ERROR_EXIT_CODE;
+    }
+    SOFTWARE_ERROR_EXIT_CODE
+}
+
+fn main() {
     let args: Vec<String> = env::args().collect();
-    let filename = &args[1];
-    let transactions = read_csv_file(filename)?;
-    let accounts = process_transactions(transactions);
-    println!("client, available, held, total, locked");
-    for (client, account) in accounts.into_iter() {
-        println!("{}, {}, {}, {}, {}", client, account.available, account.held, account.total_funds(), account.frozen);
+    let options = match parse_args(&args) {
+        Ok(options) => options,
+        Err(error) => {
+            eprintln!("error: {}", error);
+            eprintln!("{}", USAGE);
+            process::exit(USAGE_EXIT_CODE);
+        }
+    };
+    if let Err(error) = run(options) {
+        eprintln!("error: {}", error);
+        process::exit(error_exit_code(error.as_ref()));
     }
+}
+
+fn run(options: Options) -> Result<(), Box<dyn std::error::Error>> {
+    let started = Instant::now();
+    if missing_input(&options, io::stdin().is_terminal()) {
+        eprintln!("{}", USAGE);
+        process::exit(USAGE_EXIT_CODE);
+    }
+    if options.check {
+        // Validation only: nothing is applied and no balances are written. Ids are scoped the
+        // way processing would scope them.
+        let config = EngineConfig { client_scoped_tx_ids: options.client_scoped_tx_ids, ..EngineConfig::default() };
+        let mut errors = HashMap::new();
+        for input in open_inputs(&options.filenames)? {
+            for (kind, count) in validate_transactions(input, &config, options.excess_precision)? {
+                *errors.entry(kind).or_insert(0) += count;
+            }
+        }
+        let mut output = open_output(options.output_file.as_deref())?;
+        write_error_summary(&errors, "invalid rows", &mut output)?;
+        output.flush()?;
+        if !errors.is_empty() {
+            process::exit(INVALID_INPUT_EXIT_CODE);
+        }
+        return Ok(());
+    }
+    let deadline = options.timeout.map(|seconds| started + Duration::from_secs(seconds));
+    let mut timed_out = false;
+    let allowed_clients = match &options.allowlist {
+        Some(allowlist) => Some(read_client_list(io::BufReader::new(File::open(allowlist)?))?),
+        None => None,
+    };
+    let config = EngineConfig {
+        max_balance: options.max_balance,
+        allow_redispute: options.allow_redispute,
+        allowed_clients,
+        allow_negative_available: options.allow_negative_available,
+        verify_replay: options.verify_replay,
+        isolate_clients: options.isolate_clients,
+        client_scoped_tx_ids: options.client_scoped_tx_ids,
+        buffer_early_disputes: options.buffer_early_disputes,
+        record_events: options.log_events,
+        record_outcomes: false,
+        max_open_disputes: options.max_open_disputes,
+    };
+    let (accounts, client_order, anomalies, handed) = if options.decimal {
+        run_decimal(&options, config, deadline, &mut timed_out)?
+    } else if !options.interactive {
+        let inputs = open_inputs(&options.filenames)?;
+        let baseline = match &options.baseline {
+            Some(baseline_file) => Some(read_baseline(File::open(baseline_file)?)?),
+            None => None,
+        };
+        let mut engine = TransactionEngine::with_accounts(baseline.clone().unwrap_or_default(), config);
+        let handed;
+        let rows = RowCounter::new(options.progress);
+        if options.trailer.is_none()
+            && !options.skip_errors
+            && !options.report_errors
+            && !options.sort_by_seq
+            && !options.sort_by_timestamp
+            && options.parallel.is_none()
+        {
+            // Nothing needs the whole file up front, so rows are applied as they are read.
+            let inputs = inputs.into_iter().map(|input| read_transactions(input, &options, &rows));
+            (timed_out, handed) = apply_streaming(&mut engine, inputs, &options, deadline)?;
+            rows.finish();
+        } else {
+            let mut transactions = vec![];
+            for input in inputs {
+                transactions.extend(read_input(input, &options, &rows)?);
+            }
+            rows.finish();
+            // These runs read every row first, so the limit only trims what is applied.
+            if let Some(limit) = options.limit {
+                transactions.truncate(limit);
+            }
+            if let Some(client) = options.client {
+                transactions = filter_client(transactions, client);
+            }
+            if options.sort_by_seq {
+                sort_by_sequence(&mut transactions);
+            }
+            if options.sort_by_timestamp {
+                sort_by_timestamp(&mut transactions);
+            }
+            handed = transactions.len();
+            timed_out = !match options.parallel {
+                Some(threads) => engine.apply_all_parallel(transactions, threads, deadline),
+                None => engine.apply_all(transactions, deadline),
+            };
+        }
+        report_rejected_clients(&engine);
+        let anomalies = engine.anomalies();
+        if options.verify_replay {
+            engine.verify_replay()?;
+        }
+        if let Some(client) = options.describe {
+            let mut output = open_output(options.output_file.as_deref())?;
+            writeln!(output, "{}", engine.describe(client))?;
+            output.flush()?;
+            return Ok(());
+        }
+        let (accounts, client_order) = engine.into_accounts_with_order();
+        if let Some(baseline) = baseline {
+            let mut output = open_output(options.output_file.as_deref())?;
+            // The baseline's accounts are there either way, so only the transactions say whether
+            // this run had anything in it.
+            if let (0, Some(policy)) = (handed.saturating_sub(anomalies), options.on_empty) {
+                return Ok(write_empty_output(policy, &mut output)?);
+            }
+            write_deltas(&diff_accounts(&baseline, &accounts), &write_options(&options), &mut output)?;
+            output.flush()?;
+            exit_on_failure(timed_out, anomalies, options.strict);
+            return Ok(());
+        }
+        (accounts, client_order, anomalies, handed)
+    } else {
+        run_interactive(config)?
+    };
+    if options.warn_fully_held {
+        write_fully_held_warnings(&accounts, &mut io::stderr().lock())?;
+    }
+    if options.warn_negative_available {
+        write_negative_available_warnings(&accounts, &mut io::stderr().lock())?;
+    }
+    if let Some(shards) = options.shards {
+        write_shards(accounts, shards, &write_options(&options), Path::new("."))?;
+        exit_on_failure(timed_out, anomalies, options.strict);
+        return Ok(());
+    }
+    let mut output = open_output(options.output_file.as_deref())?;
+    if let Some(expected) = &options.expected {
+        let expected = read_baseline(File::open(expected)?)?;
+        write_divergences(&diverging_accounts(&expected, &accounts), &write_options(&options), &mut output)?;
+        output.flush()?;
+        exit_on_failure(timed_out, anomalies, options.strict);
+        return Ok(());
+    }
+    // Empty means no transaction took effect: none were read, or every one was ignored or refused.
+    if let (0, Some(policy)) = (handed.saturating_sub(anomalies), options.on_empty) {
+        return Ok(write_empty_output(policy, &mut output)?);
+    }
+    if options.format_version {
+        write_format_version(options.format, &mut output)?;
+    }
+    // Rounding is for display only, so everything above, the comparison against `--expected`
+    // included, sees the exact balances. CSV pads to the precision; JSON numbers can't.
+    let accounts = match (options.format, options.precision) {
+        (OutputFormat::Csv, _) | (_, None) => accounts,
+        (_, Some(decimals)) => round_accounts(&accounts, decimals),
+    };
+    let order = if options.first_seen_order { Some(client_order.as_slice()) } else { None };
+    match options.format {
+        OutputFormat::Csv => {
+            write_accounts(&accounts, &WriteOptions { order, ..write_options(&options) }, &mut output)?
+        }
+        OutputFormat::Ndjson => write_accounts_ndjson(&accounts, options.frozen_only, order, &mut output)?,
+        OutputFormat::Json if options.format_version => {
+            write_accounts_json_versioned(&accounts, options.frozen_only, order, &mut output)?
+        }
+        OutputFormat::Json => write_accounts_json(&accounts, options.frozen_only, order, &mut output)?,
+        OutputFormat::Prometheus => write_accounts_prometheus(&accounts, options.frozen_only, order, &mut output)?,
+    }
+    output.flush()?;
+    exit_on_failure(timed_out, anomalies, options.strict);
     Ok(())
 }
 
@@ -176,378 +789,311 @@ mod tests {
     use super::*;
 
     #[test]
-    fn deposit_gets_processed_successfully() {
-        let t1 = Transaction {
-            transaction_type: TransactionType::Deposit,
-            client: 0,
-            tx: 0,
-            amount: Some(10.0),
-        };
-        let t2 = Transaction {
-            transaction_type: TransactionType::Deposit,
-            client: 0,
-            tx: 1,
-            amount: Some(20.0),
-        };
-        let accounts = process_transactions(vec![t1, t2]);
-        assert!(accounts.contains_key(&0));
+    fn frozen_only_flag_is_parsed() {
+        let args: Vec<String> = vec!["transactions", "--frozen-only", "input.csv"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let options = parse_args(&args).unwrap();
+        assert_eq!(options.filenames, vec!["input.csv"]);
+        assert!(options.frozen_only);
+    }
 
-        let user_0_account = accounts.get(&0).unwrap();
-        assert_eq!(user_0_account.available, 30.0);
-        assert_eq!(user_0_account.held, 0.0);
-        assert_eq!(user_0_account.total_funds(), 30.0);
+    #[test]
+    fn client_filter_flag_is_parsed() {
+        let args: Vec<String> = vec!["transactions", "--client", "7", "input.csv"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let options = parse_args(&args).unwrap();
+        assert_eq!(options.client, Some(7));
+        assert_eq!(options.filenames, vec!["input.csv"]);
     }
 
     #[test]
-    fn withdrawal_is_ignored_if_insufficient_funds() {
-        let t1 = Transaction {
-            transaction_type: TransactionType::Withdrawal,
-            client: 0,
-            tx: 0,
-            amount: Some(10.0),
-        };
-        let t2 = Transaction {
-            transaction_type: TransactionType::Withdrawal,
-            client: 0,
-            tx: 1,
-            amount: Some(20.0),
-        };
-        let accounts = process_transactions(vec![t1, t2]);
-        assert!(accounts.contains_key(&0));
+    fn format_flag_is_parsed() {
+        let args: Vec<String> = vec!["transactions", "--format", "ndjson", "input.csv"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert_eq!(parse_args(&args).unwrap().format, OutputFormat::Ndjson);
 
-        let user_0_account = accounts.get(&0).unwrap();
-        assert_eq!(user_0_account.available, 0.0);
-        assert_eq!(user_0_account.held, 0.0);
-        assert_eq!(user_0_account.total_funds(), 0.0);
+        let args: Vec<String> = vec!["transactions", "--output", "json", "input.csv"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert_eq!(parse_args(&args).unwrap().format, OutputFormat::Json);
+
+        let args: Vec<String> = vec!["transactions", "--format", "xml", "input.csv"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert!(parse_args(&args).is_err());
     }
 
     #[test]
-    fn withdrawal_is_ignored_once_amount_exceeds_funds() {
-        let t1 = Transaction {
-            transaction_type: TransactionType::Deposit,
-            client: 0,
-            tx: 0,
-            amount: Some(20.0),
-        };
-        let t2 = Transaction {
-            transaction_type: TransactionType::Withdrawal,
-            client: 0,
-            tx: 1,
-            amount: Some(10.0),
-        };
-        let t3 = Transaction {
-            transaction_type: TransactionType::Withdrawal,
-            client: 0,
-            tx: 2,
-            amount: Some(12.0),
-        };
-        let accounts = process_transactions(vec![t1, t2, t3]);
-        assert!(accounts.contains_key(&0));
+    fn interactive_mode_does_not_need_an_input_file() {
+        let args: Vec<String> = vec!["transactions", "--interactive"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let options = parse_args(&args).unwrap();
+        assert!(options.interactive);
+        assert!(options.filenames.is_empty());
+    }
 
-        let user_0_account = accounts.get(&0).unwrap();
-        assert_eq!(user_0_account.available, 10.0);
-        assert_eq!(user_0_account.held, 0.0);
-        assert_eq!(user_0_account.total_funds(), 10.0);
+    #[test]
+    fn input_file_is_optional_so_stdin_can_be_read() {
+        let args: Vec<String> = vec!["transactions", "--frozen-only"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let options = parse_args(&args).unwrap();
+        assert!(!options.interactive);
+        assert!(options.filenames.is_empty());
     }
 
     #[test]
-    fn disputing_a_real_transaction() {
-        let t1 = Transaction {
-            transaction_type: TransactionType::Deposit,
-            client: 0,
-            tx: 0,
-            amount: Some(20.0),
-        };
-        let t2 = Transaction {
-            transaction_type: TransactionType::Withdrawal,
-            client: 0,
-            tx: 1,
-            amount: Some(5.0),
-        };
-        let t3 = Transaction {
-            transaction_type: TransactionType::Dispute,
-            client: 0,
-            tx: 1,
-            amount: None,
-        };
-        let accounts = process_transactions(vec![t1, t2, t3]);
-        assert!(accounts.contains_key(&0));
+    fn on_empty_flag_is_parsed() {
+        let args: Vec<String> = vec!["transactions", "--on-empty", "fail", "input.csv"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert_eq!(parse_args(&args).unwrap().on_empty, Some(EmptyOutput::Fail));
+    }
 
-        let user_0_account = accounts.get(&0).unwrap();
-        assert_eq!(user_0_account.disputed_transactions, vec![1]);
-        assert_eq!(user_0_account.available, 15.0);
-        assert_eq!(user_0_account.held, 5.0);
-        assert_eq!(user_0_account.total_funds(), 20.0);
+    #[test]
+    fn output_file_flag_is_parsed() {
+        let args: Vec<String> = vec!["transactions", "input.csv", "--output-file", "accounts.csv"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert_eq!(parse_args(&args).unwrap().output_file.as_deref(), Some("accounts.csv"));
     }
 
     #[test]
-    fn disputing_a_fake_transaction() {
-        let t1 = Transaction {
-            transaction_type: TransactionType::Deposit,
-            client: 0,
-            tx: 0,
-            amount: Some(20.0),
-        };
-        let t2 = Transaction {
-            transaction_type: TransactionType::Withdrawal,
-            client: 0,
-            tx: 1,
-            amount: Some(5.0),
-        };
-        let t3 = Transaction {
-            transaction_type: TransactionType::Dispute,
-            client: 0,
-            tx: 2,
-            amount: None,
-        };
-        let accounts = process_transactions(vec![t1, t2, t3]);
-        assert!(accounts.contains_key(&0));
+    fn usage_is_needed_only_when_there_is_nothing_to_read() {
+        let args: Vec<String> = vec!["transactions"].into_iter().map(String::from).collect();
+        let options = parse_args(&args).unwrap();
+        assert!(missing_input(&options, true));
+        assert!(!missing_input(&options, false));
 
-        let user_0_account = accounts.get(&0).unwrap();
-        assert_eq!(user_0_account.disputed_transactions, vec![]);
-        assert_eq!(user_0_account.available, 15.0);
-        assert_eq!(user_0_account.held, 0.0);
-        assert_eq!(user_0_account.total_funds(), 15.0);
+        let args: Vec<String> = vec!["transactions", "input.csv"].into_iter().map(String::from).collect();
+        assert!(!missing_input(&parse_args(&args).unwrap(), true));
+
+        let args: Vec<String> = vec!["transactions", "--interactive"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert!(!missing_input(&parse_args(&args).unwrap(), true));
     }
 
     #[test]
-    fn resolving_a_disputed_transaction() {
-        let t1 = Transaction {
-            transaction_type: TransactionType::Deposit,
-            client: 0,
-            tx: 0,
-            amount: Some(20.0),
-        };
-        let t2 = Transaction {
-            transaction_type: TransactionType::Withdrawal,
-            client: 0,
-            tx: 1,
-            amount: Some(5.0),
-        };
-        let t3 = Transaction {
-            transaction_type: TransactionType::Dispute,
-            client: 0,
-            tx: 1,
-            amount: None,
-        };
-        let t4 = Transaction {
-            transaction_type: TransactionType::Resolve,
-            client: 0,
-            tx: 1,
-            amount: None,
-        };
-        let accounts = process_transactions(vec![t1, t2, t3, t4]);
-        assert!(accounts.contains_key(&0));
+    fn input_format_comes_from_the_flag_or_the_extension() {
+        let args: Vec<String> = vec!["transactions", "feed.ndjson"].into_iter().map(String::from).collect();
+        assert_eq!(parse_args(&args).unwrap().input_format, InputFormat::Ndjson);
+
+        let args: Vec<String> = vec!["transactions", "--input-format", "ndjson", "feed.txt"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert_eq!(parse_args(&args).unwrap().input_format, InputFormat::Ndjson);
+
+        let args: Vec<String> = vec!["transactions", "input.csv"].into_iter().map(String::from).collect();
+        assert_eq!(parse_args(&args).unwrap().input_format, InputFormat::Csv);
 
-        let user_0_account = accounts.get(&0).unwrap();
-        assert_eq!(user_0_account.disputed_transactions, vec![]);
-        assert_eq!(user_0_account.available, 20.0);
-        assert_eq!(user_0_account.held, 0.0);
-        assert_eq!(user_0_account.total_funds(), 20.0);
+        let args: Vec<String> = vec!["transactions", "--skip-errors", "feed.json"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert!(parse_args(&args).is_err());
     }
 
     #[test]
-    fn resolving_a_fake_disputed_transaction() {
-        let t1 = Transaction {
-            transaction_type: TransactionType::Deposit,
-            client: 0,
-            tx: 0,
-            amount: Some(20.0),
-        };
-        let t2 = Transaction {
-            transaction_type: TransactionType::Withdrawal,
-            client: 0,
-            tx: 1,
-            amount: Some(5.0),
-        };
-        let t3 = Transaction {
-            transaction_type: TransactionType::Dispute,
-            client: 0,
-            tx: 1,
-            amount: None,
-        };
-        let t4 = Transaction {
-            transaction_type: TransactionType::Resolve,
-            client: 0,
-            tx: 2,
-            amount: None,
-        };
-        let accounts = process_transactions(vec![t1, t2, t3, t4]);
-        assert!(accounts.contains_key(&0));
+    fn excess_precision_flag_is_parsed() {
+        let args: Vec<String> = vec!["transactions", "--excess-precision", "reject", "input.csv"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert_eq!(parse_args(&args).unwrap().excess_precision, ExcessPrecision::Reject);
+
+        let args: Vec<String> = vec!["transactions", "input.csv"].into_iter().map(String::from).collect();
+        assert_eq!(parse_args(&args).unwrap().excess_precision, ExcessPrecision::Round);
+
+        let args: Vec<String> = vec!["transactions", "--excess-precision", "truncate", "--skip-errors", "input.csv"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert!(parse_args(&args).is_err());
 
-        let user_0_account = accounts.get(&0).unwrap();
-        assert_eq!(user_0_account.disputed_transactions, vec![1]);
-        assert_eq!(user_0_account.available, 15.0);
-        assert_eq!(user_0_account.held, 5.0);
-        assert_eq!(user_0_account.total_funds(), 20.0);
+        let args: Vec<String> = vec!["transactions", "--excess-precision", "reject", "--check", "input.csv"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert_eq!(parse_args(&args).unwrap().excess_precision, ExcessPrecision::Reject);
     }
 
     #[test]
-    fn chargeback_a_disputed_transaction() {
-        let t1 = Transaction {
-            transaction_type: TransactionType::Deposit,
-            client: 0,
-            tx: 0,
-            amount: Some(20.0),
-        };
-        let t2 = Transaction {
-            transaction_type: TransactionType::Withdrawal,
-            client: 0,
-            tx: 1,
-            amount: Some(5.0),
-        };
-        let t3 = Transaction {
-            transaction_type: TransactionType::Dispute,
-            client: 0,
-            tx: 1,
-            amount: None,
-        };
-        let t4 = Transaction {
-            transaction_type: TransactionType::Chargeback,
-            client: 0,
-            tx: 1,
-            amount: None,
-        };
-        let accounts = process_transactions(vec![t1, t2, t3, t4]);
-        assert!(accounts.contains_key(&0));
+    fn zero_amounts_flag_is_parsed() {
+        let args: Vec<String> = vec!["transactions", "input.csv"].into_iter().map(String::from).collect();
+        assert_eq!(parse_args(&args).unwrap().zero_amounts, ZeroAmounts::Skip);
+
+        let args: Vec<String> = vec!["transactions", "--zero-amounts", "reject", "input.csv"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert_eq!(parse_args(&args).unwrap().zero_amounts, ZeroAmounts::Reject);
 
-        let user_0_account = accounts.get(&0).unwrap();
-        assert_eq!(user_0_account.disputed_transactions, vec![]);
-        assert_eq!(user_0_account.frozen, true);
-        assert_eq!(user_0_account.available, 15.0);
-        assert_eq!(user_0_account.held, 0.0);
-        assert_eq!(user_0_account.total_funds(), 15.0);
+        let args: Vec<String> = vec!["transactions", "--zero-amounts", "reject", "--decimal", "input.csv"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert!(parse_args(&args).is_err());
     }
 
     #[test]
-    fn chargeback_an_existing_non_disputed_transaction() {
-        let t1 = Transaction {
-            transaction_type: TransactionType::Deposit,
-            client: 0,
-            tx: 0,
-            amount: Some(20.0),
-        };
-        let t2 = Transaction {
-            transaction_type: TransactionType::Withdrawal,
-            client: 0,
-            tx: 1,
-            amount: Some(5.0),
-        };
-        let t3 = Transaction {
-            transaction_type: TransactionType::Dispute,
-            client: 0,
-            tx: 1,
-            amount: None,
-        };
-        let t4 = Transaction {
-            transaction_type: TransactionType::Chargeback,
-            client: 0,
-            tx: 0,
-            amount: None,
-        };
-        let accounts = process_transactions(vec![t1, t2, t3, t4]);
-        assert!(accounts.contains_key(&0));
+    fn precision_flag_is_parsed() {
+        let args: Vec<String> = vec!["transactions", "--precision", "2", "input.csv"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert_eq!(parse_args(&args).unwrap().precision, Some(2));
 
-        let user_0_account = accounts.get(&0).unwrap();
-        assert_eq!(user_0_account.disputed_transactions, vec![1]);
-        assert_eq!(user_0_account.frozen, false);
-        assert_eq!(user_0_account.available, 15.0);
-        assert_eq!(user_0_account.held, 5.0);
-        assert_eq!(user_0_account.total_funds(), 20.0);
+        let args: Vec<String> = vec!["transactions", "--precision", "6", "input.csv"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert!(parse_args(&args).is_err());
     }
 
     #[test]
-    fn chargeback_a_non_existing_disputed_transaction() {
-        let t1 = Transaction {
-            transaction_type: TransactionType::Deposit,
-            client: 0,
-            tx: 0,
-            amount: Some(20.0),
-        };
-        let t2 = Transaction {
-            transaction_type: TransactionType::Withdrawal,
-            client: 0,
-            tx: 1,
-            amount: Some(5.0),
-        };
-        let t3 = Transaction {
-            transaction_type: TransactionType::Dispute,
-            client: 0,
-            tx: 1,
-            amount: None,
-        };
-        let t4 = Transaction {
-            transaction_type: TransactionType::Chargeback,
-            client: 0,
-            tx: 5,
-            amount: None,
-        };
-        let accounts = process_transactions(vec![t1, t2, t3, t4]);
-        assert!(accounts.contains_key(&0));
+    fn only_one_sort_order_can_be_chosen() {
+        let args: Vec<String> = vec!["transactions", "--sort-by-timestamp", "input.csv"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert!(parse_args(&args).unwrap().sort_by_timestamp);
+
+        let args: Vec<String> = vec!["transactions", "--sort-by-seq", "--sort-by-timestamp", "input.csv"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert!(parse_args(&args).is_err());
+    }
 
-        let user_0_account = accounts.get(&0).unwrap();
-        assert_eq!(user_0_account.disputed_transactions, vec![1]);
-        assert_eq!(user_0_account.available, 15.0);
-        assert_eq!(user_0_account.held, 5.0);
-        assert_eq!(user_0_account.total_funds(), 20.0);
+    #[test]
+    fn event_log_has_its_own_flag() {
+        let args: Vec<String> = vec!["transactions", "--verbose", "input.csv"].into_iter().map(String::from).collect();
+        let options = parse_args(&args).unwrap();
+        assert!(options.verbose && !options.log_events);
+
+        let args: Vec<String> = vec!["transactions", "--log-events", "input.csv"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let options = parse_args(&args).unwrap();
+        assert!(options.log_events && !options.verbose);
     }
 
     #[test]
-    fn read_non_existent_csv_file() {
-        assert!(read_csv_file("NoSuchFile").is_err());
+    fn decimal_runs_only_stream_csv() {
+        let args: Vec<String> = vec!["transactions", "--decimal", "--limit", "5", "input.csv"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert!(parse_args(&args).unwrap().decimal);
+
+        for flag in ["--sort-by-seq", "--skip-errors", "--check", "--interactive"].iter().copied() {
+            let args: Vec<String> =
+                vec!["transactions", "--decimal", flag, "input.csv"].into_iter().map(String::from).collect();
+            assert!(parse_args(&args).is_err(), "{}", flag);
+        }
     }
 
     #[test]
-    fn read_existent_csv_file() {
-        assert!(read_csv_file("transaction.csv").is_ok());
+    fn progress_is_off_unless_asked_for() {
+        let args: Vec<String> = vec!["transactions", "input.csv"].into_iter().map(String::from).collect();
+        assert!(!parse_args(&args).unwrap().progress);
+
+        let args: Vec<String> = vec!["transactions", "--progress", "input.csv"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert!(parse_args(&args).unwrap().progress);
     }
 
     #[test]
-    fn ensure_parsed_transactions_are_correct() {
-        let parsed_transactions = read_csv_file("test.csv");
-        assert!(parsed_transactions.is_ok());
-        let transactions = parsed_transactions.unwrap();
-        assert_eq!(transactions.len(), 6);
-        assert_eq!(transactions[0], Transaction {
-            transaction_type: TransactionType::Deposit,
-            client: 1,
-            tx: 1,
-            amount: Some(1.0),
-        });
-
-        assert_eq!(transactions[1], Transaction {
-            transaction_type: TransactionType::Withdrawal,
-            client: 2,
-            tx: 2,
-            amount: Some(2.0),
-        });
-
-        assert_eq!(transactions[2], Transaction {
-            transaction_type: TransactionType::Dispute,
-            client: 1,
-            tx: 1,
-            amount: None,
-        });
-
-        assert_eq!(transactions[3], Transaction {
-            transaction_type: TransactionType::Resolve,
-            client: 1,
-            tx: 4,
-            amount: None,
-        });
-
-        assert_eq!(transactions[4], Transaction {
-            transaction_type: TransactionType::Dispute,
-            client: 2,
-            tx: 2,
-            amount: None,
-        });
-
-        assert_eq!(transactions[5], Transaction {
-            transaction_type: TransactionType::Chargeback,
-            client: 2,
-            tx: 2,
-            amount: None,
-        });
+    fn header_is_written_unless_turned_off() {
+        let args: Vec<String> = vec!["transactions", "input.csv"].into_iter().map(String::from).collect();
+        assert!(parse_args(&args).unwrap().header);
+
+        let args: Vec<String> = vec!["transactions", "--no-header", "input.csv"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert!(!parse_args(&args).unwrap().header);
+
+        // Only CSV output has a header to leave out.
+        let args: Vec<String> = vec!["transactions", "--no-header", "--format", "json", "input.csv"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert!(parse_args(&args).is_err());
+    }
+
+    #[test]
+    fn limit_is_a_row_count() {
+        let args: Vec<String> = vec!["transactions", "input.csv"].into_iter().map(String::from).collect();
+        assert_eq!(parse_args(&args).unwrap().limit, None);
+
+        let args: Vec<String> = vec!["transactions", "--limit", "100", "input.csv"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert_eq!(parse_args(&args).unwrap().limit, Some(100));
+
+        let args: Vec<String> = vec!["transactions", "--limit", "-1", "input.csv"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert!(parse_args(&args).is_err());
+
+        let args: Vec<String> = vec!["transactions", "--limit", "100", "--interactive"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert!(parse_args(&args).is_err());
+    }
+
+    #[test]
+    fn output_delimiter_is_a_single_character() {
+        let args: Vec<String> = vec!["transactions", "input.csv"].into_iter().map(String::from).collect();
+        assert_eq!(parse_args(&args).unwrap().output_delimiter, b',');
+
+        let args: Vec<String> = vec!["transactions", "--output-delimiter", ";", "input.csv"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert_eq!(parse_args(&args).unwrap().output_delimiter, b';');
+
+        let args: Vec<String> = vec!["transactions", "--output-delimiter", "\t", "input.csv"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert_eq!(parse_args(&args).unwrap().output_delimiter, b'\t');
+
+        for invalid in &[";;", "\"", "é"] {
+            let args: Vec<String> = vec!["transactions", "--output-delimiter", invalid, "input.csv"]
+                .into_iter()
+                .map(String::from)
+                .collect();
+            assert!(parse_args(&args).is_err());
+        }
+
+        let args: Vec<String> = vec!["transactions", "--output-delimiter", ";", "--format", "json", "input.csv"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert!(parse_args(&args).is_err());
     }
 }
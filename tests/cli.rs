@@ -0,0 +1,212 @@
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+fn run(args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_transactions")).args(args).output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn files_are_processed_in_order_as_one_stream() {
+    let expected = "client,available,held,total,locked\n1,-2.5,10,7.5,false\n2,1.5,0,1.5,true\n";
+    // The second day disputes and charges back deposits made on the first.
+    assert_eq!(run(&["day1.csv", "day2.csv"]), expected);
+    // Reading every file up front gives the same result.
+    assert_eq!(run(&["--sort-by-seq", "day1.csv", "day2.csv"]), expected);
+}
+
+#[test]
+fn the_header_can_be_left_out() {
+    assert_eq!(run(&["--no-header", "day1.csv"]), "1,7.5,0,7.5,false\n2,5,0,5,false\n");
+}
+
+#[test]
+fn semicolon_separated_files_can_be_read_and_written() {
+    let output = run_with_stdin(&["--output-delimiter", ";"], "type;client;tx;amount\ndeposit;1;1;2.5\n");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "client;available;held;total;locked\n1;2.5;0;2.5;false\n");
+}
+
+fn run_with_stdin(args: &[&str], input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_transactions"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn strict_runs_fail_when_transactions_are_ignored() {
+    let input = "type, client, tx, amount\ndeposit, 1, 1, 1.0\nwithdrawal, 1, 2, 5.0\n";
+    assert_eq!(run_with_stdin(&[], input).status.code(), Some(0));
+
+    let output = run_with_stdin(&["--strict"], input);
+    assert_eq!(output.status.code(), Some(3));
+    // The balances are still written.
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "client,available,held,total,locked\n1,1,0,1,false\n");
+
+    let clean = "type, client, tx, amount\ndeposit, 1, 1, 1.0\n";
+    assert_eq!(run_with_stdin(&["--strict"], clean).status.code(), Some(0));
+
+    // An empty run fails with a code of its own, so it can be told apart from ignored transactions.
+    let empty = "type, client, tx, amount\n";
+    assert_eq!(run_with_stdin(&["--strict", "--on-empty", "fail"], empty).status.code(), Some(4));
+}
+
+#[test]
+fn processing_stops_at_the_limit() {
+    let header = "client,available,held,total,locked\n";
+    assert_eq!(run(&["--limit", "2", "day1.csv"]), format!("{}1,10,0,10,false\n2,5,0,5,false\n", header));
+    // The limit counts rows across files: this takes the first row of the second day.
+    let expected = format!("{}1,-2.5,10,7.5,false\n2,5,0,5,false\n", header);
+    assert_eq!(run(&["--limit", "4", "day1.csv", "day2.csv"]), expected);
+
+    // Rows past the limit are never read, so a malformed one there doesn't stop the run.
+    let input = "type, client, tx, amount\ndeposit, 1, 1, 1.0\nnot a transaction\n";
+    assert!(!run_with_stdin(&[], input).status.success());
+    let output = run_with_stdin(&["--limit", "1"], input);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), format!("{}1,1,0,1,false\n", header));
+}
+
+#[test]
+fn errors_are_reported_as_messages() {
+    let output = run_with_stdin(&[], "type, client, tx, amount\ndeposit, 70000, 1, 1.0\n");
+    assert_eq!(output.status.code(), Some(65));
+    assert_eq!(String::from_utf8(output.stderr).unwrap(), "error: client id 70000 out of range on line 2\n");
+
+    let output = run_with_stdin(&["--bogus"], "");
+    assert_eq!(output.status.code(), Some(64));
+    assert!(String::from_utf8(output.stderr).unwrap().starts_with("error: unknown option --bogus\n"));
+
+    let output = run_with_stdin(&["no-such-file.csv"], "");
+    assert_eq!(output.status.code(), Some(74));
+    assert!(String::from_utf8(output.stderr).unwrap().starts_with("error: "));
+}
+
+#[test]
+fn decimal_runs_round_only_the_final_balances() {
+    let input = "type, client, tx, amount\n\
+                 deposit, 1, 1, 0.00006\n\
+                 deposit, 1, 2, 0.00006\n\
+                 deposit, 2, 3, 0.1\n\
+                 deposit, 2, 4, 0.2\n";
+    // Money rounds each amount as it is read.
+    let output = run_with_stdin(&[], input);
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,0.0002,0,0.0002,false\n2,0.3,0,0.3,false\n"
+    );
+
+    let output = run_with_stdin(&["--decimal"], input);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,0.0001,0,0.0001,false\n2,0.3,0,0.3,false\n"
+    );
+    // A balance that ends on a midpoint is rounded the same way either way.
+    let input = "type, client, tx, amount\ndeposit, 1, 1, 1.00025\n";
+    for args in [&[][..], &["--decimal"]] {
+        let output = run_with_stdin(args, input);
+        let expected = "client,available,held,total,locked\n1,1.0003,0,1.0003,false\n";
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), expected, "{:?}", args);
+    }
+}
+
+#[test]
+fn precision_pads_the_output_but_not_the_expected_comparison() {
+    let input = "type, client, tx, amount\ndeposit, 1, 1, 10.004\n";
+    let output = run_with_stdin(&["--precision", "2"], input);
+    let expected = "client,available,held,total,locked\n1,10.00,0.00,10.00,false\n";
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+
+    // The baseline matches the rounded balance, but the exact one still diverges from it.
+    let expected = std::env::temp_dir().join(format!("expected-{}.csv", std::process::id()));
+    std::fs::write(&expected, "client,available,held,total,locked\n1,10,0,10,false\n").unwrap();
+    let output = run_with_stdin(&["--precision", "2", "--expected", expected.to_str().unwrap()], input);
+    std::fs::remove_file(&expected).unwrap();
+    let report = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(report.lines().count(), 2, "{}", report);
+    assert!(report.contains("10.004"), "{}", report);
+}
+
+#[test]
+fn a_run_is_empty_when_no_transaction_took_effect() {
+    let header = "type, client, tx, amount\n";
+    // The baseline's accounts don't count as something processed.
+    let baseline = std::env::temp_dir().join(format!("baseline-{}.csv", std::process::id()));
+    std::fs::write(&baseline, "client,available,held,total,locked\n1,5,0,5,false\n").unwrap();
+    let output = run_with_stdin(&["--on-empty", "fail", "--baseline", baseline.to_str().unwrap()], header);
+    std::fs::remove_file(&baseline).unwrap();
+    assert_eq!(output.status.code(), Some(4));
+
+    // Neither does an account opened by a withdrawal that was refused.
+    let refused = "type, client, tx, amount\nwithdrawal, 1, 1, 5.0\n";
+    let output = run_with_stdin(&["--on-empty", "marker"], refused);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "no accounts\n");
+    let output = run_with_stdin(&["--on-empty", "fail", "--sort-by-seq"], refused);
+    assert_eq!(output.status.code(), Some(4));
+}
+
+#[test]
+fn progress_is_reported_however_the_rows_are_read() {
+    let input = "type, client, tx, amount\ndeposit, 1, 1, 1.0\ndeposit, 2, 2, 1.0\n";
+    for args in [&["--progress"][..], &["--progress", "--sort-by-seq"], &["--progress", "--parallel", "2"]] {
+        let output = run_with_stdin(args, input);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        assert_eq!(String::from_utf8(output.stderr).unwrap(), "read 2 rows in total\n", "{:?}", args);
+    }
+}
+
+#[test]
+fn a_description_goes_to_the_output_file() {
+    let path = std::env::temp_dir().join(format!("describe-{}.txt", std::process::id()));
+    let input = "type, client, tx, amount\ndeposit, 1, 1, 1.0\n";
+    let output = run_with_stdin(&["--describe", "1", "--output-file", path.to_str().unwrap()], input);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(output.stdout.is_empty());
+    let description = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(description.contains("client 1"), "{}", description);
+}
+
+#[test]
+fn zero_and_negative_amounts_are_skipped_without_ending_the_run() {
+    let input = "type, client, tx, amount\n\
+                 deposit, 1, 1, 10.0\n\
+                 deposit, 1, 2, 0\n\
+                 withdrawal, 1, 3, -50\n\
+                 deposit, 2, 4, 0.00001\n\
+                 deposit, 2, 5, 1.0\n";
+    let output = run_with_stdin(&[], input);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let expected = "client,available,held,total,locked\n1,10,0,10,false\n2,1,0,1,false\n";
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+}
+
+#[test]
+fn skipped_amounts_are_counted_however_the_run_is_applied() {
+    let input = "type, client, tx, amount\ndeposit, 1, 1, 0\nwithdrawal, 2, 2, -1\ndeposit, 3, 3, 1.0\n";
+    for args in [&[][..], &["--parallel", "2"]] {
+        let output = run_with_stdin(args, input);
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.contains("skipped 2 transactions with an amount of zero or less"), "{:?}: {}", args, stderr);
+    }
+}
+
+#[test]
+fn zero_amounts_fail_the_run_only_when_asked_to() {
+    let input = "type, client, tx, amount\ndeposit, 1, 1, 1.0\ndeposit, 1, 2, 0\n";
+    assert_eq!(run_with_stdin(&[], input).status.code(), Some(0));
+    assert_eq!(run_with_stdin(&["--zero-amounts", "skip"], input).status.code(), Some(0));
+
+    let output = run_with_stdin(&["--zero-amounts", "reject"], input);
+    assert_eq!(output.status.code(), Some(65));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("line 3") && stderr.contains("deposit of zero"), "{}", stderr);
+}
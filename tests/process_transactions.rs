@@ -0,0 +1,182 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::{Add, AddAssign, Sub, SubAssign};
+
+use transactions::{
+    process_transactions, process_transactions_with_state, Account, Amount, Decimal, EngineConfig, PositiveAmount,
+    ProcessedTransactions, Transaction, TransactionEngine, TransactionType,
+};
+
+fn transaction(transaction_type: TransactionType, client: u16, tx: u32, amount: Option<&str>) -> Transaction {
+    Transaction::new(
+        transaction_type,
+        client,
+        tx,
+        amount.map(|amount| PositiveAmount::new(amount.parse().unwrap()).unwrap()),
+    )
+}
+
+#[test]
+fn engine_is_usable_through_the_library() {
+    let accounts = process_transactions(vec![
+        transaction(TransactionType::Deposit, 1, 1, Some("10.5")),
+        transaction(TransactionType::Withdrawal, 1, 2, Some("0.5")),
+        transaction(TransactionType::Deposit, 1, 3, Some("4")),
+        transaction(TransactionType::Dispute, 1, 3, None),
+    ]);
+    let account = &accounts[&1];
+    assert_eq!(account.available().to_string(), "10");
+    assert_eq!(account.held().to_string(), "4");
+    assert_eq!(account.total().to_string(), "14");
+    assert!(!account.is_frozen());
+}
+
+#[test]
+fn engine_can_be_configured() {
+    let transactions = vec![
+        transaction(TransactionType::Deposit, 1, 1, Some("10")),
+        transaction(TransactionType::Withdrawal, 1, 2, Some("8")),
+        transaction(TransactionType::Dispute, 1, 1, None),
+    ];
+    // By default the dispute holds the full deposit even though most of it was withdrawn.
+    let accounts = process_transactions(transactions.clone());
+    assert_eq!(accounts[&1].available().to_string(), "-8");
+
+    let config = EngineConfig { allow_negative_available: false, ..EngineConfig::default() };
+    let accounts = TransactionEngine::new(config).process(transactions);
+    assert_eq!(accounts[&1].available().to_string(), "2");
+    assert_eq!(accounts[&1].held().to_string(), "0");
+}
+
+#[test]
+fn batches_can_be_applied_on_top_of_a_snapshot() {
+    let (accounts, processed) = process_transactions_with_state(
+        vec![
+            transaction(TransactionType::Deposit, 1, 1, Some("10")),
+            transaction(TransactionType::Deposit, 2, 2, Some("3")),
+        ],
+        None,
+        None,
+    );
+    let snapshot = bincode::serialize(&(accounts, processed)).unwrap();
+
+    let (accounts, processed): (BTreeMap<u16, Account>, ProcessedTransactions) =
+        bincode::deserialize(&snapshot).unwrap();
+    let (accounts, _) = process_transactions_with_state(
+        vec![
+            transaction(TransactionType::Withdrawal, 1, 3, Some("4")),
+            // Yesterday's deposit can still be disputed and charged back.
+            transaction(TransactionType::Dispute, 2, 2, None),
+            transaction(TransactionType::Chargeback, 2, 2, None),
+            // Its id was used yesterday, so this is a duplicate.
+            transaction(TransactionType::Deposit, 1, 1, Some("5")),
+        ],
+        Some(accounts),
+        Some(processed),
+    );
+    assert_eq!(accounts[&1].available().to_string(), "6");
+    assert_eq!(accounts[&2].total().to_string(), "0");
+    assert!(accounts[&2].is_frozen());
+}
+
+// A caller-supplied fixed-point amount: whole cents in an `i32`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, PartialOrd)]
+struct Cents(i32);
+
+impl fmt::Display for Cents {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.2}", f64::from(self.0) / 100.0)
+    }
+}
+
+impl Add for Cents {
+    type Output = Cents;
+
+    fn add(self, other: Cents) -> Cents {
+        Cents(self.0 + other.0)
+    }
+}
+
+impl Sub for Cents {
+    type Output = Cents;
+
+    fn sub(self, other: Cents) -> Cents {
+        Cents(self.0 - other.0)
+    }
+}
+
+impl AddAssign for Cents {
+    fn add_assign(&mut self, other: Cents) {
+        self.0 += other.0;
+    }
+}
+
+impl SubAssign for Cents {
+    fn sub_assign(&mut self, other: Cents) {
+        self.0 -= other.0;
+    }
+}
+
+impl Amount for Cents {
+    fn checked_add(self, other: Cents) -> Option<Cents> {
+        self.0.checked_add(other.0).map(Cents)
+    }
+
+    fn checked_sub(self, other: Cents) -> Option<Cents> {
+        self.0.checked_sub(other.0).map(Cents)
+    }
+
+    fn saturating_add(self, other: Cents) -> Cents {
+        Cents(self.0.saturating_add(other.0))
+    }
+}
+
+fn cents_transaction(
+    transaction_type: TransactionType,
+    client: u16,
+    tx: u32,
+    amount: Option<i32>,
+) -> Transaction<Cents> {
+    Transaction::new(transaction_type, client, tx, amount.map(|amount| PositiveAmount::new(Cents(amount)).unwrap()))
+}
+
+#[test]
+fn engine_works_with_a_custom_amount_type() {
+    let accounts = process_transactions(vec![
+        cents_transaction(TransactionType::Deposit, 1, 1, Some(1050)),
+        cents_transaction(TransactionType::Withdrawal, 1, 2, Some(50)),
+        cents_transaction(TransactionType::Deposit, 1, 3, Some(400)),
+        cents_transaction(TransactionType::Dispute, 1, 3, None),
+        cents_transaction(TransactionType::Deposit, 2, 4, Some(i32::MAX)),
+        cents_transaction(TransactionType::Deposit, 2, 5, Some(1)),
+    ]);
+    assert_eq!(accounts[&1].available(), Cents(1000));
+    assert_eq!(accounts[&1].held(), Cents(400));
+    assert_eq!(accounts[&1].total().to_string(), "14.00");
+    // The second deposit would overflow the custom type, so it is refused like any other.
+    assert_eq!(accounts[&2].available(), Cents(i32::MAX));
+
+    let config = EngineConfig { max_balance: Some(Cents(100)), ..EngineConfig::default() };
+    let accounts = TransactionEngine::with_accounts(Default::default(), config)
+        .process(vec![cents_transaction(TransactionType::Deposit, 1, 1, Some(101))]);
+    assert_eq!(accounts[&1].available(), Cents(0));
+}
+
+#[test]
+fn engine_works_with_decimal_amounts() {
+    let deposit = |tx: u32, amount: &str| Transaction::new(
+        TransactionType::Deposit,
+        1,
+        tx,
+        PositiveAmount::new(amount.parse::<Decimal>().unwrap()),
+    );
+    let accounts = process_transactions(vec![deposit(1, "0.1"), deposit(2, "0.2")]);
+    assert_eq!(accounts[&1].available(), "0.3".parse::<Decimal>().unwrap());
+    assert_eq!(accounts[&1].total().round_dp(4).to_string(), "0.3");
+
+    // Amounts are read from text, so precision beyond four places survives.
+    let data = "type,client,tx,amount\ndeposit,1,1,0.00001\n";
+    let mut rdr = csv::Reader::from_reader(data.as_bytes());
+    let transactions: Vec<Transaction<Decimal>> = rdr.deserialize().collect::<Result<_, _>>().unwrap();
+    assert_eq!(process_transactions(transactions)[&1].available().to_string(), "0.00001");
+}